@@ -1,88 +1,204 @@
 from __future__ import annotations
-from collections import defaultdict
 
 from . import xlineparse as _xlineparse  # type: ignore
 
-from dataclasses import dataclass, replace
+import asyncio
+from dataclasses import dataclass, field as dataclass_field, replace
 import enum
 import json
+import os
+import re
 from types import NoneType, UnionType
-from typing import Annotated, Any, Literal, Union, get_args, get_origin
+from typing import (
+    Annotated,
+    Any,
+    AsyncIterator,
+    Callable,
+    Iterable,
+    Literal,
+    Union,
+    get_args,
+    get_origin,
+    overload,
+)
 import decimal
 
+from .copybook import Item, parse_copybook
+from .diff import SchemaChange, SchemaDiff, diff_schemas  # noqa: F401
+
+PadSide = Literal["left", "right", "both"]
+# With "warn", a value failing a constraint is kept and a LineWarning is issued
+Severity = Literal["error", "warn"]
+
 
 @dataclass(frozen=True, kw_only=True)
 class StrField:
     required: bool = True
+    severity: Severity = "error"
     min_length: int | None = None
     max_length: int | None = None
+    # Chars and ranges of them, eg: "0-9A-Z_", a "-" at either end is just a "-"
     invalid_characters: str | None = None
+    allowed_characters: str | None = None  # as invalid_characters, eg: " -~"
+    # Of the lengths, graphemes are as seen, eg: "e\u0301" is one
+    length_unit: Literal["bytes", "chars", "graphemes"] = "bytes"
+    # Of the output, title is as str.title()
+    transform: Literal["upper", "lower", "title"] | None = None
+    coerce_empty_quoted: bool | None = None  # overrides Schema.coerce_empty_quoted
+    intern: bool = False  # share equal outputs, for low cardinality fields
+    # Stripped before anything else, eg: pad_char="0" of "000123"
+    pad_char: str | None = None
+    pad_side: PadSide = "left"
 
     def as_dict(self) -> dict[str, Any]:
         return dict(
             kind="STR",
             required=self.required,
+            severity=self.severity,
             min_length=self.min_length,
             max_length=self.max_length,
             invalid_characters=self.invalid_characters,
+            allowed_characters=self.allowed_characters,
+            length_unit=self.length_unit,
+            transform=self.transform,
+            coerce_empty_quoted=self.coerce_empty_quoted,
+            intern=self.intern,
+            pad_char=self.pad_char,
+            pad_side=self.pad_side,
         )
 
 
 @dataclass(frozen=True, kw_only=True)
 class StrEnumField:
     required: bool = True
-    cls: type[enum.Enum]
+    severity: Severity = "error"
+    # The output is converted to a member of cls by value
+    cls: type[enum.Enum] | None = None
+    # Raw value -> output label, eg: {"01": "CREDIT", "02": "DEBIT"}
+    mapping: dict[str, str] | None = None
+    case_insensitive: bool = False
+    strip: bool = False  # ignore leading/trailing whitespace
 
     def as_dict(self) -> dict[str, Any]:
-        values = {field.value for field in self.cls}
         return dict(
             kind="STR_ENUM",
             required=self.required,
-            values=sorted(values),
+            severity=self.severity,
+            values=sorted(enum_values(self)),
+            case_insensitive=self.case_insensitive,
+            strip=self.strip,
+            mapping=self.mapping,
         )
 
 
+def int_to_json(i: int | None) -> int | str | None:
+    # Big ints go as strings so they don't get squashed through a float
+    if i is None or -(2**63) <= i < 2**63:
+        return i
+    return str(i)
+
+
+@dataclass(frozen=True, kw_only=True)
+class Sequence:
+    start: int = 1
+    step: int = 1
+
+
 @dataclass(frozen=True, kw_only=True)
 class IntField:
     required: bool = True
+    severity: Severity = "error"
     min_value: int | None = None
     max_value: int | None = None
+    exclusive_min: int | None = None
+    exclusive_max: int | None = None
+    not_equal: int | None = None
+    # Checked by .parse_lines(), all sequence fields share the one count
+    sequence: Sequence | None = None
+    # The sign is overpunched on the last digit, as COBOL signed zoned decimals,
+    # eg: "12J" is -121 and "12{" is 120
+    zoned: bool = False
+    pad_char: str | None = None  # as StrField
+    pad_side: PadSide = "left"
 
     def as_dict(self) -> dict[str, Any]:
         return dict(
             kind="INT",
             required=self.required,
-            min_value=self.min_value,
-            max_value=self.max_value,
+            severity=self.severity,
+            min_value=int_to_json(self.min_value),
+            max_value=int_to_json(self.max_value),
+            exclusive_min=int_to_json(self.exclusive_min),
+            exclusive_max=int_to_json(self.exclusive_max),
+            not_equal=int_to_json(self.not_equal),
+            sequence=(
+                None
+                if self.sequence is None
+                else dict(start=self.sequence.start, step=self.sequence.step)
+            ),
+            zoned=self.zoned,
+            pad_char=self.pad_char,
+            pad_side=self.pad_side,
         )
 
 
 @dataclass(frozen=True, kw_only=True)
 class IntEnumField:
     required: bool = True
-    cls: type[enum.Enum]
+    severity: Severity = "error"
+    # The output is converted to a member of cls by value
+    cls: type[enum.Enum] | None = None
+    # Raw value -> output label, eg: {1: "ONE", 2: "TWO"}
+    mapping: dict[int, str] | None = None
 
     def as_dict(self) -> dict[str, Any]:
-        values = {field.value for field in self.cls}
         return dict(
             kind="INT_ENUM",
             required=self.required,
-            values=sorted(values),
+            severity=self.severity,
+            values=sorted(enum_values(self)),
+            mapping=None if self.mapping is None else list(self.mapping.items()),
         )
 
 
+def enum_values(field: StrEnumField | IntEnumField) -> set[Any]:
+    if field.mapping is not None:
+        return set(field.mapping)
+    if field.cls is None:
+        raise ValueError("Enum fields need one of cls or mapping")
+    return {member.value for member in field.cls}
+
+
 @dataclass(frozen=True, kw_only=True)
 class FloatField:
     required: bool = True
+    severity: Severity = "error"
     min_value: float | None = None
     max_value: float | None = None
+    exclusive_min: float | None = None
+    exclusive_max: float | None = None
+    not_equal: float | None = None
+    allow_nan: bool = True
+    allow_inf: bool = True  # of "inf" and "-inf"
+    allow_exponent: bool = True  # eg: "1.2e5"
+    pad_char: str | None = None  # as StrField
+    pad_side: PadSide = "left"
 
     def as_dict(self) -> dict[str, Any]:
         return dict(
             kind="FLOAT",
             required=self.required,
+            severity=self.severity,
             min_value=self.min_value,
             max_value=self.max_value,
+            exclusive_min=self.exclusive_min,
+            exclusive_max=self.exclusive_max,
+            not_equal=self.not_equal,
+            allow_nan=self.allow_nan,
+            allow_inf=self.allow_inf,
+            allow_exponent=self.allow_exponent,
+            pad_char=self.pad_char,
+            pad_side=self.pad_side,
         )
 
 
@@ -92,28 +208,62 @@ def decimal_to_str(d: decimal.Decimal | None) -> str | None:
     return f"{d:f}"
 
 
+Rounding = Literal["half_up", "half_down", "half_even", "floor", "ceiling"]
+
+
 @dataclass(frozen=True, kw_only=True)
 class DecimalField:
     required: bool = True
+    severity: Severity = "error"
     round_decimal_places: int | None = None
+    rounding: Rounding = "half_even"  # only used with round_decimal_places
     min_value: decimal.Decimal | None = None
     max_value: decimal.Decimal | None = None
+    exclusive_min: decimal.Decimal | None = None
+    exclusive_max: decimal.Decimal | None = None
+    not_equal: decimal.Decimal | None = None
+    # Checked after any rounding, eg: max_digits=5, max_decimal_places=2 allows
+    # "123.45" but not "1.234" or "1234.5"
+    max_digits: int | None = None
+    max_decimal_places: int | None = None
+    zoned: bool = False  # as IntField
+    # The point isn't in the value, eg: "12345" is 123.45 with 2, as COBOL's V
+    implied_decimal_places: int | None = None
+    pad_char: str | None = None  # as StrField
+    pad_side: PadSide = "left"
 
     def as_dict(self) -> dict[str, Any]:
         return dict(
             kind="DECIMAL",
             required=self.required,
+            severity=self.severity,
             round_decimal_places=self.round_decimal_places,
+            rounding=self.rounding,
             min_value=decimal_to_str(self.min_value),
             max_value=decimal_to_str(self.max_value),
+            exclusive_min=decimal_to_str(self.exclusive_min),
+            exclusive_max=decimal_to_str(self.exclusive_max),
+            not_equal=decimal_to_str(self.not_equal),
+            max_digits=self.max_digits,
+            max_decimal_places=self.max_decimal_places,
+            zoned=self.zoned,
+            implied_decimal_places=self.implied_decimal_places,
+            pad_char=self.pad_char,
+            pad_side=self.pad_side,
         )
 
 
 @dataclass(frozen=True, kw_only=True)
 class BoolField:
     required: bool = True
-    true_value: str
-    false_value: str | None  # can only be "" if .required
+    true_value: str | None = None
+    false_value: str | None = None  # can only be "" if .required
+    # Extra representations, eg: true_values=("Y", "YES", "1")
+    true_values: tuple[str, ...] = ()
+    false_values: tuple[str, ...] = ()
+    # Also None when not .required, eg: null_values=(" ",) for unknown
+    null_values: tuple[str, ...] = ()
+    case_insensitive: bool = False
 
     def as_dict(self) -> dict[str, Any]:
         return dict(
@@ -121,14 +271,28 @@ class BoolField:
             required=self.required,
             true_value=self.true_value,
             false_value=self.false_value,
+            true_values=list(self.true_values),
+            false_values=list(self.false_values),
+            null_values=list(self.null_values),
+            case_insensitive=self.case_insensitive,
         )
 
 
 @dataclass(frozen=True, kw_only=True)
 class DatetimeField:
     required: bool = True
-    format: str
-    time_zone: str  # eg: "UTC" | "Europe/London"
+    # strftime, or "epoch_seconds" | "epoch_millis" for Unix timestamps,
+    # or a list of these to try in order
+    format: str | list[str]
+    time_zone: str | None = None  # eg: "UTC" | "Europe/London"
+    # The value has its own offset (implied if format contains %z), eg: "+0100"
+    offset_in_value: bool = False
+    # Local times that happen twice/never because of DST changes
+    ambiguous: Literal["earliest", "latest", "raise"] = "raise"
+    nonexistent: Literal["shift_forward", "shift_backward", "raise"] = "raise"
+    allow_2400: bool = False  # "24:00" is midnight, rolling onto the next day
+    output_time_zone: str | None = None  # convert to this zone, eg: "UTC"
+    output_naive: bool = False  # drop the tzinfo, after any output_time_zone
 
     def as_dict(self) -> dict[str, Any]:
         return dict(
@@ -136,13 +300,19 @@ class DatetimeField:
             required=self.required,
             format=self.format,
             time_zone=self.time_zone,
+            offset_in_value=self.offset_in_value,
+            ambiguous=self.ambiguous,
+            nonexistent=self.nonexistent,
+            allow_2400=self.allow_2400,
+            output_time_zone=self.output_time_zone,
+            output_naive=self.output_naive,
         )
 
 
 @dataclass(frozen=True, kw_only=True)
 class DateField:
     required: bool = True
-    format: str
+    format: str | list[str]  # a list of formats is tried in order
 
     def as_dict(self) -> dict[str, Any]:
         return dict(
@@ -155,13 +325,84 @@ class DateField:
 @dataclass(frozen=True, kw_only=True)
 class TimeField:
     required: bool = True
-    format: str
+    format: str | list[str]  # a list of formats is tried in order
+    allow_2400: bool = True  # "24:00" is midnight
 
     def as_dict(self) -> dict[str, Any]:
         return dict(
             kind="TIME",
             required=self.required,
             format=self.format,
+            allow_2400=self.allow_2400,
+        )
+
+
+@dataclass(frozen=True, kw_only=True)
+class CompositeField:
+    required: bool = True
+    # Split by Schema.component_delimiter, output as a tuple. Enum fields
+    # within are output by value
+    fields: list[Field]
+
+    def as_dict(self) -> dict[str, Any]:
+        return dict(
+            kind="COMPOSITE",
+            required=self.required,
+            fields=[field.as_dict() for field in self.fields],
+        )
+
+
+@dataclass(frozen=True, kw_only=True)
+class CurrencyField:
+    # Amounts with a symbol or ISO code either side, eg: "£1,234.56",
+    # "USD 99.00", "-$5.00", "99.00 EUR", output as a Decimal
+    required: bool = True
+    severity: Severity = "error"
+    # Symbol -> ISO code, these take precedence over £ $ € ¥
+    symbols: dict[str, str] | None = None
+    codes: tuple[str, ...] | None = None  # if given, only these, else ISO 4217
+    default_code: str | None = None  # of amounts without a symbol or code
+    include_code: bool = False  # output (amount, code | None)
+    thousands_separator: str | None = ","
+    min_value: decimal.Decimal | None = None
+    max_value: decimal.Decimal | None = None
+
+    def as_dict(self) -> dict[str, Any]:
+        return dict(
+            kind="CURRENCY",
+            required=self.required,
+            severity=self.severity,
+            symbols=self.symbols or {},
+            codes=None if self.codes is None else list(self.codes),
+            default_code=self.default_code,
+            include_code=self.include_code,
+            thousands_separator=self.thousands_separator,
+            min_value=decimal_to_str(self.min_value),
+            max_value=decimal_to_str(self.max_value),
+        )
+
+
+@dataclass(frozen=True, kw_only=True)
+class PercentField:
+    # Eg: "12.5%", output as a Decimal
+    required: bool = True
+    severity: Severity = "error"
+    assume_percent: bool = False  # also take "12.5" as 12.5%
+    as_fraction: bool = False  # output 12.5% as 0.125
+    as_float: bool = False
+    min_value: decimal.Decimal | None = None  # of the output
+    max_value: decimal.Decimal | None = None
+
+    def as_dict(self) -> dict[str, Any]:
+        return dict(
+            kind="PERCENT",
+            required=self.required,
+            severity=self.severity,
+            assume_percent=self.assume_percent,
+            as_fraction=self.as_fraction,
+            as_float=self.as_float,
+            min_value=decimal_to_str(self.min_value),
+            max_value=decimal_to_str(self.max_value),
         )
 
 
@@ -176,6 +417,9 @@ Field = (
     | DatetimeField
     | DateField
     | TimeField
+    | CompositeField
+    | CurrencyField
+    | PercentField
 )
 
 
@@ -202,6 +446,8 @@ def field_type_to_field(t: type) -> Field:
         field = FloatField()
     elif t is decimal.Decimal and field is None:
         field = DecimalField()
+    elif get_origin(t) is tuple and field is None:
+        field = CompositeField(fields=[field_type_to_field(a) for a in get_args(t)])
     elif issubclass(t, enum.Enum) and field is None:
         if all(isinstance(v.value, str) for v in t):
             field = StrEnumField(cls=t)
@@ -219,95 +465,905 @@ def field_type_to_field(t: type) -> Field:
     return field
 
 
+STUB_TYPES: dict[type, str] = {
+    StrField: "str",
+    IntField: "int",
+    FloatField: "float",
+    DecimalField: "Decimal",
+    CurrencyField: "Decimal",
+    BoolField: "bool",
+    DatetimeField: "dt.datetime",
+    DateField: "dt.date",
+    TimeField: "dt.time",
+}
+
+
+def field_annotation(field: Field, imports: set[str]) -> str:
+    if isinstance(field, CurrencyField) and field.include_code:
+        imports.add("from decimal import Decimal")
+        annotation = "tuple[Decimal, str | None]"
+    elif isinstance(field, PercentField):
+        annotation = "float" if field.as_float else "Decimal"
+    elif isinstance(field, CompositeField):
+        annotations = [field_annotation(f, imports) for f in field.fields]
+        annotation = f"tuple[{', '.join(annotations)}]"
+    elif isinstance(field, (StrEnumField, IntEnumField)) and field.cls is not None:
+        imports.add(f"from {field.cls.__module__} import {field.cls.__qualname__}")
+        annotation = field.cls.__qualname__
+    elif isinstance(field, (StrEnumField, IntEnumField)):
+        imports.add("from typing import Literal")
+        outputs = (
+            list(field.mapping.values())
+            if field.mapping is not None
+            else sorted(enum_values(field))
+        )
+        annotation = f"Literal[{', '.join(repr(o) for o in dict.fromkeys(outputs))}]"
+    else:
+        annotation = STUB_TYPES[type(field)]
+    if annotation == "Decimal":
+        imports.add("from decimal import Decimal")
+    if annotation.startswith("dt."):
+        imports.add("import datetime as dt")
+    return annotation if field.required else f"{annotation} | None"
+
+
+def stub_name(name: str | tuple[str, ...]) -> str:
+    words = re.findall(r"[A-Za-z0-9]+", " ".join(name_list(name)))
+    name = "".join(word[0].upper() + word[1:] for word in words)
+    if not name or name[0].isdigit():
+        name = f"L{name}"
+    return f"{name}Line"
+
+
 @dataclass(frozen=True, kw_only=True)
 class Line:
-    name: str
+    # Or the prefix/regex to match against, see .match. A tuple of names
+    # matches that many leading parts, eg: ("TX", "02")
+    name: str | tuple[str, ...]
     fields: list[Field]
+    # One per field if given, keys the fields in .parse_line_to_json() etc.
+    field_names: tuple[str, ...] = ()
+    # How the first part selects the line, exact matches take precedence
+    match: Literal["exact", "prefix", "regex"] = "exact"
+    # Other names that match, eg: an old name for the line
+    aliases: tuple[str | tuple[str, ...], ...] = ()
+    normalize_aliases: bool = False  # output .name rather than the alias matched
+    trailing_delimiter: bool | None = None  # overrides Schema.trailing_delimiter
+    allow_extra_fields: bool = False  # ignore parts after the last field
+    # With allow_extra_fields, output them as a last tuple of strs
+    keep_extra_fields: bool = False
+    allow_missing_fields: bool = False  # missing trailing fields are taken as ""
+    # Indexes into fields, when parsing many lines, no two of these lines can
+    # have the same values for them
+    unique_key: tuple[int, ...] = ()
+    versions: tuple[str, ...] = ()  # of Schema.version_field, all if empty
+    # Per field, fixed-width lines are cut into parts of this many chars rather
+    # than split on Schema.delimiter. Every line has them or none do, and the
+    # Schema needs a BytesDiscriminator or NoDiscriminator
+    widths: tuple[int, ...] = ()
 
     def as_dict(self) -> dict[str, Any]:
         return dict(
-            name=self.name,
+            name=self.name if isinstance(self.name, str) else name_list(self.name),
             fields=[field.as_dict() for field in self.fields],
+            field_names=list(self.field_names),
+            match=dict(kind=self.match),
+            aliases=[name_list(alias) for alias in self.aliases],
+            normalize_aliases=self.normalize_aliases,
+            trailing_delimiter=self.trailing_delimiter,
+            allow_extra_fields=self.allow_extra_fields,
+            keep_extra_fields=self.keep_extra_fields,
+            allow_missing_fields=self.allow_missing_fields,
+            unique_key=list(self.unique_key),
+            versions=list(self.versions),
+            widths=list(self.widths),
         )
 
 
-def convert_line_type(t: type) -> Line:
+def name_list(name: str | tuple[str, ...]) -> list[str]:
+    return [name] if isinstance(name, str) else list(name)
+
+
+def convert_line_type(t: type, has_name: bool = True) -> Line:
     assert get_origin(t) is tuple
-    name_literal, *fields = get_args(t)
-    assert get_origin(name_literal) is Literal
-    name: str
-    (name,) = get_args(name_literal)
-    return Line(name=name, fields=[field_type_to_field(t) for t in fields])
+    if not has_name:
+        return Line(name="", fields=[field_type_to_field(t) for t in get_args(t)])
+    # All the leading Literals make up the name
+    args = get_args(t)
+    names: list[str] = []
+    while len(names) < len(args) and get_origin(args[len(names)]) is Literal:
+        (name,) = get_args(args[len(names)])
+        names.append(name)
+    assert names
+    fields = [field_type_to_field(t) for t in args[len(names) :]]
+    if len(names) == 1:
+        return Line(name=names[0], fields=fields)
+    return Line(name=tuple(names), fields=fields)
+
+
+class LineParseError(ValueError):
+    def __init__(
+        self,
+        message: str,
+        *,
+        line: str | None = None,
+        # When parsing many lines, where the line is in the input
+        line_no: int | None = None,  # 1-based
+        byte_offset: int | None = None,
+    ) -> None:
+        super().__init__(message)
+        self.line = line
+        self.line_no = line_no
+        self.byte_offset = byte_offset
+
+
+# A trailer line's count or totals don't match the lines before it
+class ReconciliationError(LineParseError): ...
+
+
+# Issued through the warnings module for each constraint with severity "warn"
+# that a value fails, eg: catch them with warnings.catch_warnings(record=True)
+LineWarning = _xlineparse.LineWarning
+
+
+def batch_error(e: ValueError) -> LineParseError:
+    cls = (
+        ReconciliationError
+        if isinstance(e, _xlineparse.ReconciliationError)
+        else LineParseError
+    )
+    # Errors about a line come with (line, line_no, byte_offset)
+    if len(e.args) == 4:
+        message, line, line_no, byte_offset = e.args
+        return cls(message, line=line, line_no=line_no, byte_offset=byte_offset)
+    return cls(e.args[0])
+
+
+@dataclass(frozen=True, kw_only=True)
+class Reject:
+    line: str
+    line_no: int  # 1-based
+    error: LineParseError
+
+
+OnError = Literal["raise", "collect"]
+QuoteMode = Literal["lenient", "minimal", "all", "none"]
+Parsed = list[tuple[Any, ...]]
+# With on_error="collect", failing lines are skipped and returned as Rejects
+Collected = tuple[Parsed, list[Reject]]
+# Keyed by Line.name, with every line of the schema present
+Grouped = dict[str | tuple[str, ...], Parsed]
+CollectedGrouped = tuple[Grouped, list[Reject]]
+
+
+@dataclass(frozen=True, kw_only=True)
+class TrailerSum:
+    field: int  # index into the trailer's fields of the total
+    line: str | tuple[str, ...]  # add up this line's values
+    line_field: int  # index into the line's fields of the value
+
+
+@dataclass(kw_only=True)
+class LineStats:
+    count: int
+    # Per field, of the values before any enum conversion. The min and max are
+    # None if every value was
+    null_counts: list[int]
+    min_values: list[Any]
+    max_values: list[Any]
+
+
+# Of versions of a line, by field index. A min or max is None if the versions'
+# values don't compare
+def merge_line_stats(a: LineStats, b: LineStats) -> LineStats:
+    n = max(len(a.null_counts), len(b.null_counts))
+
+    def padded(values: list[Any], fill: Any) -> list[Any]:
+        return values + [fill] * (n - len(values))
+
+    def pick(x: Any, y: Any, f: Callable[[Any, Any], Any]) -> Any:
+        if x is None or y is None:
+            return y if x is None else x
+        try:
+            return f(x, y)
+        except TypeError:
+            return None
+
+    nulls = zip(padded(a.null_counts, 0), padded(b.null_counts, 0))
+    mins = zip(padded(a.min_values, None), padded(b.min_values, None))
+    maxs = zip(padded(a.max_values, None), padded(b.max_values, None))
+    return LineStats(
+        count=a.count + b.count,
+        null_counts=[x + y for x, y in nulls],
+        min_values=[pick(x, y, min) for x, y in mins],
+        max_values=[pick(x, y, max) for x, y in maxs],
+    )
 
 
-class LineParseError(ValueError): ...
+# Called with (lines, bytes) read so far, of the decompressed file
+Progress = Callable[[int, int], None]
+# Takes a field's value, returns what to output instead, see Schema.validators
+Validator = Callable[[Any], Any]
+
+
+# Pass to .parse_lines(), .parse_file() etc. as stats= to have it filled in
+@dataclass(kw_only=True)
+class Stats:
+    lines: int = 0
+    errors: int = 0
+    by_line: dict[str | tuple[str, ...], LineStats] = dataclass_field(
+        default_factory=dict
+    )
+
+
+@dataclass(frozen=True, kw_only=True)
+class TrailerCheck:
+    # Checked against the lines since the previous trailer (or the start)
+    line: str | tuple[str, ...]
+    count_field: int | None = None  # index into the trailer's fields
+    count_lines: tuple[str | tuple[str, ...], ...] | None = None  # default all
+    sums: tuple[TrailerSum, ...] = ()
+
+    def as_dict(self) -> dict[str, Any]:
+        return dict(
+            line=name_list(self.line),
+            count_field=self.count_field,
+            count_lines=(
+                None
+                if self.count_lines is None
+                else [name_list(line) for line in self.count_lines]
+            ),
+            sums=[
+                dict(
+                    field=trailer_sum.field,
+                    line=name_list(trailer_sum.line),
+                    line_field=trailer_sum.line_field,
+                )
+                for trailer_sum in self.sums
+            ],
+        )
+
+
+@dataclass(frozen=True, kw_only=True)
+class VersionField:
+    # When parsing many lines, this field of this line (eg: a file header) picks
+    # the Line.versions used for the lines after it
+    line: str | tuple[str, ...]
+    field: int  # index into the line's fields
+
+    def as_dict(self) -> dict[str, Any]:
+        return dict(line=name_list(self.line), field=self.field)
+
+
+@dataclass(frozen=True, kw_only=True)
+class Continuation:
+    # When parsing many lines, a record carries on over the following lines.
+    # trailing: lines ending with the marker continue onto the next, eg: "\\"
+    # leading: lines starting with the marker continue the one before, eg: "+"
+    kind: Literal["trailing", "leading"] = "trailing"
+    marker: str  # removed before joining
+    separator: str = ""  # put between the joined lines
+
+    def as_dict(self) -> dict[str, Any]:
+        return dict(kind=self.kind, marker=self.marker, separator=self.separator)
+
+
+# Output of .parse_document(), the start line with its children (which may
+# themselves be Groups) and the end line (or None)
+Group = _xlineparse.Group
+
+# Built from Schema.to_json(), .schema | .line_names | .fields_for(name) describe
+# what it will parse as plain dicts
+Parser = _xlineparse.Parser
+
+
+@dataclass(frozen=True, kw_only=True)
+class Grouper:
+    start: str | tuple[str, ...]  # line names, as in Line.name
+    children: tuple[str | tuple[str, ...], ...] = ()
+    # Without an end line, the group finishes at the first line that isn't a child
+    end: str | tuple[str, ...] | None = None
+
+    def as_dict(self) -> dict[str, Any]:
+        return dict(
+            start=name_list(self.start),
+            children=[name_list(child) for child in self.children],
+            end=None if self.end is None else name_list(self.end),
+        )
+
+
+@dataclass(frozen=True, kw_only=True)
+class FieldDiscriminator:
+    index: int = 0  # this part is the line name, the others are the fields
+
+    def as_dict(self) -> dict[str, Any]:
+        return dict(kind="field", index=self.index)
+
+
+@dataclass(frozen=True, kw_only=True)
+class BytesDiscriminator:
+    start: int  # line[start:end] is the line name, all the parts are fields
+    end: int
+
+    def as_dict(self) -> dict[str, Any]:
+        return dict(kind="bytes", start=self.start, end=self.end)
+
+
+@dataclass(frozen=True, kw_only=True)
+class NoDiscriminator:
+    # Lines have no name, there's a single Line and we output just the fields
+
+    def as_dict(self) -> dict[str, Any]:
+        return dict(kind="none")
+
+
+Discriminator = FieldDiscriminator | BytesDiscriminator | NoDiscriminator
 
 
 @dataclass(kw_only=True)
 class Schema:
-    delimiter: str
+    delimiter: str  # unused with fixed-width lines, see Line.widths
     quote_str: str | None = None
+    # lenient: quotes toggle quoting anywhere, minimal: only parts starting
+    # with a quote are quoted, with "" as a quote, all: as minimal, but every
+    # part must be quoted, none: quotes are just chars
+    quote_mode: QuoteMode = "lenient"
     trailing_delimiter: bool = False
     coerce_empty_quoted: bool = False  # convert '""': str|None -> '' instead of None
+    discriminator: Discriminator = FieldDiscriminator()
     lines: list[Line]
+    groups: list[Grouper] = dataclass_field(default_factory=list)
+    trailer_checks: list[TrailerCheck] = dataclass_field(default_factory=list)
+    # Of bytes input and files, needs to be ASCII compatible
+    encoding: str = "utf-8"
+    encoding_errors: Literal["strict", "replace"] = "strict"
+    strip_bom: bool = True  # from the start of the first line
+    # Ends each line, eg: "\x1e", by default any of "\n" | "\r\n" | "\r"
+    line_terminator: str | None = None
+    # Output (parsed, raw) per line, raw has the original text of each part
+    include_raw: bool = False
+    # For EDIFACT/X12 style lines, eg: "NAD+BY+12345::91'", see CompositeField
+    component_delimiter: str | None = None
+    release_str: str | None = None  # the char after this is taken as is
+    version_field: VersionField | None = None
+    continuation: Continuation | None = None
+    # When reading files, newlines in quoted parts don't end the line, as RFC 4180
+    multiline_quoted: bool = False
+    # Past this many lines, the record ends even if quoted, and fails to parse
+    multiline_max_lines: int = 1000
+    # Keyed by (line name, index into its fields), called with each non None
+    # value after conversion, eg: to check an IBAN. What they return is output
+    # instead, and raising fails the line. The JSON and Parquet outputs raise
+    # a ValueError with validators
+    validators: dict[tuple[str | tuple[str, ...], int], Validator] = dataclass_field(
+        default_factory=dict
+    )
 
     def __post_init__(self) -> None:
-        # Add a ._parser
+        # The parser swaps enum values for members
+        enum_members = [
+            (line_i, field_i, field.cls._value2member_map_)
+            for line_i, line in enumerate(self.lines)
+            for field_i, field in enumerate(line.fields)
+            if isinstance(field, (StrEnumField, IntEnumField)) and field.cls
+        ]
+        validators = []
+        for (name, field_i), validator in self.validators.items():
+            line_is = [
+                line_i
+                for line_i, line in enumerate(self.lines)
+                if name_list(line.name) == name_list(name)
+            ]
+            if not line_is:
+                raise ValueError(f"Validator for line {name!r} isn't in the schema")
+            validators += [(line_i, field_i, validator) for line_i in line_is]
+        self._parser = _xlineparse.Parser(self.to_json(), enum_members, validators)
+
+    # What the parser is built from, as taken by `xlineparse validate --schema`
+    def to_json(self) -> str:
         jsonable = dict(
             delimiter=self.delimiter,
             quote_str=self.quote_str,
+            quote_mode=self.quote_mode,
             trailing_delimiter=self.trailing_delimiter,
             coerce_empty_quoted=self.coerce_empty_quoted,
+            discriminator=self.discriminator.as_dict(),
             lines=[line.as_dict() for line in self.lines],
+            groups=[group.as_dict() for group in self.groups],
+            trailer_checks=[check.as_dict() for check in self.trailer_checks],
+            encoding=self.encoding,
+            encoding_errors=self.encoding_errors,
+            strip_bom=self.strip_bom,
+            line_terminator=self.line_terminator,
+            include_raw=self.include_raw,
+            component_delimiter=self.component_delimiter,
+            release_str=self.release_str,
+            version_field=(
+                None if self.version_field is None else self.version_field.as_dict()
+            ),
+            continuation=(
+                None if self.continuation is None else self.continuation.as_dict()
+            ),
+            multiline_quoted=self.multiline_quoted,
+            multiline_max_lines=self.multiline_max_lines,
         )
-        self._parser = _xlineparse.Parser(json.dumps(jsonable))
-        # Set up enum conversion map, maybe there's a more efficient way of doing this..
-        self._enum_conversions: dict[str, dict[int, StrEnumField | IntEnumField]] = (
-            defaultdict(dict)
-        )
-        for line in self.lines:
-            for i, field in enumerate(line.fields, start=1):
-                if isinstance(field, (StrEnumField, IntEnumField)):
-                    self._enum_conversions[line.name][i] = field
+        return json.dumps(jsonable)
 
     @staticmethod
     def from_type(
         *,
         delimiter: str,
         quote_str: str | None = None,  # do we quote strings like "foo"
+        quote_mode: QuoteMode = "lenient",
         trailing_delimiter: bool = False,
         coerce_empty_quoted: bool = False,
+        discriminator: Discriminator = FieldDiscriminator(),
+        groups: list[Grouper] | None = None,
+        trailer_checks: list[TrailerCheck] | None = None,
+        encoding: str = "utf-8",
+        encoding_errors: Literal["strict", "replace"] = "strict",
+        strip_bom: bool = True,
+        line_terminator: str | None = None,
+        include_raw: bool = False,
+        component_delimiter: str | None = None,
+        release_str: str | None = None,
+        continuation: Continuation | None = None,
+        multiline_quoted: bool = False,
+        multiline_max_lines: int = 1000,
+        validators: dict[tuple[str | tuple[str, ...], int], Validator] | None = None,
         t: Any,  # some day, we can use TypeForm here...
     ) -> Schema:
+        has_name = not isinstance(discriminator, NoDiscriminator)
         if get_origin(t) is Union or get_origin(t) is UnionType:
-            lines = [convert_line_type(arg) for arg in get_args(t)]
+            lines = [convert_line_type(arg, has_name) for arg in get_args(t)]
         else:
-            lines = [convert_line_type(t)]
+            lines = [convert_line_type(t, has_name)]
         return Schema(
             delimiter=delimiter,
             quote_str=quote_str,
+            quote_mode=quote_mode,
             trailing_delimiter=trailing_delimiter,
             coerce_empty_quoted=coerce_empty_quoted,
+            discriminator=discriminator,
             lines=lines,
+            groups=groups or [],
+            trailer_checks=trailer_checks or [],
+            encoding=encoding,
+            encoding_errors=encoding_errors,
+            strip_bom=strip_bom,
+            line_terminator=line_terminator,
+            include_raw=include_raw,
+            component_delimiter=component_delimiter,
+            release_str=release_str,
+            continuation=continuation,
+            multiline_quoted=multiline_quoted,
+            multiline_max_lines=multiline_max_lines,
+            validators=validators or {},
         )
 
     def parse_line(self, line: str) -> tuple[Any, ...]:
         try:
             parsed = self._parser.parse_line(line)
         except ValueError as e:
-            line = line.rstrip("\n")
+            line = line.rstrip("\r\n")
             raise LineParseError(f"Failed to parse line: '{line}'\n {e.args[0]}")
-        if self._enum_conversions:
-            enum_conversion: dict[int, StrEnumField | IntEnumField] = (
-                self._enum_conversions[parsed[0]]
-            )
-            parsed_mut = list(parsed)
-            for i, converter in enum_conversion.items():
-                v = parsed_mut[i]
-                if v is not None:
-                    parsed_mut[i] = converter.cls._value2member_map_[v]
-            parsed = tuple(parsed_mut)
         return parsed  # type: ignore
 
+    def parse_line_bytes(self, line: bytes) -> tuple[Any, ...]:
+        try:
+            parsed = self._parser.parse_line_bytes(line)
+        except ValueError as e:
+            line = line.rstrip(b"\r\n")
+            raise LineParseError(f"Failed to parse line: {line!r}\n {e.args[0]}")
+        return parsed  # type: ignore
+
+    # As {"line": name, "fields": {name: value}}, fields without a Line.field_names
+    # name are keyed by their index. Decimals are strings, datetimes etc. are
+    # ISO 8601. Enum members and validators aren't used
+    def parse_line_to_json(self, line: str) -> str:
+        try:
+            return self._parser.parse_line_to_json(line)  # type: ignore
+        except ValueError as e:
+            line = line.rstrip("\r\n")
+            raise LineParseError(f"Failed to parse line: '{line}'\n {e.args[0]}")
+
+    def _parse_batch(
+        self,
+        parse: Callable[..., Any],
+        arg: Any,
+        on_error: OnError,
+        stats: Stats | None,
+    ) -> Any:
+        try:
+            result = parse(arg, on_error, stats is not None)
+        except ValueError as e:
+            raise batch_error(e)
+        if stats is not None:
+            result, (stats.lines, stats.errors, by_line) = result
+            stats.by_line = {}
+            for line, (count, null_counts, min_values, max_values) in zip(
+                self.lines, by_line
+            ):
+                line_stats = LineStats(
+                    count=count,
+                    null_counts=null_counts,
+                    min_values=min_values,
+                    max_values=max_values,
+                )
+                if line.name in stats.by_line:  # another version of the line
+                    line_stats = merge_line_stats(stats.by_line[line.name], line_stats)
+                stats.by_line[line.name] = line_stats
+        if on_error == "raise":
+            return result
+        parsed, rejects = result
+        return parsed, self._rejects(rejects)
+
+    @overload
+    def parse_lines(
+        self,
+        lines: Iterable[str],
+        on_error: Literal["raise"] = ...,
+        stats: Stats | None = None,
+    ) -> Parsed: ...
+    @overload
+    def parse_lines(
+        self,
+        lines: Iterable[str],
+        on_error: Literal["collect"],
+        stats: Stats | None = None,
+    ) -> Collected: ...
+    def parse_lines(
+        self,
+        lines: Iterable[str],
+        on_error: OnError = "raise",
+        stats: Stats | None = None,
+    ) -> Parsed | Collected:
+        return self._parse_batch(  # type: ignore
+            self._parser.parse_lines, lines, on_error, stats
+        )
+
+    @overload
+    def parse_lines_bytes(
+        self,
+        lines: Iterable[bytes],
+        on_error: Literal["raise"] = ...,
+        stats: Stats | None = None,
+    ) -> Parsed: ...
+    @overload
+    def parse_lines_bytes(
+        self,
+        lines: Iterable[bytes],
+        on_error: Literal["collect"],
+        stats: Stats | None = None,
+    ) -> Collected: ...
+    def parse_lines_bytes(
+        self,
+        lines: Iterable[bytes],
+        on_error: OnError = "raise",
+        stats: Stats | None = None,
+    ) -> Parsed | Collected:
+        return self._parse_batch(  # type: ignore
+            self._parser.parse_lines_bytes, lines, on_error, stats
+        )
+
+    @overload
+    def parse_lines_grouped(
+        self,
+        lines: Iterable[str],
+        on_error: Literal["raise"] = ...,
+        stats: Stats | None = None,
+    ) -> Grouped: ...
+    @overload
+    def parse_lines_grouped(
+        self,
+        lines: Iterable[str],
+        on_error: Literal["collect"],
+        stats: Stats | None = None,
+    ) -> CollectedGrouped: ...
+    def parse_lines_grouped(
+        self,
+        lines: Iterable[str],
+        on_error: OnError = "raise",
+        stats: Stats | None = None,
+    ) -> Grouped | CollectedGrouped:
+        result = self._parse_batch(
+            self._parser.parse_lines_grouped, lines, on_error, stats
+        )
+        if on_error == "raise":
+            return self._grouped(result)
+        parsed, rejects = result
+        return self._grouped(parsed), rejects
+
+    # Lines of the same name in different versions share a list
+    def _grouped(self, by_line: list[Parsed]) -> Grouped:
+        grouped: Grouped = {}
+        for line, parsed in zip(self.lines, by_line):
+            grouped.setdefault(line.name, []).extend(parsed)
+        return grouped
+
+    # gzip and zstd files are decompressed as they're read
+    @overload
+    def parse_file(
+        self,
+        path: str | os.PathLike[str],
+        on_error: Literal["raise"] = ...,
+        stats: Stats | None = None,
+        progress: Progress | None = None,
+        progress_every: int = ...,
+    ) -> Parsed: ...
+    @overload
+    def parse_file(
+        self,
+        path: str | os.PathLike[str],
+        on_error: Literal["collect"],
+        stats: Stats | None = None,
+        progress: Progress | None = None,
+        progress_every: int = ...,
+    ) -> Collected: ...
+    # progress is called every progress_every lines, and at the end
+    def parse_file(
+        self,
+        path: str | os.PathLike[str],
+        on_error: OnError = "raise",
+        stats: Stats | None = None,
+        progress: Progress | None = None,
+        progress_every: int = 100_000,
+    ) -> Parsed | Collected:
+        def parse_file(path: str, on_error: OnError, with_stats: bool) -> Any:
+            return self._parser.parse_file(
+                path, on_error, with_stats, progress, progress_every
+            )
+
+        return self._parse_batch(  # type: ignore
+            parse_file, os.fspath(path), on_error, stats
+        )
+
+    @overload
+    def parse_file_to_ndjson(
+        self,
+        in_path: str | os.PathLike[str],
+        out_path: str | os.PathLike[str],
+        on_error: Literal["raise"] = ...,
+    ) -> int: ...
+    @overload
+    def parse_file_to_ndjson(
+        self,
+        in_path: str | os.PathLike[str],
+        out_path: str | os.PathLike[str],
+        on_error: Literal["collect"],
+    ) -> tuple[int, list[Reject]]: ...
+    # Writes each line as .parse_line_to_json(), one per line, returns how
+    # many were written
+    def parse_file_to_ndjson(
+        self,
+        in_path: str | os.PathLike[str],
+        out_path: str | os.PathLike[str],
+        on_error: OnError = "raise",
+    ) -> int | tuple[int, list[Reject]]:
+        try:
+            result = self._parser.parse_file_to_ndjson(
+                os.fspath(in_path), os.fspath(out_path), on_error
+            )
+        except ValueError as e:
+            raise batch_error(e)
+        if on_error == "raise":
+            return result  # type: ignore
+        written, rejects = result
+        return written, self._rejects(rejects)
+
+    @overload
+    def parse_file_to_parquet(
+        self,
+        in_path: str | os.PathLike[str],
+        out_dir: str | os.PathLike[str],
+        on_error: Literal["raise"] = ...,
+        row_group_size: int = ...,
+    ) -> list[str]: ...
+    @overload
+    def parse_file_to_parquet(
+        self,
+        in_path: str | os.PathLike[str],
+        out_dir: str | os.PathLike[str],
+        on_error: Literal["collect"],
+        row_group_size: int = ...,
+    ) -> tuple[list[str], list[Reject]]: ...
+    # Writes a Parquet file per line to out_dir, eg: "TX_02.parquet", columns
+    # are named as in .parse_line_to_json(). Decimals are decimal128 with a
+    # scale of round_decimal_places, max_decimal_places or 9. Returns the
+    # paths written, lines with no rows in the file get no file. Needs the
+    # extension built with the parquet feature
+    def parse_file_to_parquet(
+        self,
+        in_path: str | os.PathLike[str],
+        out_dir: str | os.PathLike[str],
+        on_error: OnError = "raise",
+        row_group_size: int = 100_000,
+    ) -> list[str] | tuple[list[str], list[Reject]]:
+        if not hasattr(self._parser, "parse_file_to_parquet"):
+            raise NotImplementedError(
+                "xlineparse was built without Parquet support, build it with: "
+                "maturin develop --features parquet"
+            )
+        try:
+            result = self._parser.parse_file_to_parquet(
+                os.fspath(in_path), os.fspath(out_dir), on_error, row_group_size
+            )
+        except ValueError as e:
+            raise batch_error(e)
+        if on_error == "raise":
+            return result  # type: ignore
+        paths, rejects = result
+        return paths, self._rejects(rejects)
+
+    @overload
+    def parse_file_async(
+        self,
+        path: str | os.PathLike[str],
+        on_error: Literal["raise"] = ...,
+        chunk_size: int = ...,
+    ) -> AsyncIterator[Parsed]: ...
+    @overload
+    def parse_file_async(
+        self,
+        path: str | os.PathLike[str],
+        on_error: Literal["collect"],
+        chunk_size: int = ...,
+    ) -> AsyncIterator[Collected]: ...
+    # As .parse_file(), in chunks of lines parsed by a background thread, so
+    # the event loop isn't blocked
+    async def parse_file_async(
+        self,
+        path: str | os.PathLike[str],
+        on_error: OnError = "raise",
+        chunk_size: int = 10_000,
+    ) -> AsyncIterator[Parsed | Collected]:
+        chunks = self._parser.iter_file(os.fspath(path), on_error, chunk_size)
+        while True:
+            try:
+                chunk = await asyncio.to_thread(next, chunks, None)
+            except ValueError as e:
+                raise batch_error(e)
+            if chunk is None:
+                return
+            if on_error == "raise":
+                yield chunk
+            else:
+                parsed, rejects = chunk
+                yield parsed, self._rejects(rejects)
+
+    def parse_document(self, lines: Iterable[str]) -> list[tuple[Any, ...] | Group]:
+        try:
+            return self._parser.parse_document(lines)  # type: ignore
+        except ValueError as e:
+            raise batch_error(e)
+
+    def _rejects(self, rejects: list[tuple[str, int, ValueError]]) -> list[Reject]:
+        return [
+            Reject(line=line, line_no=line_no, error=batch_error(e))
+            for line, line_no, e in rejects
+        ]
+
+    # As .parse_line(), but only checks the line. Returns None, or every problem
+    def validate_line(self, line: str) -> list[str] | None:
+        return self._parser.validate_line(line)  # type: ignore
+
+    # As .parse_lines(on_error="collect") but only checks the lines, a line may
+    # have more than one Reject
+    def validate_lines(self, lines: Iterable[str]) -> list[Reject]:
+        return self._rejects(self._parser.validate_lines(lines))
+
+    def validate_file(self, path: str | os.PathLike[str]) -> list[Reject]:
+        return self._rejects(self._parser.validate_file(os.fspath(path)))
+
+    # The parts of the line as .parse_line() sees them, unquoted
+    def split(self, line: str) -> list[str]:
+        return self._parser.split(line)  # type: ignore
+
+    # As (part, is_quoted), eg: to tell '""' from ''
+    def split_quoted(self, line: str) -> list[tuple[str, bool]]:
+        return self._parser.split_quoted(line)  # type: ignore
+
     def parse_first(self, line: str) -> str:
         return self._parser.parse_first(line)  # type: ignore
+
+    # The first n fields, including any line name, for routing lines cheaply
+    def parse_prefix(self, line: str, n: int) -> list[str]:
+        return self._parser.parse_prefix(line, n)  # type: ignore
+
+    # A random line that parses as the named line, eg: for test fixtures
+    def generate_line(
+        self, line_name: str | tuple[str, ...], seed: int | None = None
+    ) -> str:
+        return self._parser.generate_line(line_name, seed)  # type: ignore
+
+    # Of random lines picked from line_names (default all), sequence fields
+    # count up, but trailer checks aren't reconciled
+    def generate_file(
+        self,
+        path: str | os.PathLike[str],
+        n_lines: int,
+        seed: int | None = None,
+        line_names: list[str | tuple[str, ...]] | None = None,
+    ) -> None:
+        self._parser.generate_file(os.fspath(path), n_lines, seed, line_names)
+
+    # Python source with a tuple[...] alias per line, and their union as ParsedLine
+    def generate_stub(self) -> str:
+        imports: set[str] = set()
+        has_name = not isinstance(self.discriminator, NoDiscriminator)
+        aliases: dict[str, str] = {}
+        for line in self.lines:
+            args = []
+            names = name_list(line.name)
+            matched = [names]
+            if not line.normalize_aliases:
+                matched += [name_list(a) for a in line.aliases]
+            for i, name in enumerate(names if has_name else []):
+                if line.match == "exact":
+                    imports.add("from typing import Literal")
+                    options = dict.fromkeys(m[i] for m in matched if len(m) > i)
+                    args.append(f"Literal[{', '.join(map(repr, options))}]")
+                else:
+                    args.append("str")
+            args += [field_annotation(field, imports) for field in line.fields]
+            if line.keep_extra_fields:
+                args.append("tuple[str, ...]")
+            alias = stub_name(line.name) if has_name else "ParsedLine"
+            while alias in aliases:
+                alias = f"{alias}_"
+            annotation = f"tuple[{', '.join(args)}]"
+            if self.include_raw:  # with the original text of each part
+                annotation = f"tuple[{annotation}, tuple[str, ...]]"
+            aliases[alias] = annotation
+        out = ["from __future__ import annotations", ""]
+        out += sorted(i for i in imports if i.startswith("import "))
+        out += sorted(i for i in imports if i.startswith("from "))
+        out.append("")
+        out += [f"{alias} = {annotation}" for alias, annotation in aliases.items()]
+        if has_name:
+            out.append(f"ParsedLine = {' | '.join(aliases)}")
+        return "\n".join(out) + "\n"
+
+
+# Fixed-width lines from the DISPLAY items of a COBOL copybook, eg:
+#   01 CUSTOMER.
+#      05 NAME    PIC X(10).
+#      05 BALANCE PIC S9(5)V99.
+# With many 01 records, the discriminator selects them by line_names, keyed by
+# record name, eg: {"CUSTOMER": "C"}
+def schema_from_copybook(
+    text: str,
+    *,
+    discriminator: BytesDiscriminator | NoDiscriminator = NoDiscriminator(),
+    line_names: dict[str, str] | None = None,
+) -> Schema:
+    records = parse_copybook(text)
+    if not records:
+        raise ValueError("The copybook has no 01 records")
+    if isinstance(discriminator, NoDiscriminator) and len(records) > 1:
+        raise ValueError("Many records need a BytesDiscriminator and line_names")
+    lines = []
+    for record in records:
+        name = ""
+        if isinstance(discriminator, BytesDiscriminator):
+            if record.name not in (line_names or {}):
+                raise ValueError(f"No line name for record {record.name}")
+            name = (line_names or {})[record.name]
+        lines.append(
+            Line(
+                name=name,
+                fields=[copybook_field(item) for item in record.items],
+                field_names=tuple(item.name for item in record.items),
+                widths=tuple(item.width for item in record.items),
+            )
+        )
+    return Schema(delimiter="", discriminator=discriminator, lines=lines)
+
+
+def copybook_field(item: Item) -> Field:
+    zoned = item.signed and not item.sign_separate
+    if item.kind == "int":
+        return IntField(zoned=zoned)
+    if item.kind == "decimal":
+        return DecimalField(implied_decimal_places=item.decimal_places, zoned=zoned)
+    return StrField(required=False, pad_char=" ", pad_side="right")
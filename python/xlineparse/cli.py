@@ -0,0 +1,56 @@
+from __future__ import annotations
+
+import argparse
+import os
+import sys
+
+from . import xlineparse as _xlineparse  # type: ignore
+from . import batch_error
+
+
+def validate(schema_path: str, paths: list[str]) -> int:
+    try:
+        with open(schema_path) as f:
+            parser = _xlineparse.Parser(f.read(), base_dir=os.path.dirname(schema_path))
+    except (OSError, ValueError) as e:
+        print(f"{schema_path}: {e}", file=sys.stderr)
+        return 2
+    n_errors = 0
+    for path in paths:
+        try:
+            # Only the rejects are kept, so big files don't fill memory
+            rejects, n_lines = parser.validate_file(path, count_lines=True)
+        except (OSError, ValueError) as e:
+            print(f"{path}: {e}", file=sys.stderr)
+            n_errors += 1
+            continue
+        for _, line_no, e in rejects:
+            error = batch_error(e)
+            # The message starts "Failed to parse line: ..." and the detail follows
+            detail = str(error).split("\n ", 1)[-1]
+            print(f"{path}:{line_no}: {detail}")
+        # A line can have more than one reject
+        n_failed = len({line_no for _, line_no, _ in rejects})
+        print(f"{path}: {n_lines} lines, {n_failed} errors")
+        n_errors += n_failed
+    return 1 if n_errors else 0
+
+
+def main(argv: list[str] | None = None) -> int:
+    parser = argparse.ArgumentParser(prog="xlineparse")
+    commands = parser.add_subparsers(dest="command", required=True)
+    validate_parser = commands.add_parser(
+        "validate", help="check each line of the files against a schema"
+    )
+    validate_parser.add_argument(
+        "--schema",
+        required=True,
+        help="JSON, as from Schema.to_json(), includes are relative to it",
+    )
+    validate_parser.add_argument("files", nargs="+")
+    args = parser.parse_args(argv)
+    return validate(args.schema, args.files)
+
+
+if __name__ == "__main__":
+    sys.exit(main())
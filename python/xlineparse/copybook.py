@@ -0,0 +1,216 @@
+from __future__ import annotations
+
+from dataclasses import dataclass, field as dataclass_field, replace
+import re
+from typing import Iterator, Literal
+
+# Clauses that don't change the layout of DISPLAY data
+IGNORED = {
+    "IS",
+    "USAGE",
+    "DISPLAY",
+    "JUST",
+    "JUSTIFIED",
+    "RIGHT",
+    "LEFT",
+    "SYNC",
+    "SYNCHRONIZED",
+    "BLANK",
+    "WHEN",
+    "ZERO",
+    "ZEROS",
+    "ZEROES",
+    "GLOBAL",
+    "EXTERNAL",
+    "TIMES",
+    "CHARACTER",
+}
+BINARY_USAGES = {"BINARY", "PACKED-DECIMAL", "POINTER", "INDEX"}
+
+
+@dataclass(frozen=True, kw_only=True)
+class Item:
+    name: str  # lowercase with "_" for "-", distinct in the record
+    width: int  # in chars
+    kind: Literal["str", "int", "decimal"]
+    # S, overpunched on the last digit unless sign_separate
+    signed: bool = False
+    sign_separate: bool = False  # SIGN LEADING SEPARATE, eg: "-0012"
+    decimal_places: int = 0  # after V
+
+
+@dataclass(frozen=True, kw_only=True)
+class Record:
+    name: str  # as in the copybook, eg: "CUSTOMER-RECORD"
+    items: list[Item]
+
+
+@dataclass(kw_only=True)
+class Entry:
+    level: int
+    name: str
+    pic: str | None = None
+    occurs: int = 1
+    sign_separate: bool = False
+    children: list[Entry] = dataclass_field(default_factory=list)
+
+
+# Each 01 (or 77) record with its elementary items in order, OCCURS repeated
+def parse_copybook(text: str) -> list[Record]:
+    roots: list[Entry] = []
+    stack: list[Entry] = []
+    for tokens in entries(text):
+        entry = parse_entry(tokens)
+        if entry is None:
+            continue
+        if entry.level in (1, 77):
+            roots.append(entry)
+            stack = [entry]
+            continue
+        while stack and stack[-1].level >= entry.level:
+            stack.pop()
+        if not stack:
+            raise ValueError(f"{entry.name} isn't in an 01 record")
+        if stack[-1].pic is not None:
+            raise ValueError(f"{stack[-1].name} has a PIC and items under it")
+        stack[-1].children.append(entry)
+        stack.append(entry)
+    return [
+        Record(name=root.name, items=distinct(list(flatten(root, ""))))
+        for root in roots
+    ]
+
+
+# The tokens of each entry, up to its period, without comments. In fixed
+# format, without the sequence (1-6), indicator (7) and identification (73-80)
+# areas
+def entries(text: str) -> Iterator[list[str]]:
+    lines = text.splitlines()
+    fixed = all(
+        re.match(r"(\d{6}| {6})[ *\-/]", line) or not line.strip() for line in lines
+    )
+    tokens: list[str] = []
+    for line in lines:
+        if fixed:
+            if line[6:7] in ("*", "/"):
+                continue
+            line = line[7:72]
+        line = line.split("*>")[0]
+        if line.lstrip().startswith("*"):
+            continue
+        for match in re.finditer(r"'[^']*'|\"[^\"]*\"|\S+", line):
+            token = match.group()
+            if token.startswith(("'", '"')) or not token.endswith("."):
+                tokens.append(token)
+                continue
+            if token != ".":
+                tokens.append(token[:-1])
+            yield tokens
+            tokens = []
+    if tokens:
+        raise ValueError(f"Entry without a period: {' '.join(tokens)}")
+
+
+def parse_entry(tokens: list[str]) -> Entry | None:
+    if not tokens[0].isdigit():
+        raise ValueError(f"Entry doesn't start with a level: {' '.join(tokens)}")
+    level = int(tokens[0])
+    if level == 88:  # condition names don't take up any chars
+        return None
+    if level == 66:
+        raise ValueError("RENAMES isn't supported")
+    rest = [token.upper() for token in tokens[1:]]
+    name = "FILLER"
+    if rest and rest[0] not in ("PIC", "PICTURE", "OCCURS", "REDEFINES"):
+        name, rest = rest[0], rest[1:]
+    entry = Entry(level=level, name=name)
+    i = 0
+    while i < len(rest):
+        clause = rest[i]
+        if clause in ("PIC", "PICTURE"):
+            i += 1
+            if rest[i] == "IS":
+                i += 1
+            entry.pic = rest[i]
+        elif clause == "OCCURS":
+            i += 1
+            entry.occurs = int(rest[i])
+            if rest[i + 1 : i + 2] == ["TO"] or "DEPENDING" in rest:
+                raise ValueError(f"{name}: OCCURS DEPENDING ON isn't supported")
+        elif clause in ("VALUE", "VALUES"):
+            i += 1
+            if rest[i] == "IS":
+                i += 1
+        elif clause in ("INDEXED", "KEY", "ASCENDING", "DESCENDING"):
+            # The index/key name follows
+            while rest[i] in ("INDEXED", "KEY", "ASCENDING", "DESCENDING", "BY", "IS"):
+                i += 1
+        elif clause in ("SIGN", "LEADING", "TRAILING"):
+            sign = set()
+            while i < len(rest) and rest[i] in ("SIGN", "IS", "LEADING", "TRAILING"):
+                sign.add(rest[i])
+                i += 1
+            separate = rest[i : i + 1] == ["SEPARATE"]
+            if separate != ("LEADING" in sign):
+                raise ValueError(
+                    f"{name}: only SIGN LEADING SEPARATE and overpunched TRAILING "
+                    "signs are supported"
+                )
+            entry.sign_separate = separate
+            if not separate:
+                continue
+        elif clause == "REDEFINES":
+            raise ValueError(f"{name}: REDEFINES isn't supported")
+        elif clause.startswith("COMP") or clause in BINARY_USAGES:
+            raise ValueError(f"{name}: USAGE {clause} isn't supported, only DISPLAY")
+        elif clause not in IGNORED:
+            raise ValueError(f"{name}: can't parse {clause!r}")
+        i += 1
+    return entry
+
+
+def flatten(entry: Entry, suffix: str) -> Iterator[Item]:
+    for i in range(entry.occurs):
+        occurrence = suffix if entry.occurs == 1 else f"{suffix}_{i + 1}"
+        if entry.pic is not None:
+            yield to_item(entry, occurrence)
+        elif not entry.children:
+            raise ValueError(f"{entry.name} has no PIC")
+        for child in entry.children:
+            yield from flatten(child, occurrence)
+
+
+def to_item(entry: Entry, suffix: str) -> Item:
+    assert entry.pic is not None
+    name = entry.name.lower().replace("-", "_") + suffix
+    # Eg: "S9(5)V99" -> "S99999V99"
+    pic = re.sub(r"(.)\((\d+)\)", lambda m: m[1] * int(m[2]), entry.pic)
+    if re.fullmatch(r"[XA9]+", pic) and not pic.isdigit():
+        return Item(name=name, width=len(pic), kind="str")
+    match = re.fullmatch(r"(S?)(9*)(?:V(9*))?", pic)
+    if match is None or not (match[2] or match[3]):
+        raise ValueError(f"{entry.name}: PIC {entry.pic} isn't supported")
+    signed = match[1] == "S"
+    if entry.sign_separate and not signed:
+        raise ValueError(f"{entry.name}: SIGN SEPARATE needs an S in the PIC")
+    places = len(match[3] or "")
+    return Item(
+        name=name,
+        width=len(match[2]) + places + entry.sign_separate,
+        kind="decimal" if match[3] is not None else "int",
+        signed=signed,
+        sign_separate=entry.sign_separate,
+        decimal_places=places,
+    )
+
+
+# Repeated names get "_2", "_3"... eg: FILLERs
+def distinct(items: list[Item]) -> list[Item]:
+    seen: dict[str, int] = {}
+    distinct_items = []
+    for item in items:
+        seen[item.name] = seen.get(item.name, 0) + 1
+        if seen[item.name] > 1:
+            item = replace(item, name=f"{item.name}_{seen[item.name]}")
+        distinct_items.append(item)
+    return distinct_items
@@ -0,0 +1,203 @@
+from __future__ import annotations
+
+from dataclasses import dataclass
+import decimal
+import json
+from typing import Any, Literal
+
+ChangeKind = Literal[
+    "line_added",
+    "line_removed",
+    "field_added",
+    "field_removed",
+    "type_changed",
+    "tightened",
+    "loosened",
+    "changed",
+]
+LineName = str | tuple[str, ...]
+
+MIN_KEYS = {"min_length", "min_value", "exclusive_min"}
+MAX_KEYS = {
+    "max_length",
+    "max_value",
+    "exclusive_max",
+    "max_digits",
+    "max_decimal_places",
+}
+# Values that are allowed, removing any tightens the field
+SET_KEYS = {
+    "values",
+    "true_values",
+    "false_values",
+    "null_values",
+    "format",
+    "aliases",
+}
+# Limits on what's allowed, None is no limit
+LIMIT_KEYS = {"codes", "not_equal", "invalid_characters", "allowed_characters"}
+# Turning these off tightens the field or line
+ALLOW_KEYS = {
+    "allow_nan",
+    "allow_inf",
+    "allow_exponent",
+    "allow_2400",
+    "allow_extra_fields",
+    "allow_missing_fields",
+}
+
+
+@dataclass(frozen=True, kw_only=True)
+class SchemaChange:
+    kind: ChangeKind
+    line: LineName | None = None  # None for changes to the whole schema
+    field: int | None = None  # index into the line's fields
+    key: str | None = None  # the setting that changed, eg: "max_length"
+    old: Any = None
+    new: Any = None
+    # Lines that parse with the old schema might not with the new one
+    breaking: bool
+
+
+@dataclass(frozen=True, kw_only=True)
+class SchemaDiff:
+    changes: list[SchemaChange]
+
+    @property
+    def backward_compatible(self) -> bool:
+        return not any(change.breaking for change in self.changes)
+
+
+# Of two Schema.to_json() outputs, fields are compared by position. Changes
+# that aren't clearly a tightening or loosening are taken as breaking
+def diff_schemas(old_json: str, new_json: str) -> SchemaDiff:
+    old, new = json.loads(old_json), json.loads(new_json)
+    changes: list[SchemaChange] = []
+    for key in sorted((old.keys() | new.keys()) - {"lines"}):
+        changes += diff_setting(key, old.get(key), new.get(key), line=None, field=None)
+    old_lines = {line_key(line): line for line in old["lines"]}
+    new_lines = {line_key(line): line for line in new["lines"]}
+    for key, old_line in old_lines.items():
+        name = line_name(old_line)
+        if key not in new_lines:
+            changes.append(SchemaChange(kind="line_removed", line=name, breaking=True))
+        else:
+            changes += diff_line(name, old_line, new_lines[key])
+    for key, new_line in new_lines.items():
+        if key not in old_lines:
+            name = line_name(new_line)
+            changes.append(SchemaChange(kind="line_added", line=name, breaking=False))
+    return SchemaDiff(changes=changes)
+
+
+def line_key(line: dict[str, Any]) -> tuple[LineName, tuple[str, ...]]:
+    return line_name(line), tuple(line.get("versions", ()))
+
+
+def line_name(line: dict[str, Any]) -> LineName:
+    name = line["name"]
+    return name if isinstance(name, str) else tuple(name)
+
+
+def diff_line(
+    name: LineName, old: dict[str, Any], new: dict[str, Any]
+) -> list[SchemaChange]:
+    changes: list[SchemaChange] = []
+    for key in sorted((old.keys() | new.keys()) - {"name", "fields", "versions"}):
+        changes += diff_setting(key, old.get(key), new.get(key), line=name, field=None)
+    old_fields, new_fields = old["fields"], new["fields"]
+    for i, (old_field, new_field) in enumerate(zip(old_fields, new_fields)):
+        if old_field["kind"] != new_field["kind"]:
+            changes.append(
+                SchemaChange(
+                    kind="type_changed",
+                    line=name,
+                    field=i,
+                    old=old_field["kind"],
+                    new=new_field["kind"],
+                    breaking=True,
+                )
+            )
+            continue
+        for key in sorted((old_field.keys() | new_field.keys()) - {"kind"}):
+            old_value, new_value = old_field.get(key), new_field.get(key)
+            changes += diff_setting(key, old_value, new_value, line=name, field=i)
+    for i, field in enumerate(new_fields[len(old_fields) :], len(old_fields)):
+        # Old lines won't have it, fine if it can be missing and empty
+        breaking = field["required"] or not new.get("allow_missing_fields", False)
+        changes.append(
+            SchemaChange(
+                kind="field_added", line=name, field=i, new=field, breaking=breaking
+            )
+        )
+    for i, field in enumerate(old_fields[len(new_fields) :], len(new_fields)):
+        breaking = not new.get("allow_extra_fields", False)
+        changes.append(
+            SchemaChange(
+                kind="field_removed", line=name, field=i, old=field, breaking=breaking
+            )
+        )
+    return changes
+
+
+def diff_setting(
+    key: str, old: Any, new: Any, *, line: LineName | None, field: int | None
+) -> list[SchemaChange]:
+    if old == new:
+        return []
+    tightened: bool | None = None
+    if key == "required" or key == "unique_key":
+        tightened = bool(new) and not old
+    elif key in ALLOW_KEYS:
+        tightened = bool(old) and not new
+    elif key == "severity":
+        tightened = new == "error"
+    elif key in MIN_KEYS | MAX_KEYS:
+        if old is None or new is None:
+            tightened = old is None
+        elif key in MIN_KEYS:
+            tightened = to_decimal(new) > to_decimal(old)
+        else:
+            tightened = to_decimal(new) < to_decimal(old)
+    elif key in SET_KEYS:
+        old_set, new_set = as_set(old), as_set(new)
+        if old_set <= new_set or new_set <= old_set:
+            tightened = not old_set <= new_set
+    elif key in LIMIT_KEYS:
+        if old is None or new is None:
+            tightened = old is None
+    if tightened is None:
+        return [
+            SchemaChange(
+                kind="changed",
+                line=line,
+                field=field,
+                key=key,
+                old=old,
+                new=new,
+                breaking=True,
+            )
+        ]
+    return [
+        SchemaChange(
+            kind="tightened" if tightened else "loosened",
+            line=line,
+            field=field,
+            key=key,
+            old=old,
+            new=new,
+            breaking=tightened,
+        )
+    ]
+
+
+def to_decimal(value: int | float | str) -> decimal.Decimal:
+    return decimal.Decimal(str(value))
+
+
+def as_set(value: Any) -> set[Any]:
+    if value is None:
+        return set()
+    if isinstance(value, str):
+        value = [value]
+    return {json.dumps(v, sort_keys=True) for v in value}
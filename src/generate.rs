@@ -0,0 +1,411 @@
+// Random lines that parse against the schema, eg: for test fixtures
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::borrow::Cow;
+
+use {
+    part_to_value, required, BoolField, CurrencyField, DatetimeField, DecimalField, Discriminator,
+    Error, Field, FloatField, Format, Formats, IntField, LineMatch, Parser, Part, PercentField,
+    QuoteMode, StrField, Syntax, CURRENCY_SYMBOLS,
+};
+
+// Each value is checked with part_to_value, with this many tries to get a good one
+const ATTEMPTS: usize = 100;
+const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+pub struct Generator<'a> {
+    parser: &'a Parser,
+    rng: StdRng,
+    // Shared by all the sequence fields, as when parsing
+    next_sequence: i128,
+}
+impl<'a> Generator<'a> {
+    pub fn new(parser: &'a Parser, seed: Option<u64>) -> Self {
+        Generator {
+            parser,
+            rng: match seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+            next_sequence: parser
+                .plan
+                .sequence
+                .map_or(0, |sequence| sequence.start as i128),
+        }
+    }
+    // Of one of the lines, picked at random
+    pub fn random_line(&mut self, line_is: &[usize]) -> Result<String, Error> {
+        let line_i = *line_is
+            .choose(&mut self.rng)
+            .ok_or("No lines to pick from")?;
+        self.line(line_i)
+    }
+    pub fn line(&mut self, line_i: usize) -> Result<String, Error> {
+        let schema = &self.parser.schema;
+        let schema_line = &schema.lines[line_i];
+        if self.parser.plan.fixed_width {
+            return Err("Can't generate fixed-width lines".into());
+        }
+        let mut parts = vec![];
+        for field in &schema_line.fields {
+            let value = self.field(field)?;
+            parts.push(self.quote(&value));
+        }
+        let names = match schema_line.line_match {
+            LineMatch::Exact | LineMatch::Prefix => &schema_line.names,
+            LineMatch::Regex => return Err("Can't generate lines matched by a regex".into()),
+        };
+        let names = names.iter().map(|name| self.quote(&self.escape(name, &[])));
+        match schema.discriminator {
+            Discriminator::Field { index } if index <= parts.len() => {
+                parts.splice(index..index, names);
+            }
+            Discriminator::Field { index } => {
+                return Err(format!("Line {:?} has no field {}", schema_line.names, index).into())
+            }
+            Discriminator::Bytes { .. } => {
+                return Err("Can't generate lines for a bytes discriminator".into())
+            }
+            Discriminator::None => {}
+        }
+        let delimiter = self.parser.plan.delimiter?.to_string();
+        let mut line = parts.join(&delimiter);
+        if schema_line
+            .trailing_delimiter
+            .unwrap_or(schema.trailing_delimiter)
+        {
+            line.push_str(&delimiter);
+        }
+        let version = schema_line.versions.first().map(|v| v.as_str());
+        let parsed = self.parser.parse_checked(&line, version, None)?;
+        if parsed.line_index != line_i {
+            return Err(format!(
+                "Generated line '{}' matches {:?} rather than {:?}",
+                line, schema.lines[parsed.line_index].names, schema_line.names
+            )
+            .into());
+        }
+        if let Some(sequence) = self.parser.plan.sequence {
+            if self.parser.plan.sequence_fields.contains_key(&line_i) {
+                self.next_sequence += sequence.step as i128;
+            }
+        }
+        Ok(line)
+    }
+    // The escaped, but unquoted, value of the field
+    fn field(&mut self, field: &Field) -> Result<String, Error> {
+        let Field::Composite(composite_field) = field else {
+            return self.escaped(field, &[]);
+        };
+        if !composite_field.required && self.rng.gen_bool(0.1) {
+            return Ok(String::new());
+        }
+        let component_delimiter = self.parser.plan.component_delimiter.unwrap_or_default();
+        let components = composite_field
+            .fields
+            .iter()
+            .map(|field| self.escaped(field, &[component_delimiter]))
+            .collect::<Result<Vec<String>, Error>>()?;
+        Ok(components.join(&component_delimiter.to_string()))
+    }
+    // Escaped as with escape, the values are checked with part_to_value
+    fn escaped(&mut self, field: &Field, extra: &[char]) -> Result<String, Error> {
+        let plan = &self.parser.plan;
+        let syntax = Syntax {
+            coerce_empty_quoted: false,
+            component_delimiter: plan.component_delimiter,
+            release_char: plan.release_char,
+        };
+        if !required(field) && self.rng.gen_bool(0.1) {
+            return Ok(String::new());
+        }
+        for _ in 0..ATTEMPTS {
+            let Some(value) = self.value(field) else {
+                continue;
+            };
+            let value = self.escape(&value, extra).into_owned();
+            let part = Part {
+                value: Cow::Borrowed(&value),
+                is_quoted: false,
+                start: 0,
+                end: value.len(),
+            };
+            // Warnings count as failures, so the value meets every constraint
+            let mut warnings = vec![];
+            if part_to_value(&syntax, field, &part, &mut warnings).is_ok() && warnings.is_empty() {
+                return Ok(value);
+            }
+        }
+        Err(format!("Couldn't generate a value for {:?}", field).into())
+    }
+    // A candidate raw value, None to try again
+    fn value(&mut self, field: &Field) -> Option<String> {
+        Some(match field {
+            Field::Str(str_field) => self.string(str_field)?,
+            Field::StrEnum(enum_field) => {
+                let value = enum_field.values.choose(&mut self.rng)?;
+                self.unquote(value)
+            }
+            Field::Int(IntField {
+                sequence: Some(_), ..
+            }) => self.next_sequence.to_string(),
+            Field::Int(IntField {
+                min_value,
+                max_value,
+                exclusive_min,
+                exclusive_max,
+                ..
+            }) => {
+                let low = min_value.or(exclusive_min.map(|i| i + 1));
+                let high = max_value.or(exclusive_max.map(|i| i - 1));
+                let (low, high) = bounds(low, high, 0, 1000);
+                self.rng.gen_range(low..=high.max(low)).to_string()
+            }
+            Field::IntEnum(enum_field) => enum_field.values.choose(&mut self.rng)?.to_string(),
+            Field::Float(FloatField {
+                min_value,
+                max_value,
+                exclusive_min,
+                exclusive_max,
+                ..
+            }) => {
+                let (low, high) = bounds(
+                    min_value.or(*exclusive_min),
+                    max_value.or(*exclusive_max),
+                    0.0,
+                    1000.0,
+                );
+                format!("{:.2}", self.rng.gen_range(low..=high.max(low)))
+            }
+            Field::Decimal(DecimalField {
+                round_decimal_places,
+                min_value,
+                max_value,
+                exclusive_min,
+                exclusive_max,
+                max_decimal_places,
+                implied_decimal_places: Some(dp),
+                ..
+            }) => {
+                let dp = round_decimal_places.map_or(*dp, |round| round.min(*dp));
+                let value = self.decimal(
+                    min_value.or(*exclusive_min),
+                    max_value.or(*exclusive_max),
+                    max_decimal_places.map_or(dp, |max| max.min(dp)),
+                )?;
+                // With the point implied, there are always dp digits after it
+                let (whole, fraction) = value.split_once('.').unwrap_or((&value, ""));
+                format!("{}{:0<width$}", whole, fraction, width = dp as usize)
+            }
+            Field::Decimal(DecimalField {
+                round_decimal_places,
+                min_value,
+                max_value,
+                exclusive_min,
+                exclusive_max,
+                max_decimal_places,
+                ..
+            }) => self.decimal(
+                min_value.or(*exclusive_min),
+                max_value.or(*exclusive_max),
+                round_decimal_places.unwrap_or(max_decimal_places.map_or(2, |dp| dp.min(2))),
+            )?,
+            Field::Bool(bool_field) => self.boolean(bool_field)?,
+            Field::Datetime(datetime_field) => self.datetime(datetime_field)?,
+            Field::Date(date_field) => {
+                let format = date_field.format.choose(&mut self.rng)?;
+                let date = NaiveDate::from_ymd_opt(2000, 1, 1)? + random_days(&mut self.rng);
+                date.format_with_items(format.items.iter()).to_string()
+            }
+            Field::Time(time_field) => {
+                let format = time_field.format.choose(&mut self.rng)?;
+                let time = NaiveTime::from_num_seconds_from_midnight_opt(
+                    self.rng.gen_range(0..24 * 60 * 60),
+                    0,
+                )?;
+                time.format_with_items(format.items.iter()).to_string()
+            }
+            Field::Currency(currency_field) => self.currency(currency_field)?,
+            Field::Percent(PercentField {
+                as_fraction,
+                min_value,
+                max_value,
+                ..
+            }) => {
+                let scale = if *as_fraction {
+                    Decimal::ONE_HUNDRED
+                } else {
+                    Decimal::ONE
+                };
+                // None if a bound is too large to scale
+                let scaled = |d: Option<Decimal>| match d {
+                    Some(d) => d.checked_mul(scale).map(Some),
+                    None => Some(None),
+                };
+                let (low, high) = (scaled(*min_value)?, scaled(*max_value)?);
+                format!("{}%", self.decimal(low, high, 2)?)
+            }
+            Field::Composite(_) => return None,
+        })
+    }
+    fn string(&mut self, str_field: &StrField) -> Option<String> {
+        let low = str_field.min_length.unwrap_or(1).max(1);
+        let high = str_field.max_length.unwrap_or(low.max(10));
+        let length = self.rng.gen_range(low..=high.max(low));
+        let mut value = String::new();
+        for _ in 0..length * 10 {
+            if str_field.length_unit.len(&value) >= length {
+                break;
+            }
+            let ch = match &str_field.allowed_characters {
+                Some(allowed) => {
+                    let (low, high) = *allowed.ranges.choose(&mut self.rng)?;
+                    char::from_u32(self.rng.gen_range(low as u32..=high as u32))?
+                }
+                None => *ALPHABET.choose(&mut self.rng)? as char,
+            };
+            let is_invalid = str_field
+                .invalid_characters
+                .as_ref()
+                .is_some_and(|invalid| invalid.contains(ch));
+            if !is_invalid && !ch.is_control() && !self.is_special(ch) {
+                value.push(ch);
+            }
+        }
+        // Padding would be stripped
+        if let Some(pad_char) = str_field.pad_char {
+            value = value.replace(pad_char, "");
+        }
+        Some(value)
+    }
+    fn decimal(&mut self, low: Option<Decimal>, high: Option<Decimal>, dp: u32) -> Option<String> {
+        let (low, high) = bounds(low, high, Decimal::ZERO, Decimal::ONE_THOUSAND);
+        let scale = Decimal::from(10i64.checked_pow(dp)?);
+        let low = low.checked_mul(scale)?.ceil().to_i128()?;
+        let high = high.checked_mul(scale)?.floor().to_i128()?;
+        let n = self.rng.gen_range(low..=high.max(low));
+        Some(Decimal::from_i128_with_scale(n, dp).to_string())
+    }
+    fn boolean(&mut self, bool_field: &BoolField) -> Option<String> {
+        let values: Vec<&String> = if self.rng.gen_bool(0.5) {
+            bool_field.true_values().collect()
+        } else {
+            bool_field.false_values().collect()
+        };
+        let value = *values.choose(&mut self.rng)?;
+        Some(self.unquote(value))
+    }
+    fn datetime(&mut self, datetime_field: &DatetimeField) -> Option<String> {
+        let format = datetime_field.format.choose(&mut self.rng)?;
+        let utc = DateTime::<Utc>::from_timestamp(946_684_800, 0)?
+            + random_days(&mut self.rng)
+            + Duration::seconds(self.rng.gen_range(0..24 * 60 * 60));
+        Some(match (format.epoch_units_per_second(), datetime_field.tz) {
+            (Some(units_per_second), _) => (utc.timestamp() * units_per_second).to_string(),
+            (None, Some(tz)) => {
+                let local = utc.with_timezone(&tz);
+                local.format_with_items(format.items.iter()).to_string()
+            }
+            (None, None) => utc.format_with_items(format.items.iter()).to_string(),
+        })
+    }
+    fn currency(&mut self, currency_field: &CurrencyField) -> Option<String> {
+        let amount = self.decimal(currency_field.min_value, currency_field.max_value, 2)?;
+        if let Some(codes) = &currency_field.codes {
+            return Some(format!("{} {}", codes.choose(&mut self.rng)?, amount));
+        }
+        if currency_field.default_code.is_some() {
+            return Some(amount);
+        }
+        let symbols: Vec<&str> = currency_field
+            .symbols
+            .keys()
+            .map(|symbol| symbol.as_str())
+            .chain(CURRENCY_SYMBOLS.iter().map(|(symbol, _)| *symbol))
+            .collect();
+        Some(format!("{}{}", symbols.choose(&mut self.rng)?, amount))
+    }
+    fn is_special(&self, ch: char) -> bool {
+        let plan = &self.parser.plan;
+        plan.delimiter == Ok(ch)
+            || plan.quote_char == Ok(Some(ch))
+            || plan.release_char == Some(ch)
+            || plan.component_delimiter == Some(ch)
+    }
+    // Enum and bool values can be given quoted, eg: '"A"'
+    fn unquote(&self, value: &str) -> String {
+        match self.parser.plan.quote_char {
+            Ok(Some(q)) => match value.strip_prefix(q).and_then(|v| v.strip_suffix(q)) {
+                Some(unquoted) => unquoted.to_string(),
+                None => value.to_string(),
+            },
+            _ => value.to_string(),
+        }
+    }
+    // With a release_char, put it before any chars that would split the value
+    fn escape<'b>(&self, value: &'b str, extra: &[char]) -> Cow<'b, str> {
+        let Some(release_char) = self.parser.plan.release_char else {
+            return Cow::Borrowed(value);
+        };
+        let needs_release = |ch: char| self.is_special(ch) || extra.contains(&ch);
+        if !value.chars().any(needs_release) {
+            return Cow::Borrowed(value);
+        }
+        let mut escaped = String::with_capacity(value.len() + 1);
+        for ch in value.chars() {
+            if needs_release(ch) {
+                escaped.push(release_char);
+            }
+            escaped.push(ch);
+        }
+        Cow::Owned(escaped)
+    }
+    // Quote values that need it, or all of them with quote_mode all
+    fn quote(&self, value: &str) -> String {
+        let plan = &self.parser.plan;
+        let quote_mode = self.parser.schema.quote_mode;
+        let Ok(Some(q)) = plan.quote_char else {
+            return value.to_string();
+        };
+        let needs_quotes = plan.release_char.is_none()
+            && value.contains(|ch: char| plan.delimiter == Ok(ch) || ch == q);
+        if !needs_quotes && !matches!(quote_mode, QuoteMode::All) {
+            return value.to_string();
+        }
+        let inner = match quote_mode {
+            QuoteMode::Lenient => value.to_string(),
+            _ => value.replace(q, &format!("{}{}", q, q)),
+        };
+        format!("{}{}{}", q, inner, q)
+    }
+}
+
+impl Formats {
+    fn choose(&self, rng: &mut StdRng) -> Option<&Format> {
+        self.0.choose(rng)
+    }
+}
+
+// Any missing bound is a default distance from the other
+fn bounds<T>(low: Option<T>, high: Option<T>, default_low: T, default_high: T) -> (T, T)
+where
+    T: Copy + PartialOrd + std::ops::Sub<Output = T> + std::ops::Add<Output = T>,
+{
+    let width = default_high - default_low;
+    match (low, high) {
+        (Some(low), Some(high)) => (low, high),
+        (Some(low), None) => (low, low + width),
+        (None, Some(high)) if high < default_low => (high - width, high),
+        (None, Some(high)) => (default_low, high),
+        (None, None) => (default_low, default_high),
+    }
+}
+
+// Over about 30 years from 2000
+fn random_days(rng: &mut StdRng) -> Duration {
+    Duration::days(rng.gen_range(0..11_000))
+}
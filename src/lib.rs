@@ -1,23 +1,61 @@
 #![allow(dead_code)]
+// pyo3 0.20's #[pymethods] trips this lint on newer compilers
+#![allow(non_local_definitions)]
 
+#[cfg(feature = "parquet")]
+extern crate arrow_array;
+#[cfg(feature = "parquet")]
+extern crate arrow_buffer;
+#[cfg(feature = "parquet")]
+extern crate arrow_schema;
 extern crate chrono;
 extern crate chrono_tz;
+extern crate encoding_rs;
+extern crate flate2;
+extern crate memchr;
+extern crate memmap2;
+#[cfg(feature = "parquet")]
+extern crate parquet;
+#[cfg(feature = "python")]
 extern crate pyo3;
+extern crate rand;
+extern crate regex;
 extern crate rust_decimal;
 extern crate serde;
 extern crate serde_json;
+extern crate unicode_segmentation;
+extern crate zstd;
 
 use chrono::offset::LocalResult;
-use chrono::Datelike;
-use chrono::Timelike;
-use pyo3::exceptions::*;
-use pyo3::prelude::*;
-use pyo3::types::*;
 
-use chrono::{NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use chrono::format::{Item, ParseResult, Parsed, StrftimeItems};
+use chrono::{
+    DateTime, Duration, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Offset, TimeZone, Utc,
+};
 use chrono_tz::Tz;
-use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
+use encoding_rs::Encoding;
+use flate2::bufread::MultiGzDecoder;
+use memmap2::Mmap;
+use regex::Regex;
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::de::{Error as DeError, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::iter;
+use std::path::{Path, PathBuf};
+use unicode_segmentation::UnicodeSegmentation;
+
+mod generate;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "parquet")]
+mod to_parquet;
 
 // For now, we serialize schemas as JSON, maybe in the future we can use:
 // https://crates.io/crates/pythonize
@@ -44,12 +82,26 @@ enum Field {
     Date(DateField),
     #[serde(rename = "TIME")]
     Time(TimeField),
+    #[serde(rename = "COMPOSITE")]
+    Composite(CompositeField),
+    #[serde(rename = "CURRENCY")]
+    Currency(CurrencyField),
+    #[serde(rename = "PERCENT")]
+    Percent(PercentField),
 }
 impl Field {
-    fn is_str(&self) -> bool {
+    // Build lookups that aren't part of the JSON
+    fn prepare(&mut self, quote_char: Option<char>) {
         match self {
-            Field::Str(_) => true,
-            _ => false,
+            Field::StrEnum(enum_field) => enum_field.prepare(quote_char),
+            Field::IntEnum(enum_field) => enum_field.prepare(),
+            Field::Bool(bool_field) => bool_field.prepare(quote_char),
+            Field::Datetime(datetime_field) => datetime_field.prepare(),
+            Field::Currency(currency_field) => currency_field.prepare(),
+            Field::Composite(CompositeField { fields, .. }) => fields
+                .iter_mut()
+                .for_each(|field| field.prepare(quote_char)),
+            _ => {}
         }
     }
 }
@@ -57,243 +109,3192 @@ impl Field {
 #[derive(Debug, Deserialize, Serialize)]
 struct StrField {
     required: bool,
+    // Of the constraints, with warn a value failing them is kept and a warning added
+    #[serde(default)]
+    severity: Severity,
     min_length: Option<usize>,
     max_length: Option<usize>,
-    invalid_characters: Option<String>,
+    invalid_characters: Option<CharSet>,
+    // If given, only these chars are valid
+    #[serde(default)]
+    allowed_characters: Option<CharSet>,
+    // Of min_length and max_length
+    #[serde(default)]
+    length_unit: LengthUnit,
+    // Of the output, after the checks
+    #[serde(default)]
+    transform: Option<Transform>,
+    // Overrides schema.coerce_empty_quoted
+    #[serde(default)]
+    coerce_empty_quoted: Option<bool>,
+    // Share the output objects of equal values, for low cardinality fields
+    #[serde(default)]
+    intern: bool,
+    // Stripped before anything else, eg: "0" of "000123"
+    #[serde(default)]
+    pad_char: Option<char>,
+    #[serde(default)]
+    pad_side: PadSide,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct StrEnumField {
     required: bool,
+    #[serde(default)]
+    severity: Severity,
     values: Vec<String>,
+    #[serde(default)]
+    case_insensitive: bool,
+    // Ignore leading/trailing whitespace
+    #[serde(default)]
+    strip: bool,
+    // Raw value -> canonical label to output
+    #[serde(default)]
+    mapping: Option<HashMap<String, String>>,
+    // Normalized raw value -> output
+    #[serde(skip)]
+    lookup: Lookup<String, String>,
+}
+impl StrEnumField {
+    fn output<'a>(&'a self, value: &'a String) -> &'a String {
+        self.mapping
+            .as_ref()
+            .and_then(|mapping| mapping.get(value))
+            .unwrap_or(value)
+    }
+    fn normalize<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        let value = if self.strip { value.trim() } else { value };
+        if self.case_insensitive {
+            Cow::Owned(value.to_lowercase())
+        } else {
+            Cow::Borrowed(value)
+        }
+    }
+    fn prepare(&mut self, quote_char: Option<char>) {
+        let mut lookup: HashMap<String, String> = HashMap::new();
+        for value in &self.values {
+            lookup.insert(
+                self.normalize(value).into_owned(),
+                self.output(value).clone(),
+            );
+        }
+        // We allow 'A' to pass as the enum '"A"', exact matches take precedence
+        if let Some(q) = quote_char {
+            for value in &self.values {
+                if let Some(unquoted) = value.strip_prefix(q).and_then(|v| v.strip_suffix(q)) {
+                    lookup
+                        .entry(self.normalize(unquoted).into_owned())
+                        .or_insert_with(|| self.output(value).clone());
+                }
+            }
+        }
+        self.lookup = Lookup(lookup);
+    }
+    // Returns the output for a raw value
+    fn find(&self, value: &str) -> Option<&String> {
+        self.lookup.0.get(self.normalize(value).as_ref())
+    }
+}
+
+// Built from the schema once at load time, not part of the JSON
+#[derive(Default)]
+struct Lookup<K, V>(HashMap<K, V>);
+impl<K, V> fmt::Debug for Lookup<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("..")
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct IntField {
     required: bool,
-    // We use f64 here so we can represent large numbers, bit naughty
-    min_value: Option<f64>,
-    max_value: Option<f64>,
+    #[serde(default)]
+    severity: Severity,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_int_bound",
+        serialize_with = "serialize_int_bound"
+    )]
+    min_value: Option<i128>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_int_bound",
+        serialize_with = "serialize_int_bound"
+    )]
+    max_value: Option<i128>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_int_bound",
+        serialize_with = "serialize_int_bound"
+    )]
+    exclusive_min: Option<i128>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_int_bound",
+        serialize_with = "serialize_int_bound"
+    )]
+    exclusive_max: Option<i128>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_int_bound",
+        serialize_with = "serialize_int_bound"
+    )]
+    not_equal: Option<i128>,
+    // Checked across lines when parsing many, all the sequence fields share a count
+    #[serde(default)]
+    sequence: Option<Sequence>,
+    // The sign is overpunched on the last digit, as COBOL signed zoned
+    // decimals, eg: "12J" is -121
+    #[serde(default)]
+    zoned: bool,
+    #[serde(default)]
+    pad_char: Option<char>,
+    #[serde(default)]
+    pad_side: PadSide,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+struct Sequence {
+    start: i64,
+    step: i64,
+}
+
+// Int bounds come through as JSON numbers, or as strings when they're too
+// big for serde_json to hand us without going via f64
+fn deserialize_int_bound<'de, D: Deserializer<'de>>(d: D) -> Result<Option<i128>, D::Error> {
+    struct IntBoundVisitor;
+    impl<'de> Visitor<'de> for IntBoundVisitor {
+        type Value = Option<i128>;
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an integer, or a string containing an integer")
+        }
+        fn visit_none<E: DeError>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+        fn visit_unit<E: DeError>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+        fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Self::Value, D::Error> {
+            d.deserialize_any(self)
+        }
+        fn visit_i64<E: DeError>(self, v: i64) -> Result<Self::Value, E> {
+            Ok(Some(v as i128))
+        }
+        fn visit_u64<E: DeError>(self, v: u64) -> Result<Self::Value, E> {
+            Ok(Some(v as i128))
+        }
+        fn visit_str<E: DeError>(self, v: &str) -> Result<Self::Value, E> {
+            v.parse::<i128>()
+                .map(Some)
+                .map_err(|_| E::custom(format!("invalid integer bound: '{}'", v)))
+        }
+    }
+    d.deserialize_option(IntBoundVisitor)
+}
+
+// As they're read, so big bounds survive a round trip through JSON
+fn serialize_int_bound<S: Serializer>(bound: &Option<i128>, s: S) -> Result<S::Ok, S::Error> {
+    match bound {
+        Some(i) if *i < i64::MIN as i128 || *i > i64::MAX as i128 => {
+            s.serialize_str(&i.to_string())
+        }
+        Some(i) => s.serialize_i64(*i as i64),
+        None => s.serialize_none(),
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct IntEnumField {
     required: bool,
+    #[serde(default)]
+    severity: Severity,
     values: Vec<i64>,
+    // Raw value -> canonical label to output, as pairs as JSON keys are strings
+    #[serde(default)]
+    mapping: Option<Vec<(i64, String)>>,
+    // Raw value -> label, if any
+    #[serde(skip)]
+    lookup: Lookup<i64, Option<String>>,
+}
+impl IntEnumField {
+    fn prepare(&mut self) {
+        let mut lookup: HashMap<i64, Option<String>> =
+            self.values.iter().map(|value| (*value, None)).collect();
+        for (value, label) in self.mapping.iter().flatten() {
+            lookup.insert(*value, Some(label.clone()));
+        }
+        self.lookup = Lookup(lookup);
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct FloatField {
     required: bool,
+    #[serde(default)]
+    severity: Severity,
     min_value: Option<f64>,
     max_value: Option<f64>,
+    exclusive_min: Option<f64>,
+    exclusive_max: Option<f64>,
+    not_equal: Option<f64>,
+    // Of "NaN", "inf" and "-inf"
+    #[serde(default = "default_true")]
+    allow_nan: bool,
+    #[serde(default = "default_true")]
+    allow_inf: bool,
+    // Eg: "1.2e5"
+    #[serde(default = "default_true")]
+    allow_exponent: bool,
+    #[serde(default)]
+    pad_char: Option<char>,
+    #[serde(default)]
+    pad_side: PadSide,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+enum Rounding {
+    #[serde(rename = "half_up")]
+    HalfUp,
+    #[serde(rename = "half_down")]
+    HalfDown,
+    // Banker's rounding, what `round_dp` does
+    #[default]
+    #[serde(rename = "half_even")]
+    HalfEven,
+    #[serde(rename = "floor")]
+    Floor,
+    #[serde(rename = "ceiling")]
+    Ceiling,
+}
+impl Rounding {
+    fn strategy(&self) -> RoundingStrategy {
+        match self {
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::HalfDown => RoundingStrategy::MidpointTowardZero,
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::Floor => RoundingStrategy::ToNegativeInfinity,
+            Rounding::Ceiling => RoundingStrategy::ToPositiveInfinity,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct DecimalField {
     required: bool,
+    #[serde(default)]
+    severity: Severity,
     round_decimal_places: Option<u32>,
+    #[serde(default)]
+    rounding: Rounding,
     min_value: Option<Decimal>,
     max_value: Option<Decimal>,
+    exclusive_min: Option<Decimal>,
+    exclusive_max: Option<Decimal>,
+    not_equal: Option<Decimal>,
+    // Checked after any rounding, digits include leading zeros after the point.
+    // With both, there can be max_digits - max_decimal_places before the point
+    #[serde(default)]
+    max_digits: Option<u32>,
+    #[serde(default)]
+    max_decimal_places: Option<u32>,
+    // As IntField.zoned
+    #[serde(default)]
+    zoned: bool,
+    // The point isn't in the value, eg: "12345" is 123.45 with 2
+    #[serde(default)]
+    implied_decimal_places: Option<u32>,
+    #[serde(default)]
+    pad_char: Option<char>,
+    #[serde(default)]
+    pad_side: PadSide,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct BoolField {
     required: bool,
-    true_value: String,
+    // The scalar and list forms are combined
+    #[serde(default)]
+    true_value: Option<String>,
+    #[serde(default)]
     false_value: Option<String>,
+    #[serde(default)]
+    true_values: Vec<String>,
+    #[serde(default)]
+    false_values: Vec<String>,
+    // Also read as null when not required, eg: " " for unknown
+    #[serde(default)]
+    null_values: Vec<String>,
+    #[serde(default)]
+    case_insensitive: bool,
+    // Normalized raw value -> the bool, or None of null_values
+    #[serde(skip)]
+    lookup: Lookup<String, Option<bool>>,
+}
+impl BoolField {
+    fn true_values(&self) -> impl Iterator<Item = &String> + '_ {
+        self.true_value.iter().chain(self.true_values.iter())
+    }
+    fn false_values(&self) -> impl Iterator<Item = &String> + '_ {
+        self.false_value.iter().chain(self.false_values.iter())
+    }
+    fn normalize<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        if self.case_insensitive {
+            Cow::Owned(value.to_lowercase())
+        } else {
+            Cow::Borrowed(value)
+        }
+    }
+    fn prepare(&mut self, quote_char: Option<char>) {
+        let values: Vec<(&String, Option<bool>)> = self
+            .null_values
+            .iter()
+            .map(|value| (value, None))
+            .chain(self.true_values().map(|value| (value, Some(true))))
+            .chain(self.false_values().map(|value| (value, Some(false))))
+            .collect();
+        let mut lookup: HashMap<String, Option<bool>> = HashMap::new();
+        for (value, output) in &values {
+            lookup
+                .entry(self.normalize(value).into_owned())
+                .or_insert(*output);
+        }
+        // We allow 'A' to pass as the bool '"A"', exact matches take precedence
+        if let Some(q) = quote_char {
+            for (value, output) in &values {
+                if let Some(unquoted) = value.strip_prefix(q).and_then(|v| v.strip_suffix(q)) {
+                    lookup
+                        .entry(self.normalize(unquoted).into_owned())
+                        .or_insert(*output);
+                }
+            }
+        }
+        self.lookup = Lookup(lookup);
+    }
+    // Returns the bool, or None if it's a null value, for a raw value
+    fn find(&self, value: &str) -> Option<Option<bool>> {
+        self.lookup.0.get(self.normalize(value).as_ref()).copied()
+    }
+}
+
+// A strftime format, parsed into chrono items once when the schema is loaded
+struct Format {
+    format: String,
+    items: Vec<Item<'static>>,
+}
+impl Format {
+    fn parse(&self, value: &str) -> Option<Parsed> {
+        let mut parsed = Parsed::new();
+        chrono::format::parse(&mut parsed, value, self.items.iter()).ok()?;
+        Some(parsed)
+    }
+    // Values like "24:00" or "240000" mean midnight at the end of the day,
+    // chrono won't have hour 24 so try reading each "24" in the value as "00"
+    fn parse_end_of_day(&self, value: &str) -> Option<Parsed> {
+        let midnight = NaiveTime::from_hms_opt(0, 0, 0);
+        value.match_indices("24").find_map(|(i, _)| {
+            let parsed = self.parse(&format!("{}00{}", &value[..i], &value[i + 2..]))?;
+            if parsed.to_naive_time().ok() == midnight {
+                Some(parsed)
+            } else {
+                None
+            }
+        })
+    }
+    fn has_offset(&self) -> bool {
+        ["%z", "%:z", "%::z", "%:::z", "%#z"]
+            .iter()
+            .any(|spec| self.format.contains(spec))
+    }
+    // The special "epoch_*" formats are Unix timestamps rather than strftime
+    fn epoch_units_per_second(&self) -> Option<i64> {
+        match self.format.as_str() {
+            "epoch_seconds" => Some(1),
+            "epoch_millis" => Some(1_000),
+            _ => None,
+        }
+    }
+}
+
+// Either a single format or a list of fallback formats, tried in order
+struct Formats(Vec<Format>);
+impl Formats {
+    fn iter(&self) -> std::slice::Iter<'_, Format> {
+        self.0.iter()
+    }
+    fn parse_first<T>(&self, value: &str, to: impl Fn(Parsed) -> ParseResult<T>) -> Option<T> {
+        self.iter()
+            .find_map(|format| format.parse(value).and_then(|p| to(p).ok()))
+    }
+}
+impl fmt::Debug for Formats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.iter().map(|format| &format.format))
+            .finish()
+    }
+}
+impl<'de> Deserialize<'de> for Formats {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(String),
+            Many(Vec<String>),
+        }
+        let formats = match OneOrMany::deserialize(d)? {
+            OneOrMany::One(format) => vec![format],
+            OneOrMany::Many(formats) => formats,
+        };
+        if formats.is_empty() {
+            return Err(D::Error::custom("format list is empty"));
+        }
+        formats
+            .into_iter()
+            .map(|format| {
+                let items = StrftimeItems::new(&format)
+                    .parse_to_owned()
+                    .map_err(|_| D::Error::custom(format!("invalid format: '{}'", format)))?;
+                Ok(Format { format, items })
+            })
+            .collect::<Result<_, _>>()
+            .map(Formats)
+    }
+}
+impl Serialize for Formats {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(self.iter().map(|format| &format.format))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct DatetimeField {
+    required: bool,
+    format: Formats,
+    time_zone: Option<String>,
+    // Implied for any format containing %z
+    #[serde(default)]
+    offset_in_value: bool,
+    #[serde(default)]
+    ambiguous: Ambiguous,
+    #[serde(default)]
+    nonexistent: Nonexistent,
+    // Read "24:00" as midnight, rolling onto the next day
+    #[serde(default)]
+    allow_2400: bool,
+    // Convert the parsed datetime to this zone, eg: "UTC"
+    #[serde(default)]
+    output_time_zone: Option<String>,
+    // Output the local time without its offset, after any output_time_zone
+    #[serde(default)]
+    output_naive: bool,
+    // time_zone and output_time_zone parsed at load, checked by Plan::new
+    #[serde(skip)]
+    tz: Option<Tz>,
+    #[serde(skip)]
+    output_tz: Option<Tz>,
+}
+impl DatetimeField {
+    fn prepare(&mut self) {
+        self.tz = self.time_zone.as_ref().and_then(|t| t.parse().ok());
+        self.output_tz = self.output_time_zone.as_ref().and_then(|t| t.parse().ok());
+    }
+}
+
+// What to do with a local time that happens twice, eg: in the autumn DST fold
+#[derive(Debug, Default, Deserialize, Serialize)]
+enum Ambiguous {
+    #[serde(rename = "earliest")]
+    Earliest,
+    #[serde(rename = "latest")]
+    Latest,
+    #[default]
+    #[serde(rename = "raise")]
+    Raise,
+}
+
+// What to do with a local time that never happens, eg: in the spring DST gap
+#[derive(Debug, Default, Deserialize, Serialize)]
+enum Nonexistent {
+    // Move forward by the length of the gap, 01:30 -> 02:30 BST
+    #[serde(rename = "shift_forward")]
+    ShiftForward,
+    // Move back by the length of the gap, 01:30 -> 00:30 GMT
+    #[serde(rename = "shift_backward")]
+    ShiftBackward,
+    #[default]
+    #[serde(rename = "raise")]
+    Raise,
+}
+
+fn localize(
+    tz: Tz,
+    naive: &NaiveDateTime,
+    ambiguous: &Ambiguous,
+    nonexistent: &Nonexistent,
+) -> Result<DateTime<Tz>, &'static str> {
+    match (tz.from_local_datetime(naive), ambiguous, nonexistent) {
+        (LocalResult::Single(dt), _, _) => Ok(dt),
+        (LocalResult::Ambiguous(earliest, _), Ambiguous::Earliest, _) => Ok(earliest),
+        (LocalResult::Ambiguous(_, latest), Ambiguous::Latest, _) => Ok(latest),
+        (LocalResult::Ambiguous(..), Ambiguous::Raise, _) => Err("Ambiguous datetime in time_zone"),
+        (LocalResult::None, _, Nonexistent::Raise) => Err("Nonexistent datetime in time_zone"),
+        (LocalResult::None, _, shift) => {
+            // Read the time with the offset from the other side of the gap
+            let other_side = match shift {
+                Nonexistent::ShiftForward => *naive - Duration::days(1),
+                _ => *naive + Duration::days(1),
+            };
+            let offset = tz
+                .offset_from_local_datetime(&other_side)
+                .earliest()
+                .ok_or("Nonexistent datetime in time_zone")?;
+            let utc = *naive - Duration::seconds(offset.fix().local_minus_utc() as i64);
+            Ok(tz.from_utc_datetime(&utc))
+        }
+    }
+}
+
+fn parse_epoch(value: &str, units_per_second: i64) -> Option<DateTime<Utc>> {
+    let nanos = Decimal::from_str_exact(value)
+        .ok()?
+        .checked_mul(Decimal::from(1_000_000_000 / units_per_second))?
+        .trunc()
+        .to_i128()?;
+    let secs = nanos.div_euclid(1_000_000_000).to_i64()?;
+    DateTime::from_timestamp(secs, nanos.rem_euclid(1_000_000_000) as u32)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct DateField {
+    required: bool,
+    format: Formats,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct TimeField {
+    required: bool,
+    format: Formats,
+    // Read "24:00" as midnight
+    #[serde(default = "default_true")]
+    allow_2400: bool,
+}
+
+// Components split by schema.component_delimiter, eg: "12345::91" in EDIFACT.
+// Missing trailing components are empty
+#[derive(Debug, Deserialize, Serialize)]
+struct CompositeField {
+    required: bool,
+    fields: Vec<Field>,
+}
+
+// Amounts with a symbol or ISO code either side, eg: "£1,234.56",
+// "USD 99.00", "-$5.00", "99.00 EUR"
+#[derive(Debug, Deserialize, Serialize)]
+struct CurrencyField {
+    required: bool,
+    #[serde(default)]
+    severity: Severity,
+    // Symbol -> ISO code, these take precedence over £ $ € ¥
+    #[serde(default)]
+    symbols: HashMap<String, String>,
+    // If given, only these codes are valid, else any ISO 4217 code
+    #[serde(default)]
+    codes: Option<Vec<String>>,
+    // Of amounts without a symbol or code
+    #[serde(default)]
+    default_code: Option<String>,
+    // Output (amount, code) rather than just the amount
+    #[serde(default)]
+    include_code: bool,
+    #[serde(default = "default_thousands_separator")]
+    thousands_separator: Option<char>,
+    #[serde(default)]
+    min_value: Option<Decimal>,
+    #[serde(default)]
+    max_value: Option<Decimal>,
+    // Of symbols, longest first (then by symbol) so eg: "R$" is tried before "R"
+    #[serde(skip)]
+    sorted_symbols: Vec<(String, String)>,
+}
+
+fn default_thousands_separator() -> Option<char> {
+    Some(',')
+}
+
+const CURRENCY_SYMBOLS: [(&str, &str); 4] =
+    [("£", "GBP"), ("$", "USD"), ("€", "EUR"), ("¥", "JPY")];
+
+// Of amounts when CurrencyField.codes isn't given, sorted for binary_search
+const ISO_4217_CODES: [&str; 182] = [
+    "AED", "AFN", "ALL", "AMD", "ANG", "AOA", "ARS", "AUD", "AWG", "AZN", "BAM", "BBD", "BDT",
+    "BGN", "BHD", "BIF", "BMD", "BND", "BOB", "BOV", "BRL", "BSD", "BTN", "BWP", "BYN", "BZD",
+    "CAD", "CDF", "CHE", "CHF", "CHW", "CLF", "CLP", "CNY", "COP", "COU", "CRC", "CUC", "CUP",
+    "CVE", "CZK", "DJF", "DKK", "DOP", "DZD", "EGP", "ERN", "ETB", "EUR", "FJD", "FKP", "GBP",
+    "GEL", "GHS", "GIP", "GMD", "GNF", "GTQ", "GYD", "HKD", "HNL", "HTG", "HUF", "IDR", "ILS",
+    "INR", "IQD", "IRR", "ISK", "JMD", "JOD", "JPY", "KES", "KGS", "KHR", "KMF", "KPW", "KRW",
+    "KWD", "KYD", "KZT", "LAK", "LBP", "LKR", "LRD", "LSL", "LYD", "MAD", "MDL", "MGA", "MKD",
+    "MMK", "MNT", "MOP", "MRU", "MUR", "MVR", "MWK", "MXN", "MXV", "MYR", "MZN", "NAD", "NGN",
+    "NIO", "NOK", "NPR", "NZD", "OMR", "PAB", "PEN", "PGK", "PHP", "PKR", "PLN", "PYG", "QAR",
+    "RON", "RSD", "RUB", "RWF", "SAR", "SBD", "SCR", "SDG", "SEK", "SGD", "SHP", "SLE", "SLL",
+    "SOS", "SRD", "SSP", "STN", "SVC", "SYP", "SZL", "THB", "TJS", "TMT", "TND", "TOP", "TRY",
+    "TTD", "TWD", "TZS", "UAH", "UGX", "USD", "USN", "UYI", "UYU", "UYW", "UZS", "VED", "VES",
+    "VND", "VUV", "WST", "XAF", "XAG", "XAU", "XBA", "XBB", "XBC", "XBD", "XCD", "XCG", "XDR",
+    "XOF", "XPD", "XPF", "XPT", "XSU", "XTS", "XUA", "XXX", "YER", "ZAR", "ZMW", "ZWG", "ZWL",
+];
+
+impl CurrencyField {
+    fn prepare(&mut self) {
+        let mut sorted: Vec<_> = self.symbols.clone().into_iter().collect();
+        sorted.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        self.sorted_symbols = sorted;
+    }
+    fn symbols(&self) -> impl Iterator<Item = (&str, &str)> {
+        let custom = self
+            .sorted_symbols
+            .iter()
+            .map(|(s, c)| (s.as_str(), c.as_str()));
+        custom.chain(CURRENCY_SYMBOLS.iter().copied())
+    }
+    // Strip a symbol or code from the start (or end) of value
+    fn strip_code<'a>(&'a self, value: &'a str, from_end: bool) -> Option<(&'a str, &'a str)> {
+        for (symbol, code) in self.symbols() {
+            let stripped = if from_end {
+                value.strip_suffix(symbol)
+            } else {
+                value.strip_prefix(symbol)
+            };
+            if let Some(stripped) = stripped {
+                return Some((stripped.trim(), code));
+            }
+        }
+        let (code, rest) = if from_end {
+            let i = value.len().checked_sub(3)?;
+            (value.get(i..)?, value.get(..i)?)
+        } else {
+            (value.get(..3)?, value.get(3..)?)
+        };
+        let is_code = match &self.codes {
+            // So that parse can say the code isn't allowed
+            Some(_) => code.bytes().all(|b| b.is_ascii_uppercase()),
+            None => ISO_4217_CODES.binary_search(&code).is_ok(),
+        };
+        is_code.then(|| (rest.trim(), code))
+    }
+    fn parse(&self, value: &str) -> Result<(Decimal, Option<String>), &'static str> {
+        let mut value = value.trim();
+        let mut negative = false;
+        if let Some(stripped) = value.strip_prefix('-') {
+            (value, negative) = (stripped.trim_start(), true);
+        }
+        let mut code = None;
+        if let Some((stripped, c)) = self.strip_code(value, false) {
+            (value, code) = (stripped, Some(c));
+        }
+        if let Some(stripped) = value.strip_prefix('-') {
+            if negative {
+                return Err("Currency has two signs");
+            }
+            (value, negative) = (stripped, true);
+        }
+        if code.is_none() {
+            if let Some((stripped, c)) = self.strip_code(value, true) {
+                (value, code) = (stripped, Some(c));
+            }
+        }
+        let amount: Cow<str> = match self.thousands_separator {
+            Some(separator) if value.contains(separator) => {
+                Cow::Owned(value.replace(separator, ""))
+            }
+            _ => Cow::Borrowed(value),
+        };
+        let amount = match Decimal::from_str_exact(&amount) {
+            Ok(amount) if amount.is_sign_negative() => return Err("Currency has two signs"),
+            Ok(amount) if negative => -amount,
+            Ok(amount) => amount,
+            Err(_) => return Err("Does not parse as currency"),
+        };
+        let code = code
+            .map(str::to_string)
+            .or_else(|| self.default_code.clone());
+        match (&self.codes, &code) {
+            (Some(codes), Some(code)) if !codes.contains(code) => {
+                Err("Currency code isn't allowed")
+            }
+            (Some(_), None) => Err("Currency code is missing"),
+            _ => Ok((amount, code)),
+        }
+    }
+}
+
+// Eg: "12.5%"
+#[derive(Debug, Deserialize, Serialize)]
+struct PercentField {
+    required: bool,
+    #[serde(default)]
+    severity: Severity,
+    // Also take "12.5" as 12.5%
+    #[serde(default)]
+    assume_percent: bool,
+    // Output 12.5% as 0.125
+    #[serde(default)]
+    as_fraction: bool,
+    // Output a float rather than a Decimal
+    #[serde(default)]
+    as_float: bool,
+    // Of the output
+    #[serde(default)]
+    min_value: Option<Decimal>,
+    #[serde(default)]
+    max_value: Option<Decimal>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Line {
+    // Several names match on several leading parts
+    #[serde(rename = "name", deserialize_with = "deserialize_one_or_many")]
+    names: Vec<String>,
+    fields: Vec<Field>,
+    // Per field, keys the fields in JSON output
+    #[serde(default)]
+    field_names: Vec<String>,
+    #[serde(rename = "match", default)]
+    line_match: LineMatch,
+    // Other names that match, eg: an old name for the line
+    #[serde(default)]
+    aliases: Vec<Vec<String>>,
+    // Output names rather than the alias matched
+    #[serde(default)]
+    normalize_aliases: bool,
+    // Overrides schema.trailing_delimiter
+    #[serde(default)]
+    trailing_delimiter: Option<bool>,
+    // Ignore parts after the last field
+    #[serde(default)]
+    allow_extra_fields: bool,
+    // With allow_extra_fields, output them as a last, composite value
+    #[serde(default)]
+    keep_extra_fields: bool,
+    // Missing trailing fields are taken as empty
+    #[serde(default)]
+    allow_missing_fields: bool,
+    // Indexes into fields, no two of these lines in a batch can have the same values
+    #[serde(default)]
+    unique_key: Vec<usize>,
+    // The schema versions the line is in, all of them if empty
+    #[serde(default)]
+    versions: Vec<String>,
+    // Per field, fixed-width lines are cut into parts of this many chars
+    // rather than split on the delimiter
+    #[serde(default)]
+    widths: Vec<usize>,
+}
+impl Line {
+    fn in_version(&self, version: Option<&str>) -> bool {
+        match version {
+            Some(version) => self.versions.is_empty() || self.versions.iter().any(|v| v == version),
+            None => true,
+        }
+    }
+}
+
+fn deserialize_one_or_many<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    match OneOrMany::deserialize(d)? {
+        OneOrMany::One(one) => Ok(vec![one]),
+        OneOrMany::Many(many) if many.is_empty() => Err(D::Error::custom("empty line name")),
+        OneOrMany::Many(many) => Ok(many),
+    }
+}
+
+// How the first part of a line selects this Line, for prefix and regex the
+// name is the prefix or pattern
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(tag = "kind")]
+enum LineMatch {
+    #[default]
+    #[serde(rename = "exact")]
+    Exact,
+    #[serde(rename = "prefix")]
+    Prefix,
+    #[serde(rename = "regex")]
+    Regex,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Schema {
+    delimiter: String,
+    quote_str: Option<String>,
+    #[serde(default)]
+    quote_mode: QuoteMode,
+    trailing_delimiter: bool,
+    coerce_empty_quoted: bool,
+    lines: Vec<Line>,
+    #[serde(default)]
+    discriminator: Discriminator,
+    #[serde(default)]
+    groups: Vec<Grouper>,
+    #[serde(default)]
+    trailer_checks: Vec<TrailerCheck>,
+    // Of bytes input, eg: "utf-8" | "latin-1" | "cp1252"
+    #[serde(default = "default_encoding")]
+    encoding: String,
+    #[serde(default)]
+    encoding_errors: EncodingErrors,
+    // Of the first line of a batch, or any line given to parse_line
+    #[serde(default = "default_true")]
+    strip_bom: bool,
+    // Splits files into lines, "\n" | "\r\n" | "\r" if not given
+    #[serde(default)]
+    line_terminator: Option<String>,
+    // Output (parsed, raw) with the text of each value
+    #[serde(default)]
+    include_raw: bool,
+    // Splits COMPOSITE fields, eg: ":" in EDIFACT
+    #[serde(default)]
+    component_delimiter: Option<String>,
+    // The char after this is taken as is, eg: "?" in EDIFACT
+    #[serde(default)]
+    release_str: Option<String>,
+    // When parsing many lines, picks the Line.versions used for the lines after it
+    #[serde(default)]
+    version_field: Option<VersionField>,
+    // When parsing many lines, joins records split over physical lines
+    #[serde(default)]
+    continuation: Option<Continuation>,
+    // When reading files, line terminators in quoted parts don't end the line
+    #[serde(default)]
+    multiline_quoted: bool,
+    // Past this many lines, the record ends at the next terminator even if
+    // quoted, failing to parse
+    #[serde(default = "default_multiline_max_lines")]
+    multiline_max_lines: usize,
+}
+
+fn default_multiline_max_lines() -> usize {
+    1000
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct VersionField {
+    line: Vec<String>,
+    // Index into the line's fields
+    field: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Continuation {
+    kind: ContinuationKind,
+    // Removed before joining, eg: "\\" at the end or "+" at the start
+    marker: String,
+    // Put between the joined lines
+    #[serde(default)]
+    separator: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ContinuationKind {
+    // A line ending with the marker continues onto the next
+    Trailing,
+    // A line starting with the marker continues the one before
+    Leading,
+}
+
+fn default_encoding() -> String {
+    "utf-8".to_string()
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    #[default]
+    Error,
+    Warn,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+enum PadSide {
+    #[default]
+    #[serde(rename = "left")]
+    Left,
+    #[serde(rename = "right")]
+    Right,
+    #[serde(rename = "both")]
+    Both,
+}
+impl PadSide {
+    // Zeros keep one if they're all padding, eg: "000" -> "0"
+    fn strip<'a>(&self, value: &'a str, pad_char: char) -> &'a str {
+        let stripped = match self {
+            PadSide::Left => value.trim_start_matches(pad_char),
+            PadSide::Right => value.trim_end_matches(pad_char),
+            PadSide::Both => value.trim_matches(pad_char),
+        };
+        if stripped.is_empty() && pad_char == '0' {
+            &value[..value.len().min(1)]
+        } else {
+            stripped
+        }
+    }
+}
+
+// Chars and ranges of them, eg: "0-9A-Z_", a "-" at either end is just a "-"
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
+struct CharSet {
+    text: String,
+    ranges: Vec<(char, char)>,
+}
+impl From<String> for CharSet {
+    fn from(text: String) -> Self {
+        let chars: Vec<char> = text.chars().collect();
+        let mut ranges = vec![];
+        let mut i = 0;
+        while i < chars.len() {
+            if i + 2 < chars.len() && chars[i + 1] == '-' {
+                ranges.push((chars[i], chars[i + 2]));
+                i += 3;
+            } else {
+                ranges.push((chars[i], chars[i]));
+                i += 1;
+            }
+        }
+        CharSet { text, ranges }
+    }
+}
+impl From<CharSet> for String {
+    fn from(char_set: CharSet) -> Self {
+        char_set.text
+    }
+}
+impl fmt::Debug for CharSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.text.fmt(f)
+    }
+}
+impl CharSet {
+    fn contains(&self, ch: char) -> bool {
+        self.ranges
+            .iter()
+            .any(|(low, high)| *low <= ch && ch <= *high)
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+enum LengthUnit {
+    #[default]
+    #[serde(rename = "bytes")]
+    Bytes,
+    #[serde(rename = "chars")]
+    Chars,
+    // As seen, eg: "e\u{301}" is one
+    #[serde(rename = "graphemes")]
+    Graphemes,
+}
+impl LengthUnit {
+    fn len(&self, value: &str) -> usize {
+        match self {
+            LengthUnit::Bytes => value.len(),
+            LengthUnit::Chars => value.chars().count(),
+            LengthUnit::Graphemes => value.graphemes(true).count(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+enum Transform {
+    #[serde(rename = "upper")]
+    Upper,
+    #[serde(rename = "lower")]
+    Lower,
+    // As Python's str.title, eg: "o'neil ltd" -> "O'Neil Ltd"
+    #[serde(rename = "title")]
+    Title,
+}
+impl Transform {
+    fn apply(&self, value: &str) -> String {
+        match self {
+            Transform::Upper => value.to_uppercase(),
+            Transform::Lower => value.to_lowercase(),
+            Transform::Title => {
+                let mut out = String::with_capacity(value.len());
+                let mut in_word = false;
+                for ch in value.chars() {
+                    if in_word {
+                        out.extend(ch.to_lowercase());
+                    } else {
+                        out.extend(ch.to_uppercase());
+                    }
+                    in_word = ch.is_alphabetic();
+                }
+                out
+            }
+        }
+    }
+}
+
+// How quote_str is treated when splitting
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize)]
+pub enum QuoteMode {
+    // Quotes start and end quoting anywhere in a part
+    #[default]
+    #[serde(rename = "lenient")]
+    Lenient,
+    // Only parts starting with a quote are quoted, "" in them is a quote,
+    // other quotes are taken as is
+    #[serde(rename = "minimal")]
+    Minimal,
+    // As minimal, but every part has to be quoted
+    #[serde(rename = "all")]
+    All,
+    // Quotes are taken as is
+    #[serde(rename = "none")]
+    None,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+enum EncodingErrors {
+    #[default]
+    #[serde(rename = "strict")]
+    Strict,
+    // Undecodable bytes become U+FFFD
+    #[serde(rename = "replace")]
+    Replace,
+}
+
+enum TerminatorKind {
+    // As Python's universal newlines
+    Universal,
+    Exact(String),
+}
+
+struct Terminator {
+    kind: TerminatorKind,
+    // The release_char, a terminator after an unreleased one is part of the line
+    release: Option<u8>,
+}
+impl Terminator {
+    // The (start, end) of the first terminator in bytes
+    fn find(&self, bytes: &[u8]) -> Option<(usize, usize)> {
+        let mut search = 0;
+        loop {
+            let (start, end) = match &self.kind {
+                TerminatorKind::Universal => {
+                    memchr::memchr2(b'\n', b'\r', &bytes[search..]).map(|i| {
+                        let i = search + i;
+                        if bytes[i] == b'\r' && bytes.get(i + 1) == Some(&b'\n') {
+                            (i, i + 2)
+                        } else {
+                            (i, i + 1)
+                        }
+                    })
+                }
+                TerminatorKind::Exact(terminator) => {
+                    memchr::memmem::find(&bytes[search..], terminator.as_bytes())
+                        .map(|i| (search + i, search + i + terminator.len()))
+                }
+            }?;
+            if !self.is_released(&bytes[..start]) {
+                return Some((start, end));
+            }
+            search = end;
+        }
+    }
+    fn strip<'b>(&self, line: &'b str) -> &'b str {
+        &line[..self.strip_bytes(line.as_bytes()).len()]
+    }
+    fn strip_bytes<'b>(&self, line: &'b [u8]) -> &'b [u8] {
+        let stripped = match &self.kind {
+            TerminatorKind::Universal => {
+                let end = line
+                    .iter()
+                    .rposition(|byte| !matches!(byte, b'\n' | b'\r'))
+                    .map_or(0, |i| i + 1);
+                &line[..end]
+            }
+            TerminatorKind::Exact(terminator) => {
+                line.strip_suffix(terminator.as_bytes()).unwrap_or(line)
+            }
+        };
+        match self.is_released(stripped) {
+            true => line,
+            false => stripped,
+        }
+    }
+    // Whether before ends in an unreleased release_char, as QuoteScan.released
+    fn is_released(&self, before: &[u8]) -> bool {
+        self.release.is_some_and(|release| {
+            let n = before
+                .iter()
+                .rev()
+                .take_while(|&&byte| byte == release)
+                .count();
+            n % 2 == 1
+        })
+    }
+}
+
+// Where Parser::split_lines is up to in the bytes, so a record split over
+// chunks of a file isn't scanned again as each is added
+struct LineSplit {
+    quote_scan: Option<QuoteScan>,
+    // Where to look for the next terminator, past any in quotes
+    search: usize,
+    // Of the record so far, ending in a quoted part
+    quoted_lines: usize,
+}
+
+// Follows the quoting of a line's bytes as split_line would, to tell whether a
+// terminator is in a quoted part
+#[derive(Clone, Copy, Default)]
+struct QuoteScan {
+    delimiter: u8,
+    quote: u8,
+    release: Option<u8>,
+    lenient: bool,
+    in_quoted: bool,
+    // Of the bytes so far
+    part_len: usize,
+    released: bool,
+}
+impl QuoteScan {
+    fn feed(&mut self, bytes: &[u8]) {
+        let mut i = 0;
+        while i < bytes.len() {
+            let byte = bytes[i];
+            if self.released {
+                self.released = false;
+            } else if self.release == Some(byte) {
+                self.released = true;
+            } else if byte == self.quote && self.lenient {
+                self.in_quoted = !self.in_quoted;
+            } else if byte == self.quote && self.part_len == 0 {
+                self.in_quoted = true;
+            } else if byte == self.quote && self.in_quoted {
+                // A "" is a quote, the bytes fed always end at a terminator
+                if bytes.get(i + 1) == Some(&self.quote) {
+                    i += 1;
+                    self.part_len += 1;
+                } else {
+                    self.in_quoted = false;
+                }
+            } else if byte == self.delimiter && !self.in_quoted {
+                self.part_len = 0;
+                i += 1;
+                continue;
+            }
+            self.part_len += 1;
+            i += 1;
+        }
+    }
+}
+
+enum Decoder {
+    Utf8,
+    // Each byte is its codepoint, like Python's "latin-1" (the WHATWG
+    // encodings treat latin-1 as cp1252)
+    Latin1,
+    Other(&'static Encoding),
+}
+
+// A start line, then any of the children, then the end line (if any). Lines
+// are given by their names
+#[derive(Debug, Deserialize, Serialize)]
+struct Grouper {
+    start: Vec<String>,
+    children: Vec<Vec<String>>,
+    end: Option<Vec<String>>,
+}
+
+// Checks a trailer line against the lines since the previous one (or the start)
+#[derive(Debug, Deserialize, Serialize)]
+struct TrailerCheck {
+    line: Vec<String>,
+    // Index into the trailer's fields of the number of lines
+    count_field: Option<usize>,
+    // The lines to count, all but the trailer if not given
+    count_lines: Option<Vec<Vec<String>>>,
+    sums: Vec<TrailerSum>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct TrailerSum {
+    // Index into the trailer's fields of the total
+    field: usize,
+    // The line and the index into its fields of the values to add up
+    line: Vec<String>,
+    line_field: usize,
+}
+
+// Where the line name comes from
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "kind")]
+enum Discriminator {
+    // The part at index, the rest of the parts are the fields
+    #[serde(rename = "field")]
+    Field { index: usize },
+    // A byte range of the line, all the parts are fields
+    #[serde(rename = "bytes")]
+    Bytes { start: usize, end: usize },
+    // No line name, there's a single line and the output is just the fields
+    #[serde(rename = "none")]
+    None,
+}
+impl Default for Discriminator {
+    fn default() -> Self {
+        Discriminator::Field { index: 0 }
+    }
+}
+
+impl Schema {
+    pub fn line_names(&self) -> impl Iterator<Item = &[String]> + '_ {
+        self.lines.iter().map(|line| line.names.as_slice())
+    }
+    // Of the first line with these names, or an alias of them
+    pub fn line_index(&self, names: &[String]) -> Option<usize> {
+        self.lines
+            .iter()
+            .position(|line| line.names == names)
+            .or_else(|| {
+                self.lines
+                    .iter()
+                    .position(|line| line.aliases.iter().any(|alias| alias == names))
+            })
+    }
+    // Build lookups that aren't part of the JSON
+    fn prepare(&mut self, quote_char: Option<char>) {
+        for field in self
+            .lines
+            .iter_mut()
+            .flat_map(|line| line.fields.iter_mut())
+        {
+            field.prepare(quote_char)
+        }
+    }
+}
+
+enum Matcher {
+    Prefix(String),
+    Regex(Regex),
+}
+
+// Everything parse_line needs from the Schema, worked out once up front. Bad
+// delimiters are kept as errors so they're raised when parsing, as before
+struct Plan {
+    delimiter: Result<char, &'static str>,
+    quote_char: Result<Option<char>, &'static str>,
+    component_delimiter: Option<char>,
+    release_char: Option<char>,
+    decoder: Decoder,
+    terminator: Terminator,
+    // Of schema.multiline_quoted
+    quote_scan: Option<QuoteScan>,
+    // Every line has Line.widths
+    fixed_width: bool,
+    // Line name (joined by NAME_SEP) -> indexes into schema.lines, the first
+    // line in the version wins
+    lines: HashMap<String, Vec<usize>>,
+    // The distinct number of names, longest first
+    name_lengths: Vec<usize>,
+    // Prefix/regex lines, tried in order if there's no exact match
+    matchers: Vec<(Vec<Matcher>, usize)>,
+    // schema.groups with the names swapped for line indexes
+    groups: Vec<PlanGroup>,
+    // Start line index -> index into groups
+    group_starts: HashMap<usize, usize>,
+    trailer_checks: Vec<PlanTrailerCheck>,
+    sequence: Option<Sequence>,
+    // Line index -> index of its sequence field
+    sequence_fields: HashMap<usize, usize>,
+    // Line index and field index of schema.version_field
+    version_field: Option<(usize, usize)>,
+}
+
+struct PlanGroup {
+    start: usize,
+    children: Vec<usize>,
+    end: Option<usize>,
+}
+
+// As with PlanGroup, lines are indexes into schema.lines
+struct PlanTrailerCheck {
+    line: usize,
+    count_field: Option<usize>,
+    count_lines: Option<Vec<usize>>,
+    // (trailer field, line, line field)
+    sums: Vec<(usize, usize, usize)>,
+}
+
+const NAME_SEP: &str = "\x1f";
+impl Plan {
+    fn new(schema: &Schema) -> Result<Self, String> {
+        if matches!(schema.discriminator, Discriminator::None) && schema.lines.len() != 1 {
+            return Err("Schemas without a line name need exactly one line".to_string());
+        }
+        let delimiter = if schema.delimiter.len() == 1 {
+            Ok(schema.delimiter.chars().next().unwrap())
+        } else {
+            Err("Delimiter needs to be of length 1")
+        };
+        let quote_char = match (&schema.quote_str, schema.quote_mode) {
+            (Some(_), QuoteMode::None) => Ok(None),
+            (Some(quote_str), _) if quote_str.len() == 1 => Ok(quote_str.chars().next()),
+            (Some(_), _) => Err("Quote needs to be of length 1"),
+            (None, QuoteMode::All) => return Err("quote_mode all needs a quote_str".to_string()),
+            (None, _) => Ok(None),
+        };
+        let single_char = |name: &str, value: &Option<String>| match value {
+            Some(value) if value.chars().count() == 1 => Ok(value.chars().next()),
+            Some(_) => Err(format!("{} needs to be of length 1", name)),
+            None => Ok(None),
+        };
+        let component_delimiter = single_char("component_delimiter", &schema.component_delimiter)?;
+        let release_char = single_char("release_str", &schema.release_str)?;
+        for field in schema.lines.iter().flat_map(|line| line.fields.iter()) {
+            if let Field::Composite(CompositeField { fields, .. }) = field {
+                if component_delimiter.is_none() {
+                    return Err("COMPOSITE fields need a component_delimiter".to_string());
+                }
+                if fields
+                    .iter()
+                    .any(|field| matches!(field, Field::Composite(_)))
+                {
+                    return Err("COMPOSITE fields can't be nested".to_string());
+                }
+            }
+            let components = match field {
+                Field::Composite(CompositeField { fields, .. }) => &fields[..],
+                _ => std::slice::from_ref(field),
+            };
+            for field in components {
+                let Field::Datetime(DatetimeField {
+                    time_zone,
+                    output_time_zone,
+                    ..
+                }) = field
+                else {
+                    continue;
+                };
+                for (name, zone) in [
+                    ("time_zone", time_zone),
+                    ("output_time_zone", output_time_zone),
+                ] {
+                    if let Some(zone) = zone.as_ref().filter(|zone| zone.parse::<Tz>().is_err()) {
+                        return Err(format!("Unknown {}: '{}'", name, zone));
+                    }
+                }
+            }
+        }
+        let decoder = match schema.encoding.to_lowercase().replace('_', "-").as_str() {
+            "utf-8" | "utf8" => Decoder::Utf8,
+            "latin-1" | "latin1" | "iso-8859-1" | "iso8859-1" => Decoder::Latin1,
+            label => match Encoding::for_label(label.as_bytes()) {
+                // Lines are split on delimiters etc. before decoding
+                Some(encoding) if !encoding.is_ascii_compatible() => {
+                    return Err(format!(
+                        "Encoding '{}' isn't ASCII compatible",
+                        schema.encoding
+                    ))
+                }
+                Some(encoding) => Decoder::Other(encoding),
+                None => return Err(format!("Unknown encoding: '{}'", schema.encoding)),
+            },
+        };
+        let kind = match &schema.line_terminator {
+            None => TerminatorKind::Universal,
+            Some(terminator) if terminator.is_empty() => {
+                return Err("line_terminator can't be empty".to_string())
+            }
+            Some(terminator) => TerminatorKind::Exact(terminator.clone()),
+        };
+        let terminator = Terminator {
+            kind,
+            release: match release_char {
+                Some(ch) if ch.is_ascii() => Some(ch as u8),
+                Some(_) => return Err("release_str needs to be ASCII".to_string()),
+                None => None,
+            },
+        };
+        let fixed_width = schema.lines.iter().any(|line| !line.widths.is_empty());
+        if fixed_width {
+            if let Some(line) = schema
+                .lines
+                .iter()
+                .find(|line| line.widths.len() != line.fields.len() || line.widths.contains(&0))
+            {
+                return Err(format!("Line {:?} needs a width per field", line.names));
+            }
+            if let Discriminator::Field { .. } = schema.discriminator {
+                return Err("Fixed-width lines need a bytes or none discriminator".to_string());
+            }
+            if schema.multiline_quoted {
+                return Err("multiline_quoted can't be used with fixed-width lines".to_string());
+            }
+        }
+        let quote_scan = match (schema.multiline_quoted, delimiter, quote_char) {
+            (false, _, _) => None,
+            (true, Err(e), _) | (true, _, Err(e)) => return Err(e.to_string()),
+            (true, _, _) if matches!(schema.quote_mode, QuoteMode::None) => {
+                return Err("multiline_quoted can't be used with quote_mode none".to_string())
+            }
+            (true, _, Ok(None)) => return Err("multiline_quoted needs a quote_str".to_string()),
+            (true, Ok(delimiter), Ok(Some(quote_char))) => {
+                let ascii = |ch: Option<char>| match ch {
+                    Some(ch) if ch.is_ascii() => Ok(Some(ch as u8)),
+                    Some(_) => Err("multiline_quoted needs ASCII delimiters and quotes"),
+                    None => Ok(None),
+                };
+                Some(QuoteScan {
+                    delimiter: ascii(Some(delimiter))?.unwrap(),
+                    quote: ascii(Some(quote_char))?.unwrap(),
+                    release: ascii(release_char)?,
+                    lenient: matches!(schema.quote_mode, QuoteMode::Lenient),
+                    ..QuoteScan::default()
+                })
+            }
+        };
+        let mut lines = HashMap::new();
+        let mut name_lengths = vec![];
+        let mut matchers = vec![];
+        for (i, line) in schema.lines.iter().enumerate() {
+            for names in iter::once(&line.names).chain(&line.aliases) {
+                match line.line_match {
+                    LineMatch::Exact => {
+                        lines.entry(names.join(NAME_SEP)).or_insert(vec![]).push(i);
+                        name_lengths.push(names.len());
+                    }
+                    LineMatch::Prefix => {
+                        let prefixes = names.iter().cloned().map(Matcher::Prefix).collect();
+                        matchers.push((prefixes, i))
+                    }
+                    LineMatch::Regex => {
+                        let regexes = names
+                            .iter()
+                            .map(|name| Regex::new(name).map(Matcher::Regex))
+                            .collect::<Result<_, _>>()
+                            .map_err(|e| e.to_string())?;
+                        matchers.push((regexes, i))
+                    }
+                }
+            }
+        }
+        name_lengths.sort_unstable_by(|a, b| b.cmp(a));
+        name_lengths.dedup();
+
+        let find = |names: &Vec<String>| {
+            schema
+                .line_index(names)
+                .ok_or_else(|| format!("Line {:?} isn't in the schema", names))
+        };
+        let mut groups = vec![];
+        let mut group_starts = HashMap::new();
+        for (group_i, group) in schema.groups.iter().enumerate() {
+            let start = find(&group.start)?;
+            if group_starts.insert(start, group_i).is_some() {
+                return Err(format!("Line {:?} starts more than one group", group.start));
+            }
+            groups.push(PlanGroup {
+                start,
+                children: group.children.iter().map(find).collect::<Result<_, _>>()?,
+                end: group.end.as_ref().map(find).transpose()?,
+            });
+        }
+        let check_field = |line: usize, field: usize| {
+            if field < schema.lines[line].fields.len() {
+                Ok(field)
+            } else {
+                Err(format!(
+                    "Line {:?} doesn't have a field {}",
+                    schema.lines[line].names, field
+                ))
+            }
+        };
+        let mut trailer_checks = vec![];
+        for check in &schema.trailer_checks {
+            let line = find(&check.line)?;
+            let mut sums = vec![];
+            for sum in &check.sums {
+                let sum_line = find(&sum.line)?;
+                sums.push((
+                    check_field(line, sum.field)?,
+                    sum_line,
+                    check_field(sum_line, sum.line_field)?,
+                ));
+            }
+            trailer_checks.push(PlanTrailerCheck {
+                line,
+                count_field: check
+                    .count_field
+                    .map(|field| check_field(line, field))
+                    .transpose()?,
+                count_lines: check
+                    .count_lines
+                    .as_ref()
+                    .map(|lines| lines.iter().map(find).collect::<Result<_, _>>())
+                    .transpose()?,
+                sums,
+            });
+        }
+        for (line_i, line) in schema.lines.iter().enumerate() {
+            for field in &line.unique_key {
+                check_field(line_i, *field)?;
+            }
+            let distinct: HashSet<&String> = line.field_names.iter().collect();
+            if !line.field_names.is_empty()
+                && (line.field_names.len() != line.fields.len()
+                    || distinct.len() != line.field_names.len())
+            {
+                return Err(format!(
+                    "Line {:?} needs a distinct field name per field",
+                    line.names
+                ));
+            }
+        }
+        let version_field = match &schema.version_field {
+            Some(version_field) => {
+                let line = find(&version_field.line)?;
+                if !schema.lines[line].versions.is_empty() {
+                    return Err(format!(
+                        "Line {:?} has the version, so needs to be in every version",
+                        version_field.line
+                    ));
+                }
+                Some((line, check_field(line, version_field.field)?))
+            }
+            None => None,
+        };
+        if schema
+            .continuation
+            .as_ref()
+            .is_some_and(|continuation| continuation.marker.is_empty())
+        {
+            return Err("continuation.marker can't be empty".to_string());
+        }
+        let mut sequence = None;
+        let mut sequence_fields = HashMap::new();
+        for (line_i, line) in schema.lines.iter().enumerate() {
+            for (field_i, field) in line.fields.iter().enumerate() {
+                if let Field::Int(IntField {
+                    sequence: Some(field_sequence),
+                    ..
+                }) = field
+                {
+                    if sequence.is_some_and(|s| s != *field_sequence) {
+                        return Err("Sequence fields need the same start and step".to_string());
+                    }
+                    if sequence_fields.insert(line_i, field_i).is_some() {
+                        return Err(format!("Line {:?} has more than one sequence", line.names));
+                    }
+                    sequence = Some(*field_sequence);
+                }
+            }
+        }
+        Ok(Plan {
+            delimiter,
+            quote_char,
+            component_delimiter,
+            release_char,
+            decoder,
+            terminator,
+            quote_scan,
+            fixed_width,
+            lines,
+            name_lengths,
+            matchers,
+            groups,
+            group_starts,
+            trailer_checks,
+            sequence,
+            sequence_fields,
+            version_field,
+        })
+    }
+    // Returns the line index and how many of the names it used, of the lines
+    // in_version allows
+    fn line_index(
+        &self,
+        names: &[&str],
+        in_version: impl Fn(usize) -> bool,
+    ) -> Option<(usize, usize)> {
+        for n in &self.name_lengths {
+            let found = match names.get(..*n) {
+                Some([name]) => self.lines.get(*name),
+                Some(names) => self.lines.get(&names.join(NAME_SEP)),
+                None => None,
+            };
+            if let Some(i) = found.and_then(|lines| lines.iter().find(|i| in_version(**i))) {
+                return Some((*i, *n));
+            }
+        }
+        self.matchers.iter().find_map(|(matchers, i)| {
+            let is_match = in_version(*i)
+                && names.len() >= matchers.len()
+                && matchers
+                    .iter()
+                    .zip(names)
+                    .all(|(matcher, name)| match matcher {
+                        Matcher::Prefix(prefix) => name.starts_with(prefix.as_str()),
+                        Matcher::Regex(regex) => regex.is_match(name),
+                    });
+            if is_match {
+                Some((*i, matchers.len()))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+// A parsed field, or line name. Values of the same kind order as you'd expect
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum Value {
+    // An empty, not required field
+    Null,
+    Str(String),
+    Int(i128),
+    Float(f64),
+    Decimal(Decimal),
+    Bool(bool),
+    Datetime(DateTime<FixedOffset>),
+    // Of DatetimeField.output_naive
+    NaiveDatetime(NaiveDateTime),
+    Date(NaiveDate),
+    Time(NaiveTime),
+    Composite(Vec<Value>),
+}
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+    // Ints, floats and decimals, for adding up
+    pub fn to_decimal(&self) -> Option<Decimal> {
+        match self {
+            Value::Int(i) => Decimal::from_i128(*i),
+            Value::Float(f) => Decimal::from_str_exact(&f.to_string()).ok(),
+            Value::Decimal(d) => Some(*d),
+            _ => None,
+        }
+    }
+    // Decimals, and ints and floats JSON numbers can't hold, are strings.
+    // Datetimes, dates and times are ISO 8601 strings
+    pub fn to_json(&self) -> serde_json::Value {
+        use serde_json::Value as Json;
+        match self {
+            Value::Null => Json::Null,
+            Value::Int(i) => match i.to_i64() {
+                Some(i) => Json::from(i),
+                None => Json::String(i.to_string()),
+            },
+            Value::Float(f) if f.is_finite() => Json::from(*f),
+            Value::Bool(b) => Json::Bool(*b),
+            Value::Composite(values) => values.iter().map(Value::to_json).collect(),
+            value => Json::String(value.to_string()),
+        }
+    }
+}
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => Ok(()),
+            Value::Str(s) => f.write_str(s),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Decimal(d) => write!(f, "{}", d),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Datetime(dt) => write!(f, "{}", dt.to_rfc3339()),
+            Value::NaiveDatetime(dt) => write!(f, "{}", dt.format("%Y-%m-%dT%H:%M:%S%.f")),
+            Value::Date(d) => write!(f, "{}", d),
+            Value::Time(t) => write!(f, "{}", t),
+            Value::Composite(values) => {
+                let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                write!(f, "({})", values.join(", "))
+            }
+        }
+    }
+}
+
+// A Value in a Line.unique_key, equal to another when the values are, eg: the
+// decimals 1.0 and 1.00
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum KeyValue {
+    Null,
+    Str(String),
+    Int(i128),
+    // The bits, with -0.0 as 0.0
+    Float(u64),
+    // Normalized
+    Decimal(Decimal),
+    Bool(bool),
+    Datetime(DateTime<FixedOffset>),
+    NaiveDatetime(NaiveDateTime),
+    Date(NaiveDate),
+    Time(NaiveTime),
+    Composite(Vec<KeyValue>),
+}
+impl From<&Value> for KeyValue {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => KeyValue::Null,
+            Value::Str(s) => KeyValue::Str(s.clone()),
+            Value::Int(i) => KeyValue::Int(*i),
+            Value::Float(x) if *x == 0.0 => KeyValue::Float(0.0f64.to_bits()),
+            Value::Float(x) => KeyValue::Float(x.to_bits()),
+            Value::Decimal(d) => KeyValue::Decimal(d.normalize()),
+            Value::Bool(b) => KeyValue::Bool(*b),
+            Value::Datetime(dt) => KeyValue::Datetime(*dt),
+            Value::NaiveDatetime(dt) => KeyValue::NaiveDatetime(*dt),
+            Value::Date(d) => KeyValue::Date(*d),
+            Value::Time(t) => KeyValue::Time(*t),
+            Value::Composite(values) => {
+                KeyValue::Composite(values.iter().map(Into::into).collect())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    Invalid,
+    // A trailer doesn't agree with the lines before it
+    Reconciliation,
+}
+
+// Where a line was when parsing many of them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub line: String,
+    // 1-based
+    pub line_no: usize,
+    // Of the start of the line
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub location: Option<Location>,
+}
+impl Error {
+    fn reconciliation(message: String) -> Self {
+        Error {
+            kind: ErrorKind::Reconciliation,
+            message,
+            location: None,
+        }
+    }
+}
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error {
+            kind: ErrorKind::Invalid,
+            message,
+            location: None,
+        }
+    }
+}
+impl<'a> From<&'a str> for Error {
+    fn from(message: &'a str) -> Self {
+        Error::from(message.to_string())
+    }
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+impl std::error::Error for Error {}
+
+// A parsed line
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedLine {
+    // Index into schema.lines
+    pub line_index: usize,
+    // The line name(s), then the fields
+    pub values: Vec<Value>,
+    // How many of the values are line names
+    pub n_names: usize,
+    // The text each value was parsed from, with schema.include_raw
+    pub raw: Option<Vec<String>>,
+    // Of the constraints with severity warn that failed
+    pub warnings: Vec<String>,
+}
+impl ParsedLine {
+    // Without the line name(s), and with any kept extra fields last
+    pub fn fields(&self) -> &[Value] {
+        &self.values[self.n_names..]
+    }
+}
+
+// What was seen when parsing many lines
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub lines: usize,
+    pub errors: usize,
+    // Per schema line
+    pub line_stats: Vec<LineStats>,
+}
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineStats {
+    pub count: usize,
+    // Per field, min and max are None if every value was
+    pub null_counts: Vec<usize>,
+    pub min_values: Vec<Option<Value>>,
+    pub max_values: Vec<Option<Value>>,
+}
+impl Stats {
+    pub fn new(schema: &Schema) -> Self {
+        let line_stats = schema
+            .lines
+            .iter()
+            .map(|line| LineStats {
+                count: 0,
+                null_counts: vec![0; line.fields.len()],
+                min_values: vec![None; line.fields.len()],
+                max_values: vec![None; line.fields.len()],
+            })
+            .collect();
+        Stats {
+            lines: 0,
+            errors: 0,
+            line_stats,
+        }
+    }
+    pub fn add(&mut self, parsed: &ParsedLine) {
+        self.lines += 1;
+        let line_stats = &mut self.line_stats[parsed.line_index];
+        line_stats.count += 1;
+        let n_fields = line_stats.null_counts.len();
+        for (i, value) in parsed.fields()[..n_fields].iter().enumerate() {
+            if value.is_null() {
+                line_stats.null_counts[i] += 1;
+                continue;
+            }
+            let min = &mut line_stats.min_values[i];
+            let is_min = match min {
+                Some(min) => value < min,
+                None => true,
+            };
+            if is_min {
+                *min = Some(value.clone());
+            }
+            let max = &mut line_stats.max_values[i];
+            let is_max = match max {
+                Some(max) => value > max,
+                None => true,
+            };
+            if is_max {
+                *max = Some(value.clone());
+            }
+        }
+    }
+    pub fn add_error(&mut self) {
+        self.lines += 1;
+        self.errors += 1;
+    }
+}
+
+// Schema JSON can declare "field_groups": {name: {"fields": [...], "field_names": [...]}},
+// used in Line.fields as {"kind": "GROUP", "name": name, "prefix": "billing_"}, and
+// "includes": [path, ...] of JSON files with more includes, field_groups and lines.
+// These are resolved before deserializing the Schema
+#[derive(Debug, Deserialize)]
+struct FieldGroup {
+    fields: Vec<serde_json::Value>,
+    // Name the fields that aren't named by the groups in them
+    #[serde(default)]
+    field_names: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GroupRef {
+    name: String,
+    // Put before each of the group's field names
+    #[serde(default)]
+    prefix: String,
+}
+
+// Takes the includes, then the field_groups and lines of schema, included lines first.
+// A file included again by another path, eg: two includes that both include a
+// common file, is only taken the first time. stack is the files being included
+fn include(
+    schema: &mut serde_json::Map<String, serde_json::Value>,
+    base_dir: &Path,
+    stack: &mut Vec<PathBuf>,
+    included: &mut HashSet<PathBuf>,
+    groups: &mut HashMap<String, FieldGroup>,
+    lines: &mut Vec<serde_json::Value>,
+) -> Result<(), Error> {
+    let paths: Vec<String> = match schema.remove("includes") {
+        Some(paths) => serde_json::from_value(paths).map_err(|_| "includes need paths")?,
+        None => vec![],
+    };
+    for path in paths {
+        let path = base_dir.join(path);
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("Can't include schema {:?}: {}", path, e))?;
+        let canonical = path.canonicalize().map_err(|e| e.to_string())?;
+        if stack.contains(&canonical) {
+            return Err(format!("Schema {:?} includes itself", path).into());
+        }
+        if !included.insert(canonical.clone()) {
+            continue;
+        }
+        let mut included_schema: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(&text)
+                .map_err(|e| format!("Included schema {:?}: {}", path, e))?;
+        if let Some(key) = included_schema
+            .keys()
+            .find(|key| !["includes", "field_groups", "lines"].contains(&key.as_str()))
+        {
+            return Err(format!(
+                "Included schema {:?} can only have includes, field_groups and lines, not {:?}",
+                path, key
+            )
+            .into());
+        }
+        let included_dir = canonical.parent().unwrap_or(Path::new("")).to_path_buf();
+        stack.push(canonical);
+        include(
+            &mut included_schema,
+            &included_dir,
+            stack,
+            included,
+            groups,
+            lines,
+        )?;
+        stack.pop();
+    }
+    if let Some(own_groups) = schema.remove("field_groups") {
+        let own_groups: HashMap<String, FieldGroup> =
+            serde_json::from_value(own_groups).map_err(|e| format!("field_groups: {}", e))?;
+        for (name, group) in own_groups {
+            if groups.contains_key(&name) {
+                return Err(format!("Field group {:?} is declared more than once", name).into());
+            }
+            groups.insert(name, group);
+        }
+    }
+    if let Some(own_lines) = schema.remove("lines") {
+        let serde_json::Value::Array(own_lines) = own_lines else {
+            return Err("lines need to be a list".into());
+        };
+        lines.extend(own_lines);
+    }
+    Ok(())
+}
+
+// Swaps GROUP fields for the group's fields, also within COMPOSITE fields,
+// then names the fields that aren't named by a group in order with names, if
+// given. None is unnamed
+fn expand_fields(
+    fields: &[serde_json::Value],
+    names: &[String],
+    groups: &HashMap<String, FieldGroup>,
+    stack: &mut Vec<String>,
+) -> Result<(Vec<serde_json::Value>, Vec<Option<String>>), Error> {
+    let mut expanded = vec![];
+    let mut expanded_names = vec![];
+    for field in fields {
+        let kind = field.get("kind").and_then(|kind| kind.as_str());
+        if kind == Some("COMPOSITE") {
+            let Some(serde_json::Value::Array(fields)) = field.get("fields") else {
+                return Err("COMPOSITE field needs fields".into());
+            };
+            // The parts of a composite aren't named, so neither are its groups'
+            let (fields, _) = expand_fields(fields, &[], groups, stack)?;
+            let mut field = field.clone();
+            field["fields"] = fields.into();
+            expanded.push(field);
+            expanded_names.push(None);
+            continue;
+        }
+        if kind != Some("GROUP") {
+            expanded.push(field.clone());
+            expanded_names.push(None);
+            continue;
+        }
+        let group_ref: GroupRef =
+            serde_json::from_value(field.clone()).map_err(|e| format!("GROUP field: {}", e))?;
+        let Some(group) = groups.get(&group_ref.name) else {
+            return Err(format!("Unknown field group {:?}", group_ref.name).into());
+        };
+        if stack.contains(&group_ref.name) {
+            return Err(format!("Field group {:?} includes itself", group_ref.name).into());
+        }
+        stack.push(group_ref.name.clone());
+        let (group_fields, group_names) =
+            expand_fields(&group.fields, &group.field_names, groups, stack)
+                .map_err(|e| format!("Field group {:?}: {}", group_ref.name, e))?;
+        stack.pop();
+        expanded.extend(group_fields);
+        expanded_names.extend(
+            group_names
+                .into_iter()
+                .map(|name| name.map(|name| format!("{}{}", group_ref.prefix, name))),
+        );
+    }
+    if !names.is_empty() {
+        let mut names = names.iter();
+        for name in expanded_names.iter_mut().filter(|name| name.is_none()) {
+            *name = Some(names.next().ok_or("too few field_names")?.clone());
+        }
+        if names.next().is_some() {
+            return Err("too many field_names".into());
+        }
+    }
+    Ok((expanded, expanded_names))
+}
+
+fn resolve_schema(
+    schema: &mut serde_json::Map<String, serde_json::Value>,
+    base_dir: &Path,
+) -> Result<(), Error> {
+    if !schema.contains_key("includes") && !schema.contains_key("field_groups") {
+        return Ok(());
+    }
+    let mut groups = HashMap::new();
+    let mut lines = vec![];
+    include(
+        schema,
+        base_dir,
+        &mut vec![],
+        &mut HashSet::new(),
+        &mut groups,
+        &mut lines,
+    )?;
+    for line in &mut lines {
+        let Some(line) = line.as_object_mut() else {
+            continue;
+        };
+        let fields: Vec<serde_json::Value> = match line.get("fields") {
+            Some(fields) => serde_json::from_value(fields.clone()).map_err(|e| e.to_string())?,
+            None => continue,
+        };
+        let names: Vec<String> = match line.get("field_names") {
+            Some(names) => serde_json::from_value(names.clone()).map_err(|e| e.to_string())?,
+            None => vec![],
+        };
+        let name = line.get("name").cloned().unwrap_or_default();
+        let (fields, names) = expand_fields(&fields, &names, &groups, &mut vec![])
+            .map_err(|e| format!("Line {}: {}", name, e))?;
+        line.insert("fields".to_string(), fields.into());
+        if names.iter().any(Option::is_some) {
+            let names: Option<Vec<String>> = names.into_iter().collect();
+            let names = names.ok_or_else(|| {
+                format!(
+                    "Line {} needs field_names for the fields its groups don't name",
+                    name
+                )
+            })?;
+            line.insert("field_names".to_string(), names.into());
+        }
+    }
+    schema.insert("lines".to_string(), lines.into());
+    Ok(())
+}
+
+pub struct Parser {
+    schema: Schema,
+    plan: Plan,
+}
+impl Parser {
+    pub fn new(mut schema: Schema) -> Result<Self, Error> {
+        let plan = Plan::new(&schema)?;
+        schema.prepare(plan.quote_char.unwrap_or(None));
+        Ok(Parser { schema, plan })
+    }
+    pub fn from_json(schema_json_str: &str) -> Result<Self, Error> {
+        Parser::from_json_in(schema_json_str, Path::new(""))
+    }
+    // With schema.includes relative to base_dir, eg: the directory of the schema file
+    pub fn from_json_in(schema_json_str: &str, base_dir: &Path) -> Result<Self, Error> {
+        let mut schema: serde_json::Value =
+            serde_json::from_str(schema_json_str).map_err(|e| e.to_string())?;
+        if let Some(schema) = schema.as_object_mut() {
+            resolve_schema(schema, base_dir)?;
+        }
+        let schema: Schema = serde_json::from_value(schema).map_err(|e| e.to_string())?;
+        Parser::new(schema)
+    }
+    // As {"line": name(s), "fields": {name: value}}, fields without a
+    // Line.field_names name are keyed by their index, kept extra fields by "extra"
+    pub fn to_json(&self, parsed: &ParsedLine) -> serde_json::Value {
+        let schema_line = &self.schema.lines[parsed.line_index];
+        let line = match &parsed.values[..parsed.n_names] {
+            [] => serde_json::Value::Null,
+            [name] => name.to_json(),
+            names => names.iter().map(Value::to_json).collect(),
+        };
+        let fields: serde_json::Map<String, serde_json::Value> = parsed
+            .fields()
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let name = match schema_line.field_names.get(i) {
+                    Some(name) => name.clone(),
+                    None if i >= schema_line.fields.len() => "extra".to_string(),
+                    None => i.to_string(),
+                };
+                (name, value.to_json())
+            })
+            .collect();
+        serde_json::json!({"line": line, "fields": fields})
+    }
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+    // The line name(s), then the fields
+    pub fn parse_line(&self, line: &str) -> Result<Vec<Value>, Error> {
+        self.parse_indexed(self.strip_bom(line))
+            .map(|parsed| parsed.values)
+    }
+    pub fn parse_line_bytes(&self, line: &[u8]) -> Result<Vec<Value>, Error> {
+        self.parse_line(&self.decode(line)?)
+    }
+    // The line name, or the first field for schemas without one
+    pub fn parse_first(&self, line: &str) -> Result<String, Error> {
+        let quote_char = self.plan.quote_char?;
+        if let Some(quote_char) = quote_char.filter(|q| line.starts_with(*q)) {
+            return Ok(line
+                .chars()
+                .skip(1)
+                .take_while(|ch| *ch != quote_char)
+                .collect());
+        };
+        let delimiter = self.plan.delimiter?;
+        Ok(line.chars().take_while(|ch| *ch != delimiter).collect())
+    }
+    // The first n parts of the line, unquoted and unescaped, without matching
+    // the line against the schema or parsing the rest of it
+    pub fn parse_prefix(&self, line: &str, n: usize) -> Result<Vec<String>, Error> {
+        let parts = split_line_n(
+            self.plan.terminator.strip(line),
+            self.plan.delimiter?,
+            self.plan.quote_char?,
+            self.schema.quote_mode,
+            self.plan.release_char,
+            n,
+        );
+        Ok(parts
+            .iter()
+            .map(|part| unescape(&part.value, self.plan.release_char).into_owned())
+            .collect())
+    }
+    // The parts of the line as parse_line sees them, unquoted and unescaped
+    pub fn split<'a>(&self, line: &'a str) -> Result<Vec<Part<'a>>, Error> {
+        let delimiter = self.plan.delimiter?;
+        let line = self.plan.terminator.strip(line);
+        let mut parts = split_line(
+            line,
+            delimiter,
+            self.plan.quote_char?,
+            self.schema.quote_mode,
+            self.plan.release_char,
+        );
+        // Lines that aren't in the schema split as its trailing_delimiter
+        let (_, names) = self.line_names(line, &parts)?;
+        let trailing_delimiter = match self.find_line(&names, None) {
+            Some((line_i, _)) => self.trailing_delimiter(line_i),
+            None => self.schema.trailing_delimiter,
+        };
+        let n_parts = self.n_parts(line, &parts, trailing_delimiter)?;
+        parts.truncate(n_parts);
+        for part in parts.iter_mut() {
+            if let Cow::Owned(value) = unescape(&part.value, self.plan.release_char) {
+                part.value = Cow::Owned(value);
+            }
+        }
+        Ok(parts)
+    }
+    // Find the schema line, and so whether the last part is just from a
+    // trailing delimiter, and check the quoting. Also returns the candidates
+    // for the line name
+    fn match_line<'b>(
+        &self,
+        line: &'b str,
+        parts: &'b [Part],
+        version: Option<&str>,
+    ) -> Result<(Matched, Vec<&'b str>), Error> {
+        let (start, names) = self.line_names(line, parts)?;
+        let (line_i, n_names) = self
+            .find_line(&names, version)
+            .ok_or_else(|| format!("No schema line matching '{}'", names[0]))?;
+        let n_parts = self.n_parts(line, parts, self.trailing_delimiter(line_i))?;
+        let matched = Matched {
+            line_i,
+            start: start.min(n_parts),
+            n_names,
+            n_parts,
+        };
+        Ok((matched, names))
+    }
+    // The index of the first name part and the candidates for the line name
+    fn line_names<'b>(
+        &self,
+        line: &'b str,
+        parts: &'b [Part],
+    ) -> Result<(usize, Vec<&'b str>), Error> {
+        let (start, names): (usize, Vec<&str>) = match self.schema.discriminator {
+            Discriminator::Field { index } => {
+                if parts.len() <= index {
+                    return Err(format!("Split line has length < {}", index + 1).into());
+                }
+                (
+                    index,
+                    parts[index..].iter().map(|part| part.as_str()).collect(),
+                )
+            }
+            Discriminator::Bytes { start, end } => {
+                let name = line
+                    .get(start..end)
+                    .ok_or_else(|| format!("Can't take bytes {}..{} of line", start, end))?;
+                (parts.len(), vec![name])
+            }
+            Discriminator::None => (parts.len(), vec![]),
+        };
+        Ok((start, names))
+    }
+    // The (line_i, n_names) of the schema line, if any
+    fn find_line(&self, names: &[&str], version: Option<&str>) -> Option<(usize, usize)> {
+        match self.schema.discriminator {
+            Discriminator::None => Some((0, 0)),
+            _ => self
+                .plan
+                .line_index(names, |i| self.schema.lines[i].in_version(version)),
+        }
+    }
+    fn trailing_delimiter(&self, line_i: usize) -> bool {
+        self.schema.lines[line_i]
+            .trailing_delimiter
+            .unwrap_or(self.schema.trailing_delimiter)
+    }
+    // Without the part after any trailing delimiter, checking the quoting
+    fn n_parts(
+        &self,
+        line: &str,
+        parts: &[Part],
+        trailing_delimiter: bool,
+    ) -> Result<usize, Error> {
+        let mut n_parts = parts.len();
+        if trailing_delimiter && !self.plan.fixed_width {
+            match parts.last() {
+                Some(last) if n_parts > 1 && last.start == line.len() && !last.is_quoted => {
+                    n_parts -= 1
+                }
+                _ => return Err("Line doesn't have trailing delimiter".into()),
+            }
+        }
+        if matches!(self.schema.quote_mode, QuoteMode::All) {
+            if let Some(part) = parts[..n_parts].iter().find(|part| !part.is_quoted) {
+                return Err(format!("Part isn't quoted - '{}'", part.value).into());
+            }
+        }
+        Ok(n_parts)
+    }
+    pub fn parse_indexed(&self, line: &str) -> Result<ParsedLine, Error> {
+        self.parse_checked(line, None, None)
+    }
+    // Every problem with the line rather than just the first, without keeping the output
+    pub fn validate_line(&self, line: &str) -> Vec<Error> {
+        self.validate_line_parsed(line).0
+    }
+    // As validate_line, with the output if the line matched, failing fields as Null
+    pub fn validate_line_parsed(&self, line: &str) -> (Vec<Error>, Option<ParsedLine>) {
+        let mut errors = vec![];
+        match self.parse_checked(self.strip_bom(line), None, Some(&mut errors)) {
+            Ok(parsed) => (errors, Some(parsed)),
+            Err(e) => {
+                errors.push(e);
+                (errors, None)
+            }
+        }
+    }
+    // With a version, only lines in it match. With field_errors, failing fields
+    // are added to them and output as Null
+    fn parse_checked(
+        &self,
+        line: &str,
+        version: Option<&str>,
+        mut field_errors: Option<&mut Vec<Error>>,
+    ) -> Result<ParsedLine, Error> {
+        let line_stripped = self.plan.terminator.strip(line);
+        if let Some(mut scan) = self.plan.quote_scan {
+            scan.feed(line_stripped.as_bytes());
+            if scan.in_quoted {
+                return Err("Quoted part isn't closed".into());
+            }
+        }
+        // Fixed-width lines are cut once we know which line it is
+        let all_parts = if self.plan.fixed_width {
+            vec![]
+        } else {
+            split_line(
+                line_stripped,
+                self.plan.delimiter?,
+                self.plan.quote_char?,
+                self.schema.quote_mode,
+                self.plan.release_char,
+            )
+        };
+        let (mut matched, names) = self.match_line(line_stripped, &all_parts, version)?;
+        let width_parts;
+        let parts = if self.plan.fixed_width {
+            width_parts = cut_line(line_stripped, &self.schema.lines[matched.line_i].widths);
+            // As with a bytes discriminator, all the parts are fields
+            matched.start = width_parts.len();
+            matched.n_parts = width_parts.len();
+            &width_parts[..]
+        } else {
+            &all_parts[..matched.n_parts]
+        };
+        let Matched {
+            line_i,
+            start,
+            n_names,
+            ..
+        } = matched;
+        let schema_line = &self.schema.lines[line_i];
+        let missing = Part {
+            value: Cow::Borrowed(""),
+            is_quoted: false,
+            start: line_stripped.len(),
+            end: line_stripped.len(),
+        };
+        let mut field_parts: Vec<&Part> = parts
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i < start || *i >= start + n_names)
+            .map(|(_, part)| part)
+            .collect();
+
+        let n_fields = schema_line.fields.len();
+        let is_allowed = match field_parts.len() {
+            n if n > n_fields => schema_line.allow_extra_fields,
+            n if n < n_fields => schema_line.allow_missing_fields,
+            _ => true,
+        };
+        if !is_allowed {
+            return Err(format!(
+                "Mismatched line length, schema length: {}, actual length: (header={}) + {}",
+                n_fields,
+                parts.len() - field_parts.len(),
+                field_parts.len()
+            )
+            .into());
+        }
+        let extra_parts = field_parts.split_off(n_fields.min(field_parts.len()));
+        field_parts.resize(n_fields, &missing);
+
+        let raw = if self.schema.include_raw {
+            let name_parts = parts.iter().skip(start).take(n_names);
+            let raw_parts = name_parts.chain(field_parts.iter().copied());
+            let mut raw: Vec<String> = raw_parts
+                .map(|part| line_stripped[part.start..part.end].to_string())
+                .collect();
+            if schema_line.keep_extra_fields {
+                raw.push(match (extra_parts.first(), extra_parts.last()) {
+                    (Some(first), Some(last)) => line_stripped[first.start..last.end].to_string(),
+                    _ => String::new(),
+                });
+            }
+            Some(raw)
+        } else {
+            None
+        };
+        let mut values: Vec<Value> = if schema_line.normalize_aliases {
+            schema_line.names.iter().cloned().map(Value::Str).collect()
+        } else {
+            names[..n_names]
+                .iter()
+                .map(|name| Value::Str(name.to_string()))
+                .collect()
+        };
+        let syntax = Syntax {
+            coerce_empty_quoted: self.schema.coerce_empty_quoted,
+            component_delimiter: self.plan.component_delimiter,
+            release_char: self.plan.release_char,
+        };
+        let mut warnings = vec![];
+        for (schema_field, part) in schema_line.fields.iter().zip(field_parts) {
+            match (
+                part_to_value(&syntax, schema_field, part, &mut warnings),
+                &mut field_errors,
+            ) {
+                (Ok(value), _) => values.push(value),
+                (Err(e), Some(errors)) => {
+                    errors.push(e.into());
+                    values.push(Value::Null);
+                }
+                (Err(e), None) => return Err(e.into()),
+            }
+        }
+        if schema_line.keep_extra_fields {
+            let extra = extra_parts
+                .iter()
+                .map(|part| Value::Str(unescape(&part.value, self.plan.release_char).into_owned()));
+            values.push(Value::Composite(extra.collect()));
+        }
+        Ok(ParsedLine {
+            line_index: line_i,
+            n_names: values.len() - n_fields - schema_line.keep_extra_fields as usize,
+            values,
+            raw,
+            warnings,
+        })
+    }
+    // Calls on_line with each line and its length including the terminator,
+    // returns how many bytes were used. Unless at_eof, the unused bytes may be
+    // the start of a line
+    pub fn split_lines<E, F: FnMut(&[u8], usize) -> Result<(), E>>(
+        &self,
+        bytes: &[u8],
+        at_eof: bool,
+        on_line: &mut F,
+    ) -> Result<usize, E> {
+        self.split_lines_from(&mut self.line_split(), bytes, at_eof, on_line)
+    }
+    fn line_split(&self) -> LineSplit {
+        LineSplit {
+            quote_scan: self.plan.quote_scan,
+            search: 0,
+            quoted_lines: 0,
+        }
+    }
+    // As split_lines, carrying on from where split left off in the previous
+    // bytes, less the bytes used
+    fn split_lines_from<E, F: FnMut(&[u8], usize) -> Result<(), E>>(
+        &self,
+        split: &mut LineSplit,
+        bytes: &[u8],
+        at_eof: bool,
+        on_line: &mut F,
+    ) -> Result<usize, E> {
+        let terminator = &self.plan.terminator;
+        let mut start = 0;
+        while let Some((line_end, next_start)) = terminator.find(&bytes[split.search..]) {
+            let (line_end, next_start) = (split.search + line_end, split.search + next_start);
+            // A "\r" at the end could be the start of a "\r\n"
+            let maybe_crlf = matches!(terminator.kind, TerminatorKind::Universal)
+                && next_start == bytes.len()
+                && bytes[line_end] == b'\r';
+            if maybe_crlf && !at_eof {
+                break;
+            }
+            if let Some(scan) = &mut split.quote_scan {
+                scan.feed(&bytes[split.search..next_start]);
+                split.search = next_start;
+                if scan.in_quoted && split.quoted_lines + 1 < self.schema.multiline_max_lines {
+                    split.quoted_lines += 1;
+                    continue;
+                }
+                *scan = self.plan.quote_scan.unwrap();
+                split.quoted_lines = 0;
+            }
+            on_line(&bytes[start..line_end], next_start - start)?;
+            start = next_start;
+            split.search = next_start;
+        }
+        if at_eof && start < bytes.len() {
+            on_line(&bytes[start..], bytes.len() - start)?;
+            start = bytes.len();
+            split.search = start;
+        }
+        split.search -= start;
+        Ok(start)
+    }
+    // Calls on_line with each line of the (possibly compressed) file, joined
+    // as in schema.continuation
+    pub fn read_file<E: From<io::Error>, F: FnMut(&[u8], usize) -> Result<(), E>>(
+        &self,
+        path: &str,
+        on_line: &mut F,
+    ) -> Result<(), E> {
+        let mut joiner = Joiner::new(self);
+        self.read_physical_lines(path, &mut |line: &[u8], raw_len: usize| {
+            joiner.push(line, raw_len, on_line)
+        })?;
+        joiner.finish(on_line)
+    }
+    fn read_physical_lines<E: From<io::Error>, F: FnMut(&[u8], usize) -> Result<(), E>>(
+        &self,
+        path: &str,
+        on_line: &mut F,
+    ) -> Result<(), E> {
+        match open_file(path)? {
+            Input::Mapped(mmap) => {
+                self.split_lines(&mmap, true, on_line)?;
+            }
+            Input::Stream(mut reader) => {
+                // Whatever's left of the previous chunk after its last terminator
+                let mut pending = vec![];
+                let mut split = self.line_split();
+                loop {
+                    let chunk = reader.fill_buf()?;
+                    let (at_eof, chunk_len) = (chunk.is_empty(), chunk.len());
+                    pending.extend_from_slice(chunk);
+                    reader.consume(chunk_len);
+                    let used = self.split_lines_from(&mut split, &pending, at_eof, on_line)?;
+                    pending.drain(..used);
+                    if at_eof {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn strip_bom<'b>(&self, line: &'b str) -> &'b str {
+        match line.strip_prefix('\u{feff}') {
+            Some(stripped) if self.schema.strip_bom => stripped,
+            _ => line,
+        }
+    }
+    // Decode bytes input as schema.encoding
+    pub fn decode<'b>(&self, bytes: &'b [u8]) -> Result<Cow<'b, str>, Error> {
+        let replace = matches!(self.schema.encoding_errors, EncodingErrors::Replace);
+        let decoded = match self.plan.decoder {
+            Decoder::Utf8 if replace => Some(String::from_utf8_lossy(bytes)),
+            Decoder::Utf8 => std::str::from_utf8(bytes).ok().map(Cow::Borrowed),
+            Decoder::Latin1 if bytes.is_ascii() => {
+                std::str::from_utf8(bytes).ok().map(Cow::Borrowed)
+            }
+            Decoder::Latin1 => Some(Cow::Owned(bytes.iter().map(|b| *b as char).collect())),
+            Decoder::Other(encoding) if replace => {
+                Some(encoding.decode_without_bom_handling(bytes).0)
+            }
+            Decoder::Other(encoding) => {
+                encoding.decode_without_bom_handling_and_without_replacement(bytes)
+            }
+        };
+        decoded.ok_or_else(|| format!("Line isn't valid {}", self.schema.encoding).into())
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct DatetimeField {
-    required: bool,
-    format: String,
-    time_zone: String,
+// Joins the lines of a record as in schema.continuation, the raw_len passed
+// on is that of all of them
+pub struct Joiner<'a> {
+    continuation: Option<&'a Continuation>,
+    terminator: &'a Terminator,
+    // The record so far, and its raw_len
+    pending: Option<(Vec<u8>, usize)>,
 }
-
-#[derive(Debug, Deserialize, Serialize)]
-struct DateField {
-    required: bool,
-    format: String,
+impl<'a> Joiner<'a> {
+    pub fn new(parser: &'a Parser) -> Self {
+        Joiner {
+            continuation: parser.schema.continuation.as_ref(),
+            terminator: &parser.plan.terminator,
+            pending: None,
+        }
+    }
+    pub fn push<E, F: FnMut(&[u8], usize) -> Result<(), E>>(
+        &mut self,
+        line: &[u8],
+        raw_len: usize,
+        on_line: &mut F,
+    ) -> Result<(), E> {
+        let Some(continuation) = self.continuation else {
+            return on_line(line, raw_len);
+        };
+        let marker = continuation.marker.as_bytes();
+        // Lines from parse_lines etc. can end in a terminator
+        let line = self.terminator.strip_bytes(line);
+        match continuation.kind {
+            ContinuationKind::Trailing => {
+                let (record, record_len) = self.pending.get_or_insert_with(|| (vec![], 0));
+                *record_len += raw_len;
+                match line.strip_suffix(marker) {
+                    Some(line) => {
+                        record.extend_from_slice(line);
+                        record.extend_from_slice(continuation.separator.as_bytes());
+                    }
+                    None => {
+                        record.extend_from_slice(line);
+                        let (record, record_len) = self.pending.take().unwrap();
+                        on_line(&record, record_len)?;
+                    }
+                }
+            }
+            ContinuationKind::Leading => match (&mut self.pending, line.strip_prefix(marker)) {
+                (Some((record, record_len)), Some(line)) => {
+                    record.extend_from_slice(continuation.separator.as_bytes());
+                    record.extend_from_slice(line);
+                    *record_len += raw_len;
+                }
+                // Only complete once we see the next line isn't a continuation
+                (pending, _) => {
+                    if let Some((record, record_len)) = pending.replace((line.to_vec(), raw_len)) {
+                        on_line(&record, record_len)?;
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+    // Passes on any record left at the end
+    pub fn finish<E, F: FnMut(&[u8], usize) -> Result<(), E>>(
+        &mut self,
+        on_line: &mut F,
+    ) -> Result<(), E> {
+        match self.pending.take() {
+            Some((record, record_len)) => on_line(&record, record_len),
+            None => Ok(()),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct TimeField {
-    required: bool,
-    format: String,
+enum Input {
+    // Uncompressed files are parsed straight out of the page cache
+    Mapped(Mmap),
+    Stream(Box<dyn BufRead>),
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Line {
-    name: String,
-    fields: Vec<Field>,
+// Decompresses gzip and zstd files, spotted by their magic bytes
+fn open_file(path: &str) -> io::Result<Input> {
+    let mut file = BufReader::new(File::open(path)?);
+    let magic = file.fill_buf()?;
+    let is_gzip = magic.starts_with(&[0x1f, 0x8b]);
+    let is_zstd = magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]);
+    if is_gzip {
+        Ok(Input::Stream(Box::new(BufReader::new(
+            MultiGzDecoder::new(file),
+        ))))
+    } else if is_zstd {
+        Ok(Input::Stream(Box::new(BufReader::new(
+            zstd::Decoder::with_buffer(file)?,
+        ))))
+    } else {
+        // Safety: the file mustn't be changed by anyone else while we parse it
+        let mmap = unsafe { Mmap::map(file.get_ref())? };
+        Ok(Input::Mapped(mmap))
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Schema {
-    delimiter: String,
-    quote_str: Option<String>,
-    trailing_delimiter: bool,
-    coerce_empty_quoted: bool,
-    lines: Vec<Line>,
+// Which schema line a split line is, see Parser::match_line
+struct Matched {
+    line_i: usize,
+    // Index of the first name part, n_names long
+    start: usize,
+    n_names: usize,
+    // Without the part after any trailing delimiter
+    n_parts: usize,
 }
 
-#[pyclass(frozen, module = "xlineparse")]
-pub struct Parser {
-    // Schema lives for the duration of the program
-    pub schema: &'static Schema,
+// State carried between lines when parsing many of them
+pub struct Batch<'a> {
+    parser: &'a Parser,
+    // Per trailer check, the lines counted and the totals since the last trailer
+    counts: Vec<usize>,
+    sums: Vec<Vec<Decimal>>,
+    // 1-based, of the last line parsed
+    line_no: usize,
+    // Of the start of the last line parsed, and the line after it
+    offset: usize,
+    next_offset: usize,
+    next_sequence: i128,
+    // Per line, the unique keys seen -> the line_no they were first seen at
+    keys: Vec<HashMap<Vec<KeyValue>, usize>>,
+    // From the last schema.version_field seen
+    version: Option<String>,
 }
-#[pymethods]
-impl Parser {
-    #[new]
-    fn new<'a>(_py: Python<'a>, schema_json_str: &str) -> PyResult<Self> {
-        let parsed_data: serde_json::Result<Schema> = serde_json::from_str(schema_json_str);
-        match parsed_data {
-            Ok(schemas) => {
-                // Schema lives for the duration of the program
-                let boxed = Box::new(schemas);
-                let leaked = Box::leak(boxed);
-                Ok(Parser { schema: leaked })
-            }
-            Err(e) => Err(PyValueError::new_err(e.to_string())),
-        }
-    }
-    fn parse_line<'a>(&self, _py: Python<'a>, line: &str) -> PyResult<PyObject> {
-        let delimiter = if self.schema.delimiter.len() == 1 {
-            Ok(self.schema.delimiter.chars().next().unwrap())
+impl<'a> Batch<'a> {
+    pub fn new(parser: &'a Parser) -> Self {
+        let checks = &parser.plan.trailer_checks;
+        Batch {
+            parser,
+            counts: vec![0; checks.len()],
+            sums: checks
+                .iter()
+                .map(|check| vec![Decimal::ZERO; check.sums.len()])
+                .collect(),
+            line_no: 0,
+            offset: 0,
+            next_offset: 0,
+            next_sequence: parser
+                .plan
+                .sequence
+                .map_or(0, |sequence| sequence.start as i128),
+            keys: vec![HashMap::new(); parser.schema.lines.len()],
+            version: None,
+        }
+    }
+    pub fn line_no(&self) -> usize {
+        self.line_no
+    }
+    // raw_len includes the line terminator, to keep track of the offset
+    fn start_line(&mut self, raw_len: usize) {
+        self.line_no += 1;
+        self.offset = self.next_offset;
+        self.next_offset += raw_len;
+    }
+    // Adds where we are to the error
+    pub fn error(&self, e: Error, verb: &str, line: &str) -> Error {
+        let line = self.parser.plan.terminator.strip(line);
+        Error {
+            kind: e.kind,
+            message: format!(
+                "Failed to {} line: '{}' (line {}, byte {})\n {}",
+                verb, line, self.line_no, self.offset, e.message
+            ),
+            location: Some(Location {
+                line: line.to_string(),
+                line_no: self.line_no,
+                offset: self.offset,
+            }),
+        }
+    }
+    pub fn parse_bytes(&mut self, line: &[u8], raw_len: usize) -> Result<ParsedLine, Error> {
+        match self.parser.decode(line) {
+            Ok(decoded) => self.parse(&decoded, raw_len),
+            Err(e) => {
+                self.start_line(raw_len);
+                Err(self.error(e, "decode", &String::from_utf8_lossy(line)))
+            }
+        }
+    }
+    // As Parser.validate_line, with the checks across lines
+    pub fn validate(&mut self, line: &str, raw_len: usize) -> Vec<Error> {
+        self.validate_parsed(line, raw_len).0
+    }
+    // As validate, with the output if the line matched, failing fields as Null
+    pub fn validate_parsed(
+        &mut self,
+        line: &str,
+        raw_len: usize,
+    ) -> (Vec<Error>, Option<ParsedLine>) {
+        self.start_line(raw_len);
+        let line = if self.line_no == 1 {
+            self.parser.strip_bom(line)
         } else {
-            Err(PyValueError::new_err("Delimiter needs to be of length 1"))
-        }?;
-
-        let quote_char = if let Some(quote_str) = &self.schema.quote_str {
-            if quote_str.len() == 1 {
-                Ok(Some(quote_str.chars().next().unwrap()))
-            } else {
-                Err(PyValueError::new_err("Quote needs to be of length 1"))
-            }?
+            line
+        };
+        let mut errors = vec![];
+        let version = self.version.as_deref();
+        match self.parser.parse_checked(line, version, Some(&mut errors)) {
+            Ok(mut parsed) => {
+                self.locate_warnings(&mut parsed);
+                let mut errors: Vec<Error> = errors
+                    .into_iter()
+                    .map(|e| self.error(e, "parse", line))
+                    .collect();
+                if let Err(e) = self.check_trailers(parsed.line_index, parsed.fields()) {
+                    errors.push(self.error(e, "reconcile", line))
+                }
+                if let Err(e) = self.check_sequence(parsed.line_index, parsed.fields()) {
+                    errors.push(self.error(e, "parse", line))
+                }
+                if let Err(e) = self.check_unique(parsed.line_index, parsed.fields()) {
+                    errors.push(self.error(e, "parse", line))
+                }
+                if let Err(e) = self.set_version(parsed.line_index, parsed.fields()) {
+                    errors.push(self.error(e, "parse", line))
+                }
+                (errors, Some(parsed))
+            }
+            Err(e) => (vec![self.error(e, "parse", line)], None),
+        }
+    }
+    pub fn validate_bytes(&mut self, line: &[u8], raw_len: usize) -> Vec<Error> {
+        self.validate_bytes_parsed(line, raw_len).0
+    }
+    pub fn validate_bytes_parsed(
+        &mut self,
+        line: &[u8],
+        raw_len: usize,
+    ) -> (Vec<Error>, Option<ParsedLine>) {
+        match self.parser.decode(line) {
+            Ok(decoded) => self.validate_parsed(&decoded, raw_len),
+            Err(e) => {
+                self.start_line(raw_len);
+                let line = String::from_utf8_lossy(line);
+                (vec![self.error(e, "decode", &line)], None)
+            }
+        }
+    }
+    pub fn parse(&mut self, line: &str, raw_len: usize) -> Result<ParsedLine, Error> {
+        self.start_line(raw_len);
+        let line = if self.line_no == 1 {
+            self.parser.strip_bom(line)
         } else {
-            None
+            line
         };
-
-        let mut line_stripped = line.trim_end_matches('\n');
-        if self.schema.trailing_delimiter {
-            line_stripped = if line_stripped.ends_with(delimiter) {
-                Ok(&line_stripped[..line_stripped.len() - 1])
-            } else {
-                Err(PyValueError::new_err(
-                    "Line doesn't have trailing delimiter",
-                ))
-            }?;
+        let mut parsed = self
+            .parser
+            .parse_checked(line, self.version.as_deref(), None)
+            .map_err(|e| self.error(e, "parse", line))?;
+        self.check_trailers(parsed.line_index, parsed.fields())
+            .map_err(|e| self.error(e, "reconcile", line))?;
+        self.check_sequence(parsed.line_index, parsed.fields())
+            .map_err(|e| self.error(e, "parse", line))?;
+        self.check_unique(parsed.line_index, parsed.fields())
+            .map_err(|e| self.error(e, "parse", line))?;
+        self.set_version(parsed.line_index, parsed.fields())
+            .map_err(|e| self.error(e, "parse", line))?;
+        self.locate_warnings(&mut parsed);
+        Ok(parsed)
+    }
+    // Adds where we are to the warnings, as error does for errors
+    fn locate_warnings(&self, parsed: &mut ParsedLine) {
+        for warning in parsed.warnings.iter_mut() {
+            *warning = format!("Line {}, byte {}: {}", self.line_no, self.offset, warning);
+        }
+    }
+    fn check_sequence(&mut self, line_i: usize, fields: &[Value]) -> Result<(), Error> {
+        let (Some(sequence), Some(field_i)) = (
+            self.parser.plan.sequence,
+            self.parser.plan.sequence_fields.get(&line_i),
+        ) else {
+            return Ok(());
         };
-        let parts = split_line(line_stripped, delimiter, quote_char);
-
-        let first = parts
-            .get(0)
-            .ok_or(PyValueError::new_err("Split line has length < 1"))?;
-
-        let schema_line = self
-            .schema
-            .lines
-            .iter()
-            .find(|schema_line| schema_line.name == first.value)
-            .ok_or_else(|| {
-                PyValueError::new_err(format!("No schema line matching '{}'", first.value))
-            })?;
-
-        if schema_line.fields.len() != parts.len() - 1 {
-            return Err(PyValueError::new_err(format!(
-                "Mismatched line length, schema length: {}, actual length: (header=1) + {}",
-                schema_line.fields.len(),
-                parts.len() - 1
-            )));
-        }
-
-        let mut py_items: Vec<PyObject> = vec![first.value.clone().into_py(_py)];
-        for (schema_field, part) in schema_line.fields.iter().zip(parts.iter().skip(1)) {
-            py_items.push(part_to_py(
-                _py,
-                self.schema.coerce_empty_quoted,
-                quote_char,
-                schema_field,
-                part,
-            )?)
-        }
-        Ok(PyTuple::new(_py, &py_items).into_py(_py))
-    }
-    fn parse_first<'a>(&self, _py: Python<'a>, line: &str) -> PyResult<PyObject> {
-        let quote_char = if let Some(quote_str) = &self.schema.quote_str {
-            if quote_str.len() == 1 {
-                Ok(Some(quote_str.chars().next().unwrap()))
+        let Value::Int(actual) = fields[*field_i] else {
+            return Ok(());
+        };
+        let expected = self.next_sequence;
+        if actual != expected {
+            let problem = if actual == expected - sequence.step as i128 {
+                "Duplicate sequence number"
             } else {
-                Err(PyValueError::new_err("Quote needs to be of length 1"))
-            }?
-        } else {
-            None
+                "Sequence gap"
+            };
+            return Err(format!(
+                "{} at line {}, expected: {}, actual: {}",
+                problem, self.line_no, expected, actual
+            )
+            .into());
+        }
+        self.next_sequence = actual + sequence.step as i128;
+        Ok(())
+    }
+    fn set_version(&mut self, line_i: usize, fields: &[Value]) -> Result<(), Error> {
+        let Some((version_line, field_i)) = self.parser.plan.version_field else {
+            return Ok(());
         };
-        if quote_char.is_some() && line.starts_with(quote_char.unwrap()) {
-            let mut out = String::new();
-            for ch in line.chars().skip(1) {
-                if ch == quote_char.unwrap() {
-                    break;
+        if line_i != version_line {
+            return Ok(());
+        }
+        let version = fields[field_i].to_string();
+        let lines = &self.parser.schema.lines;
+        if !lines.iter().any(|line| line.versions.contains(&version)) {
+            return Err(format!("Version '{}' isn't in the schema", version).into());
+        }
+        self.version = Some(version);
+        Ok(())
+    }
+    fn check_unique(&mut self, line_i: usize, fields: &[Value]) -> Result<(), Error> {
+        let unique_key = &self.parser.schema.lines[line_i].unique_key;
+        if unique_key.is_empty() {
+            return Ok(());
+        }
+        let key = unique_key
+            .iter()
+            .map(|i| KeyValue::from(&fields[*i]))
+            .collect();
+        match self.keys[line_i].entry(key) {
+            Entry::Occupied(first) => {
+                let key: Vec<String> = unique_key.iter().map(|i| fields[*i].to_string()).collect();
+                Err(format!(
+                    "Duplicate key ({}) at line {}, first seen at line {}",
+                    key.join(", "),
+                    self.line_no,
+                    first.get()
+                )
+                .into())
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(self.line_no);
+                Ok(())
+            }
+        }
+    }
+    fn check_trailers(&mut self, line_i: usize, fields: &[Value]) -> Result<(), Error> {
+        let field = |i: usize| &fields[i];
+        let schema_lines = &self.parser.schema.lines;
+        for (check_i, check) in self.parser.plan.trailer_checks.iter().enumerate() {
+            let sums = &mut self.sums[check_i];
+            if check.line == line_i {
+                if let Some(count_field) = check.count_field {
+                    let Value::Int(expected) = *field(count_field) else {
+                        return Err(
+                            format!("Line count isn't an int: {:?}", field(count_field)).into()
+                        );
+                    };
+                    let actual = self.counts[check_i];
+                    if expected != actual as i128 {
+                        return Err(Error::reconciliation(format!(
+                            "Line count, expected: {}, actual: {}",
+                            expected, actual
+                        )));
+                    }
                 }
-                out.push(ch)
+                for ((trailer_field, sum_line, sum_field), actual) in check.sums.iter().zip(&*sums)
+                {
+                    let Some(expected) = field(*trailer_field).to_decimal() else {
+                        return Err(
+                            format!("Total isn't a number: {:?}", field(*trailer_field)).into()
+                        );
+                    };
+                    if expected != *actual {
+                        return Err(Error::reconciliation(format!(
+                            "Total of {:?} field {}, expected: {}, actual: {}",
+                            schema_lines[*sum_line].names, sum_field, expected, actual
+                        )));
+                    }
+                }
+                self.counts[check_i] = 0;
+                sums.iter_mut().for_each(|sum| *sum = Decimal::ZERO);
+                continue;
             }
-            return Ok(out.into_py(_py));
-        };
-
-        let delimiter = if self.schema.delimiter.len() == 1 {
-            Ok(self.schema.delimiter.chars().next().unwrap())
-        } else {
-            Err(PyValueError::new_err("Delimiter needs to be of length 1"))
-        }?;
-        let mut out = String::new();
-        for ch in line.chars() {
-            if ch == delimiter {
-                break;
+            let counted = match &check.count_lines {
+                Some(count_lines) => count_lines.contains(&line_i),
+                None => true,
+            };
+            if counted {
+                self.counts[check_i] += 1;
+            }
+            for ((_, sum_line, sum_field), sum) in check.sums.iter().zip(sums.iter_mut()) {
+                let value = field(*sum_field);
+                if *sum_line == line_i && !value.is_null() {
+                    let Some(value) = value.to_decimal() else {
+                        return Err(format!("Can't add up {:?}", value).into());
+                    };
+                    *sum = sum.checked_add(value).ok_or_else(|| {
+                        Error::reconciliation(format!(
+                            "Total of {:?} field {} is too large",
+                            schema_lines[*sum_line].names, sum_field
+                        ))
+                    })?;
+                }
             }
-            out.push(ch)
         }
-        return Ok(out.into_py(_py));
+        Ok(())
+    }
+}
+
+// Consecutive parts of the widths in chars, any rest of the line is a last
+// part. A short line has fewer parts, the last of them maybe short
+fn cut_line<'a>(line: &'a str, widths: &[usize]) -> Vec<Part<'a>> {
+    let part = |start: usize, end: usize| Part {
+        value: Cow::Borrowed(&line[start..end]),
+        is_quoted: false,
+        start,
+        end,
+    };
+    let mut parts = vec![];
+    let mut start = 0;
+    for width in widths {
+        if start == line.len() {
+            break;
+        }
+        let end = line[start..]
+            .char_indices()
+            .nth(*width)
+            .map_or(line.len(), |(i, _)| start + i);
+        parts.push(part(start, end));
+        start = end;
+    }
+    if start < line.len() {
+        parts.push(part(start, line.len()));
+    }
+    parts
+}
+
+pub struct Part<'a> {
+    // Borrowed from the line unless quotes had to be dropped from the middle
+    pub value: Cow<'a, str>,
+    pub is_quoted: bool,
+    // Byte range of the part in the line, including any quotes
+    pub start: usize,
+    pub end: usize,
+}
+impl Part<'_> {
+    pub fn as_str(&self) -> &str {
+        &self.value
     }
 }
 
-struct Part {
-    value: String,
-    is_quoted: bool,
+// The value of a part as it's split, a slice of the line while the chars are contiguous
+struct PartValue<'a> {
+    line: &'a str,
+    start: usize,
+    end: usize,
+    owned: Option<String>,
 }
-impl Part {
-    fn as_str(&self) -> &str {
-        self.value.as_str()
+impl<'a> PartValue<'a> {
+    fn push(&mut self, i: usize, ch: char) {
+        match &mut self.owned {
+            Some(owned) => owned.push(ch),
+            None if self.start == self.end => {
+                self.start = i;
+                self.end = i + ch.len_utf8();
+            }
+            None if self.end == i => self.end += ch.len_utf8(),
+            None => {
+                let mut owned = self.line[self.start..self.end].to_string();
+                owned.push(ch);
+                self.owned = Some(owned);
+            }
+        }
+    }
+    fn take(&mut self) -> Cow<'a, str> {
+        let value = match self.owned.take() {
+            Some(owned) => Cow::Owned(owned),
+            None => Cow::Borrowed(&self.line[self.start..self.end]),
+        };
+        self.end = self.start;
+        value
     }
 }
 
-fn split_line(line: &str, delimiter: char, quote_char: Option<char>) -> Vec<Part> {
+// With a release_char, escaped chars are kept escaped in the parts, see unescape
+pub fn split_line(
+    line: &str,
+    delimiter: char,
+    quote_char: Option<char>,
+    quote_mode: QuoteMode,
+    release_char: Option<char>,
+) -> Vec<Part<'_>> {
+    split_line_n(
+        line,
+        delimiter,
+        quote_char,
+        quote_mode,
+        release_char,
+        usize::MAX,
+    )
+}
+
+// As split_line, stopping after max_parts
+fn split_line_n(
+    line: &str,
+    delimiter: char,
+    quote_char: Option<char>,
+    quote_mode: QuoteMode,
+    release_char: Option<char>,
+    max_parts: usize,
+) -> Vec<Part<'_>> {
+    if max_parts == 0 {
+        return vec![];
+    }
+    let lenient = matches!(quote_mode, QuoteMode::Lenient);
     let mut parts_mut: Vec<Part> = vec![];
-    let mut value = String::new();
+    let mut value = PartValue {
+        line,
+        start: 0,
+        end: 0,
+        owned: None,
+    };
     let mut in_quoted = false;
     let mut is_quoted = false;
-    for ch in line.chars() {
-        if quote_char.is_some() && ch == quote_char.unwrap() {
+    let mut start = 0;
+    let mut chars = line.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        if release_char == Some(ch) {
+            value.push(i, ch);
+            if let Some((j, escaped)) = chars.next() {
+                value.push(j, escaped);
+            }
+        } else if quote_char == Some(ch) && lenient {
             in_quoted = !in_quoted;
             is_quoted = true;
+        } else if quote_char == Some(ch) && i == start {
+            in_quoted = true;
+            is_quoted = true;
+        } else if quote_char == Some(ch) && in_quoted {
+            if chars.as_str().starts_with(ch) {
+                let (j, _) = chars.next().unwrap();
+                value.push(j, ch);
+            } else {
+                in_quoted = false;
+            }
         } else if ch == delimiter && !in_quoted {
             parts_mut.push(Part {
-                value: value.clone(),
-                is_quoted: is_quoted,
+                value: value.take(),
+                is_quoted,
+                start,
+                end: i,
             });
-            value.clear();
+            if parts_mut.len() == max_parts {
+                return parts_mut;
+            }
             is_quoted = false;
+            start = i + ch.len_utf8();
         } else {
-            value.push(ch);
+            value.push(i, ch);
         };
     }
     parts_mut.push(Part {
-        value: value.clone(),
-        is_quoted: is_quoted,
+        value: value.take(),
+        is_quoted,
+        start,
+        end: line.len(),
     });
     parts_mut
 }
 
+// Drop the release_char from each escaped char
+fn unescape(value: &str, release_char: Option<char>) -> Cow<'_, str> {
+    match release_char {
+        Some(r) if value.contains(r) => {
+            let mut out = String::with_capacity(value.len());
+            let mut chars = value.chars();
+            while let Some(ch) = chars.next() {
+                if ch == r {
+                    out.extend(chars.next());
+                } else {
+                    out.push(ch);
+                }
+            }
+            Cow::Owned(out)
+        }
+        _ => Cow::Borrowed(value),
+    }
+}
+
+// Split on the component_delimiter, leaving the components escaped
+fn split_components(value: &str, delimiter: char, release_char: Option<char>) -> Vec<&str> {
+    let mut components = vec![];
+    let mut start = 0;
+    let mut chars = value.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        if release_char == Some(ch) {
+            chars.next();
+        } else if ch == delimiter {
+            components.push(&value[start..i]);
+            start = i + ch.len_utf8();
+        }
+    }
+    components.push(&value[start..]);
+    components
+}
+
+fn padding(field: &Field) -> Option<(char, &PadSide)> {
+    let (pad_char, pad_side) = match field {
+        Field::Str(StrField {
+            pad_char, pad_side, ..
+        })
+        | Field::Int(IntField {
+            pad_char, pad_side, ..
+        })
+        | Field::Float(FloatField {
+            pad_char, pad_side, ..
+        })
+        | Field::Decimal(DecimalField {
+            pad_char, pad_side, ..
+        }) => (pad_char, pad_side),
+        _ => return None,
+    };
+    pad_char.map(|pad_char| (pad_char, pad_side))
+}
+
 fn required(field: &Field) -> bool {
     match field {
         Field::Str(StrField { required, .. })
@@ -305,185 +3306,587 @@ fn required(field: &Field) -> bool {
         | Field::Bool(BoolField { required, .. })
         | Field::Datetime(DatetimeField { required, .. })
         | Field::Date(DateField { required, .. })
-        | Field::Time(TimeField { required, .. }) => *required,
+        | Field::Time(TimeField { required, .. })
+        | Field::Composite(CompositeField { required, .. })
+        | Field::Currency(CurrencyField { required, .. })
+        | Field::Percent(PercentField { required, .. }) => *required,
+    }
+}
+
+// Swaps an overpunched last char for the digit and a sign, eg: "12J" -> "-121"
+fn unzone(value: Cow<str>) -> Cow<str> {
+    let Some(last) = value.chars().last() else {
+        return value;
+    };
+    let (digit, negative) = match last {
+        '{' => (0, false),
+        'A'..='I' => (last as u32 - 'A' as u32 + 1, false),
+        '}' => (0, true),
+        'J'..='R' => (last as u32 - 'J' as u32 + 1, true),
+        _ => return value,
+    };
+    let digits = &value[..value.len() - 1];
+    let sign = if negative { "-" } else { "" };
+    Cow::Owned(format!("{}{}{}", sign, digits, digit))
+}
+
+// Puts the point before the last places digits, eg: "-12345" -> "-123.45"
+fn imply_point(value: &str, places: u32) -> Option<String> {
+    let digits = value
+        .strip_prefix('-')
+        .or_else(|| value.strip_prefix('+'))
+        .unwrap_or(value);
+    let sign = &value[..value.len() - digits.len()];
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
     }
+    let places = places as usize;
+    let digits = format!("{:0>width$}", digits, width = places + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - places);
+    Some(match places {
+        0 => format!("{}{}", sign, whole),
+        _ => format!("{}{}.{}", sign, whole, fraction),
+    })
 }
 
-fn part_to_py<'a>(
-    _py: Python<'a>,
+fn severity(field: &Field) -> Severity {
+    match field {
+        Field::Str(StrField { severity, .. })
+        | Field::StrEnum(StrEnumField { severity, .. })
+        | Field::Int(IntField { severity, .. })
+        | Field::IntEnum(IntEnumField { severity, .. })
+        | Field::Float(FloatField { severity, .. })
+        | Field::Decimal(DecimalField { severity, .. })
+        | Field::Currency(CurrencyField { severity, .. })
+        | Field::Percent(PercentField { severity, .. }) => *severity,
+        _ => Severity::Error,
+    }
+}
+
+// How values are written, from the Schema
+struct Syntax {
     coerce_empty_quoted: bool,
-    quote_char: Option<char>,
+    component_delimiter: Option<char>,
+    release_char: Option<char>,
+}
+
+// Failed constraints of fields with severity warn are added to warnings
+fn part_to_value(
+    syntax: &Syntax,
     schema_field: &Field,
     part: &Part,
-) -> PyResult<PyObject> {
+    warnings: &mut Vec<String>,
+) -> Result<Value, String> {
+    // Composites are unescaped per component
+    let value = match schema_field {
+        Field::Composite(_) => Cow::Borrowed(part.as_str()),
+        _ => unescape(part.as_str(), syntax.release_char),
+    };
+    let value = match (padding(schema_field), value) {
+        (Some((pad_char, pad_side)), Cow::Borrowed(value)) => {
+            Cow::Borrowed(pad_side.strip(value, pad_char))
+        }
+        (Some((pad_char, pad_side)), Cow::Owned(value)) => {
+            Cow::Owned(pad_side.strip(&value, pad_char).to_string())
+        }
+        (None, value) => value,
+    };
+    // COBOL style numbers are rewritten before they're parsed
+    let value = match schema_field {
+        Field::Int(IntField { zoned: true, .. }) => unzone(value),
+        Field::Decimal(DecimalField {
+            zoned,
+            implied_decimal_places,
+            ..
+        }) => {
+            let value = if *zoned { unzone(value) } else { value };
+            match implied_decimal_places {
+                Some(places) if !value.is_empty() => match imply_point(&value, *places) {
+                    Some(value) => Cow::Owned(value),
+                    None => {
+                        return Err(format!(
+                            "Implied decimal isn't just digits - '{}' given schema: {:?}",
+                            value, schema_field
+                        ))
+                    }
+                },
+                _ => value,
+            }
+        }
+        _ => value,
+    };
+    let part = &Part {
+        value,
+        is_quoted: part.is_quoted,
+        start: part.start,
+        end: part.end,
+    };
     let err = |extra: &str| {
-        Err(PyValueError::new_err(format!(
+        Err(format!(
             "{} - '{}' given schema: {:?}",
             extra, part.value, schema_field,
-        )))
+        ))
     };
-    // Return None for empty values
-    let none: Option<&str> = None;
-    let coerce = coerce_empty_quoted && schema_field.is_str() && part.is_quoted;
-    if part.value == "" && !required(schema_field) && !coerce {
-        return Ok(none.into_py(_py));
-    }
-    // Later, we allow 'A' to pass as the enum or bool '"A"'
-    let mut part_with_quotes = part.value.clone();
-    quote_char.map(|q| {
-        part_with_quotes.clear();
-        part_with_quotes.push(q);
-        part_with_quotes.push_str(part.as_str());
-        part_with_quotes.push(q);
-    });
+    let warn = severity(schema_field) == Severity::Warn;
+    let mut violated = |extra: &str| {
+        let message = format!(
+            "{} - '{}' given schema: {:?}",
+            extra, part.value, schema_field
+        );
+        if warn {
+            warnings.push(message);
+            Ok(())
+        } else {
+            Err(message)
+        }
+    };
+    // Null for empty values
+    let coerce = part.is_quoted
+        && match schema_field {
+            Field::Str(str_field) => str_field
+                .coerce_empty_quoted
+                .unwrap_or(syntax.coerce_empty_quoted),
+            _ => false,
+        };
+    if part.value.is_empty() && !required(schema_field) && !coerce {
+        return Ok(Value::Null);
+    }
     match schema_field {
         Field::Str(StrField {
             min_length,
             max_length,
             invalid_characters,
+            allowed_characters,
+            length_unit,
+            transform,
             ..
         }) => {
-            if min_length.is_some() && part.value.len() < min_length.unwrap() {
-                return err("String is too short");
+            let length = length_unit.len(&part.value);
+            if min_length.is_some() && length < min_length.unwrap() {
+                violated("String is too short")?;
             }
-            if max_length.is_some() && part.value.len() > max_length.unwrap() {
-                return err("String is too long");
+            if max_length.is_some() && length > max_length.unwrap() {
+                violated("String is too long")?;
             }
             if let Some(invalid_characters_) = invalid_characters {
                 if part.value.chars().any(|c| invalid_characters_.contains(c)) {
-                    return err("String contains invalid characters");
+                    violated("String contains invalid characters")?;
                 }
             }
-            Ok(part.value.clone().into_py(_py))
-        }
-        Field::StrEnum(StrEnumField { values, .. }) => {
-            if values.contains(&part.value) {
-                Ok(part.value.clone().into_py(_py))
-            } else if values.contains(&part_with_quotes) {
-                Ok(part_with_quotes.clone().into_py(_py))
-            } else {
-                err("Value not in enum")
+            if let Some(allowed_characters_) = allowed_characters {
+                if !part.value.chars().all(|c| allowed_characters_.contains(c)) {
+                    violated("String contains characters that aren't allowed")?;
+                }
+            }
+            match transform {
+                Some(transform) => Ok(Value::Str(transform.apply(&part.value))),
+                None => Ok(Value::Str(part.value.to_string())),
             }
         }
+        Field::StrEnum(enum_field) => match enum_field.find(&part.value) {
+            Some(v) => Ok(Value::Str(v.clone())),
+            None => {
+                violated("Value not in enum")?;
+                Ok(Value::Str(part.value.to_string()))
+            }
+        },
         Field::Int(IntField {
             min_value,
             max_value,
+            exclusive_min,
+            exclusive_max,
+            not_equal,
             ..
         }) => part.value.parse::<i128>().map_or_else(
             |_| err("Does not parse as int"),
             |i| {
-                if min_value.is_some() && i < (min_value.unwrap() as i128) {
-                    return err("Int is too small");
+                if min_value.is_some() && i < min_value.unwrap() {
+                    violated("Int is too small")?;
+                }
+                if max_value.is_some() && i > max_value.unwrap() {
+                    violated("Int is too large")?;
                 }
-                if max_value.is_some() && i > (max_value.unwrap() as i128) {
-                    return err("Int is too large");
+                if exclusive_min.is_some() && i <= exclusive_min.unwrap() {
+                    violated("Int is too small")?;
                 }
-                Ok(i.into_py(_py))
+                if exclusive_max.is_some() && i >= exclusive_max.unwrap() {
+                    violated("Int is too large")?;
+                }
+                if not_equal.is_some() && i == not_equal.unwrap() {
+                    violated("Int is a disallowed value")?;
+                }
+                Ok(Value::Int(i))
             },
         ),
-        Field::IntEnum(IntEnumField { values, .. }) => part.value.parse::<i64>().map_or_else(
+        Field::IntEnum(IntEnumField { lookup, .. }) => part.value.parse::<i64>().map_or_else(
             |_| err("Does not parse as int"),
-            |i| {
-                if values.contains(&i) {
-                    Ok(i.into_py(_py))
-                } else {
-                    err("Value not in enum")
+            |i| match lookup.0.get(&i) {
+                Some(Some(label)) => Ok(Value::Str(label.clone())),
+                Some(None) => Ok(Value::Int(i as i128)),
+                None => {
+                    violated("Value not in enum")?;
+                    Ok(Value::Int(i as i128))
                 }
             },
         ),
         Field::Float(FloatField {
             min_value,
             max_value,
+            exclusive_min,
+            exclusive_max,
+            not_equal,
+            allow_nan,
+            allow_inf,
+            allow_exponent,
             ..
         }) => part.value.parse::<f64>().map_or_else(
             |_| err("Does not parse as float"),
             |i| {
+                if !allow_nan && i.is_nan() {
+                    violated("Float is NaN")?;
+                }
+                if !allow_inf && i.is_infinite() {
+                    violated("Float is infinite")?;
+                }
+                if !allow_exponent && i.is_finite() && part.value.contains(['e', 'E']) {
+                    violated("Float has an exponent")?;
+                }
                 if min_value.is_some() && i < min_value.unwrap() {
-                    return err("Float is too small");
+                    violated("Float is too small")?;
                 }
                 if max_value.is_some() && i > max_value.unwrap() {
-                    return err("Float is too large");
+                    violated("Float is too large")?;
+                }
+                if exclusive_min.is_some() && i <= exclusive_min.unwrap() {
+                    violated("Float is too small")?;
                 }
-                Ok(i.into_py(_py))
+                if exclusive_max.is_some() && i >= exclusive_max.unwrap() {
+                    violated("Float is too large")?;
+                }
+                if not_equal.is_some() && i == not_equal.unwrap() {
+                    violated("Float is a disallowed value")?;
+                }
+                Ok(Value::Float(i))
             },
         ),
         Field::Decimal(DecimalField {
             round_decimal_places,
+            rounding,
             min_value,
             max_value,
+            exclusive_min,
+            exclusive_max,
+            not_equal,
+            max_digits,
+            max_decimal_places,
             ..
         }) => Decimal::from_str_exact(part.as_str()).map_or_else(
             |_| err("Does not parse as decimal"),
             |i| {
                 if min_value.is_some() && i < min_value.unwrap() {
-                    return err("Decimal is too small");
+                    violated("Decimal is too small")?;
                 }
                 if max_value.is_some() && i > max_value.unwrap() {
-                    return err("Decimal is too large");
+                    violated("Decimal is too large")?;
+                }
+                if exclusive_min.is_some() && i <= exclusive_min.unwrap() {
+                    violated("Decimal is too small")?;
+                }
+                if exclusive_max.is_some() && i >= exclusive_max.unwrap() {
+                    violated("Decimal is too large")?;
                 }
-                if round_decimal_places.is_some() {
-                    return Ok(i.round_dp(round_decimal_places.unwrap()).into_py(_py));
+                if not_equal.is_some() && i == not_equal.unwrap() {
+                    violated("Decimal is a disallowed value")?;
                 }
-                Ok(i.into_py(_py))
+                let i = match round_decimal_places {
+                    Some(dp) => i.round_dp_with_strategy(*dp, rounding.strategy()),
+                    None => i,
+                };
+                if max_decimal_places.is_some_and(|dp| i.scale() > dp) {
+                    violated("Decimal has too many decimal places")?;
+                }
+                if let Some(max_digits) = max_digits {
+                    let digits =
+                        (i.mantissa().unsigned_abs().to_string().len() as u32).max(i.scale());
+                    // As SQL's NUMERIC(max_digits, max_decimal_places)
+                    let max_whole = max_digits.saturating_sub(max_decimal_places.unwrap_or(0));
+                    if digits > *max_digits || digits - i.scale() > max_whole {
+                        violated("Decimal has too many digits")?;
+                    }
+                }
+                Ok(Value::Decimal(i))
             },
         ),
-        Field::Bool(BoolField {
-            true_value,
-            false_value,
+        Field::Bool(bool_field) => match bool_field.find(&part.value) {
+            Some(None) if bool_field.required => err("Null value for required bool"),
+            Some(None) => Ok(Value::Null),
+            Some(Some(b)) => Ok(Value::Bool(b)),
+            None => err("Value is neither true or false value"),
+        },
+        Field::Datetime(DatetimeField {
+            format,
+            offset_in_value,
+            ambiguous,
+            nonexistent,
+            allow_2400,
+            output_naive,
+            tz,
+            output_tz,
             ..
         }) => {
-            if &part.value == true_value || &part_with_quotes == true_value {
-                Ok(true.into_py(_py))
-            } else if false_value.as_ref().map_or(false, |false_value_| {
-                &part.value == false_value_ || &part_with_quotes == false_value_
-            }) {
-                Ok(false.into_py(_py))
-            } else {
-                err("Value is neither true or false value")
+            let tz = *tz;
+            let output = |dt: DateTime<FixedOffset>| {
+                let dt = match output_tz {
+                    Some(output_tz) => dt.with_timezone(output_tz).fixed_offset(),
+                    None => dt,
+                };
+                if *output_naive {
+                    Value::NaiveDatetime(dt.naive_local())
+                } else {
+                    Value::Datetime(dt)
+                }
+            };
+            for f in format.iter() {
+                if let Some(units_per_second) = f.epoch_units_per_second() {
+                    if let Some(utc) = parse_epoch(part.as_str(), units_per_second) {
+                        return Ok(output(match tz {
+                            Some(tz) => utc.with_timezone(&tz).fixed_offset(),
+                            None => utc.fixed_offset(),
+                        }));
+                    }
+                } else if *offset_in_value || f.has_offset() {
+                    // The value carries its own offset, time_zone isn't needed
+                    let dt = f
+                        .parse(part.as_str())
+                        .and_then(|p| p.to_datetime().ok())
+                        .or_else(|| {
+                            let p = f.parse_end_of_day(part.as_str()).filter(|_| *allow_2400)?;
+                            Some(p.to_datetime().ok()? + Duration::days(1))
+                        });
+                    if let Some(dt) = dt {
+                        return Ok(output(dt));
+                    }
+                } else if let Some(naive) = f
+                    .parse(part.as_str())
+                    .and_then(|p| p.to_naive_datetime_with_offset(0).ok())
+                    .or_else(|| {
+                        let p = f.parse_end_of_day(part.as_str()).filter(|_| *allow_2400)?;
+                        Some(p.to_naive_datetime_with_offset(0).ok()? + Duration::days(1))
+                    })
+                {
+                    let tz = match tz {
+                        Some(tz) => tz,
+                        None => return err("Missing time_zone for format without offset"),
+                    };
+                    // Localize the whole NaiveDateTime so we keep sub-second precision
+                    return match localize(tz, &naive, ambiguous, nonexistent) {
+                        Ok(dt) => Ok(output(dt.fixed_offset())),
+                        Err(e) => err(e),
+                    };
+                }
             }
+            err(&format!(
+                "Does not parse as datetime, tried formats: {:?}",
+                format
+            ))
         }
-        Field::Datetime(DatetimeField {
-            format, time_zone, ..
-        }) => {
-            let tz: Result<Tz, _> = time_zone.parse();
-            if tz.is_err() {
-                return err("Invalid timezone");
-            }
-            NaiveDateTime::parse_from_str(part.as_str(), format).map_or_else(
-                |_| err("Does not parse as datetime"),
-                |i| {
-                    let dt = tz.unwrap().with_ymd_and_hms(
-                        i.year(),
-                        i.month(),
-                        i.day(),
-                        i.hour(),
-                        i.minute(),
-                        i.second(),
-                    );
-                    match dt {
-                        LocalResult::Single(dt) => Ok(dt.into_py(_py)),
-                        _ => err("Does not parse as datetime"),
-                    }
+        Field::Date(DateField { format, .. }) => format
+            .parse_first(part.as_str(), |p| p.to_naive_date())
+            .map_or_else(
+                || {
+                    err(&format!(
+                        "Does not parse as date, tried formats: {:?}",
+                        format
+                    ))
                 },
-            )
+                |i| Ok(Value::Date(i)),
+            ),
+        Field::Time(TimeField {
+            format, allow_2400, ..
+        }) => format
+            .parse_first(part.as_str(), |p| p.to_naive_time())
+            .or_else(|| {
+                format
+                    .iter()
+                    .filter(|_| *allow_2400)
+                    .find_map(|f| f.parse_end_of_day(part.as_str())?.to_naive_time().ok())
+            })
+            .map_or_else(
+                || {
+                    err(&format!(
+                        "Does not parse as time, tried formats: {:?}",
+                        format
+                    ))
+                },
+                |i| Ok(Value::Time(i)),
+            ),
+        Field::Currency(currency_field) => {
+            let (amount, code) = match currency_field.parse(&part.value) {
+                Ok(parsed) => parsed,
+                Err(e) => return err(e),
+            };
+            if currency_field.min_value.is_some() && amount < currency_field.min_value.unwrap() {
+                violated("Currency is too small")?;
+            }
+            if currency_field.max_value.is_some() && amount > currency_field.max_value.unwrap() {
+                violated("Currency is too large")?;
+            }
+            if currency_field.include_code {
+                let code = code.map_or(Value::Null, Value::Str);
+                return Ok(Value::Composite(vec![Value::Decimal(amount), code]));
+            }
+            Ok(Value::Decimal(amount))
         }
-        Field::Date(DateField { format, .. }) => NaiveDate::parse_from_str(part.as_str(), format)
-            .map_or_else(|_| err("Does not parse as date"), |i| Ok(i.into_py(_py))),
-        Field::Time(TimeField { format, .. }) => {
-            let part_24_to_00 = if part.value == "240000" {
-                "000000"
-            } else {
-                part.as_str()
-            }; // I kno rite
-            NaiveTime::parse_from_str(part_24_to_00, format)
-                .map_or_else(|_| err("Does not parse as time"), |i| Ok(i.into_py(_py)))
+        Field::Percent(PercentField {
+            assume_percent,
+            as_fraction,
+            as_float,
+            min_value,
+            max_value,
+            ..
+        }) => {
+            let value = part.value.trim();
+            let number = match value.strip_suffix('%') {
+                Some(number) => number.trim_end(),
+                None if *assume_percent => value,
+                None => return err("Percent is missing a %"),
+            };
+            let Ok(mut percent) = Decimal::from_str_exact(number) else {
+                return err("Does not parse as percent");
+            };
+            if *as_fraction {
+                percent /= Decimal::ONE_HUNDRED;
+            }
+            if min_value.is_some() && percent < min_value.unwrap() {
+                violated("Percent is too small")?;
+            }
+            if max_value.is_some() && percent > max_value.unwrap() {
+                violated("Percent is too large")?;
+            }
+            match (as_float, percent.to_f64()) {
+                (true, Some(f)) => Ok(Value::Float(f)),
+                (true, None) => err("Percent is out of range of float"),
+                (false, _) => Ok(Value::Decimal(percent)),
+            }
+        }
+        Field::Composite(composite_field) => {
+            // The schema is checked for a component_delimiter up front
+            let delimiter = syntax.component_delimiter.unwrap_or_default();
+            let components = split_components(&part.value, delimiter, syntax.release_char);
+            if components.len() > composite_field.fields.len() {
+                return err("Too many components");
+            }
+            let values = composite_field
+                .fields
+                .iter()
+                .enumerate()
+                .map(|(i, field)| {
+                    let value = components.get(i).copied().unwrap_or_default();
+                    let component = Part {
+                        value: Cow::Borrowed(value),
+                        is_quoted: false,
+                        start: 0,
+                        end: value.len(),
+                    };
+                    part_to_value(syntax, field, &component, warnings)
+                })
+                .collect::<Result<_, _>>()?;
+            Ok(Value::Composite(values))
         }
     }
 }
 
-#[pymodule]
-#[pyo3(name = "xlineparse")]
-fn init_mod(_py: Python, m: &PyModule) -> PyResult<()> {
-    m.add_class::<Parser>()?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = r#"{
+        "delimiter": "|",
+        "quote_str": null,
+        "trailing_delimiter": false,
+        "coerce_empty_quoted": false,
+        "lines": [
+            {"name": "a", "fields": [{"kind": "INT", "required": true}]},
+            {
+                "name": "b",
+                "fields": [
+                    {"kind": "STR", "required": false},
+                    {"kind": "DECIMAL", "required": true}
+                ]
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn from_json() {
+        let parser = Parser::from_json(SCHEMA).unwrap();
+        assert_eq!(parser.schema().lines.len(), 2);
+        let error = Parser::from_json(r#"{"delimiter": "|"}"#).err().unwrap();
+        assert!(error.message.contains("missing field"), "{}", error);
+    }
+
+    #[test]
+    fn parse_line() {
+        let parser = Parser::from_json(SCHEMA).unwrap();
+        assert_eq!(
+            parser.parse_line("a|1").unwrap(),
+            vec![Value::Str("a".to_string()), Value::Int(1)]
+        );
+        assert_eq!(
+            parser.parse_line("b||1.50").unwrap(),
+            vec![
+                Value::Str("b".to_string()),
+                Value::Null,
+                Value::Decimal(Decimal::new(150, 2))
+            ]
+        );
+        let error = parser.parse_line("a|x").unwrap_err();
+        assert!(
+            error.message.starts_with("Does not parse as int"),
+            "{}",
+            error
+        );
+        let error = parser.parse_line("c|1").unwrap_err();
+        assert_eq!(error.message, "No schema line matching 'c'");
+    }
+
+    #[test]
+    fn read_file() {
+        let path = std::env::temp_dir().join(format!("xlineparse-{}.psv", std::process::id()));
+        std::fs::write(&path, "a|1\r\nb|x|2\na|3").unwrap();
+        let parser = Parser::from_json(SCHEMA).unwrap();
+        let mut lines = vec![];
+        let result = parser.read_file(path.to_str().unwrap(), &mut |line: &[u8], _| {
+            lines.push(parser.parse_line_bytes(line)?);
+            Ok::<(), Box<dyn std::error::Error>>(())
+        });
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2], vec![Value::Str("a".to_string()), Value::Int(3)]);
+    }
+
+    #[test]
+    fn warnings_located() {
+        let schema = SCHEMA.replace(
+            r#"{"kind": "INT", "required": true}"#,
+            r#"{"kind": "INT", "required": true, "min_value": 0, "severity": "warn"}"#,
+        );
+        let parser = Parser::from_json(&schema).unwrap();
+        let mut batch = Batch::new(&parser);
+        let parsed = batch.parse("a|-1\n", 5).unwrap();
+        assert!(
+            parsed.warnings[0].starts_with("Line 1, byte 0: "),
+            "{:?}",
+            parsed.warnings
+        );
+        let (errors, parsed) = batch.validate_parsed("a|-2\n", 5);
+        assert!(errors.is_empty(), "{:?}", errors);
+        let warnings = parsed.unwrap().warnings;
+        assert!(
+            warnings[0].starts_with("Line 2, byte 5: "),
+            "{:?}",
+            warnings
+        );
+    }
 }
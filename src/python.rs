@@ -0,0 +1,1015 @@
+// The Python bindings, on top of the core Parser
+use pyo3::exceptions::*;
+use pyo3::prelude::*;
+use pyo3::types::*;
+
+use generate::Generator;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+#[cfg(feature = "parquet")]
+use to_parquet::ParquetFiles;
+use {Batch, Error, ErrorKind, Field, Joiner, LineMatch, ParsedLine, Stats, StrField, Value};
+
+pyo3::create_exception!(xlineparse, ReconciliationError, PyValueError);
+// For the constraints with severity warn
+pyo3::create_exception!(xlineparse, LineWarning, PyUserWarning);
+
+// Errors from a batch are raised with (message, line, line_no, offset) as the args
+impl From<Error> for PyErr {
+    fn from(e: Error) -> PyErr {
+        match (e.kind, e.location) {
+            (ErrorKind::Invalid, None) => PyValueError::new_err(e.message),
+            (ErrorKind::Reconciliation, None) => ReconciliationError::new_err(e.message),
+            (kind, Some(location)) => {
+                let args = (e.message, location.line, location.line_no, location.offset);
+                match kind {
+                    ErrorKind::Reconciliation => ReconciliationError::new_err(args),
+                    ErrorKind::Invalid => PyValueError::new_err(args),
+                }
+            }
+        }
+    }
+}
+
+impl IntoPy<PyObject> for Value {
+    fn into_py(self, py: Python) -> PyObject {
+        match self {
+            Value::Null => py.None(),
+            Value::Str(s) => s.into_py(py),
+            Value::Int(i) => i.into_py(py),
+            Value::Float(f) => f.into_py(py),
+            Value::Decimal(d) => d.into_py(py),
+            Value::Bool(b) => b.into_py(py),
+            Value::Datetime(dt) => dt.into_py(py),
+            Value::NaiveDatetime(dt) => dt.into_py(py),
+            Value::Date(d) => d.into_py(py),
+            Value::Time(t) => t.into_py(py),
+            Value::Composite(values) => {
+                let items: Vec<PyObject> = values.into_iter().map(|v| v.into_py(py)).collect();
+                PyTuple::new(py, items).into_py(py)
+            }
+        }
+    }
+}
+
+#[pyclass(frozen, name = "Parser", module = "xlineparse")]
+pub struct PyParser {
+    // Shared with the threads of iter_file
+    parser: Arc<::Parser>,
+    // (line index, field index) -> enum output -> Python enum member
+    members: HashMap<(usize, usize), Py<PyDict>>,
+    // The line names and enum outputs, so each is made once
+    strings: HashMap<String, Py<PyString>>,
+    // The outputs of StrFields with intern, shared by this parser's lines
+    interned: Mutex<HashMap<String, Py<PyString>>>,
+    // (line index, field index) -> called with each non null value, returning
+    // what's output in its place
+    validators: HashMap<(usize, usize), PyObject>,
+}
+#[pymethods]
+impl PyParser {
+    // enum_members are (line index, field index, value -> enum member),
+    // validators are (line index, field index, callable), schema includes are
+    // relative to base_dir
+    #[new]
+    #[pyo3(signature = (schema_json_str, enum_members=None, validators=None, base_dir=""))]
+    fn new<'a>(
+        _py: Python<'a>,
+        schema_json_str: &str,
+        enum_members: Option<Vec<(usize, usize, Py<PyDict>)>>,
+        validators: Option<Vec<(usize, usize, PyObject)>>,
+        base_dir: &str,
+    ) -> PyResult<Self> {
+        let parser = Arc::new(::Parser::from_json_in(
+            schema_json_str,
+            Path::new(base_dir),
+        )?);
+        let mut members = HashMap::new();
+        for (line_i, field_i, field_members) in enum_members.into_iter().flatten() {
+            let field = parser
+                .schema()
+                .lines
+                .get(line_i)
+                .and_then(|line| line.fields.get(field_i));
+            match field {
+                Some(Field::StrEnum(_)) | Some(Field::IntEnum(_)) => {
+                    members.insert((line_i, field_i), field_members);
+                }
+                _ => return Err(PyValueError::new_err("enum_members need enum fields")),
+            }
+        }
+        let mut by_field = HashMap::new();
+        for (line_i, field_i, validator) in validators.into_iter().flatten() {
+            let exists = parser
+                .schema()
+                .lines
+                .get(line_i)
+                .is_some_and(|line| field_i < line.fields.len());
+            if !exists || !validator.as_ref(_py).is_callable() {
+                return Err(PyValueError::new_err("validators need callables of fields"));
+            }
+            by_field.insert((line_i, field_i), validator);
+        }
+        let mut strings = HashMap::new();
+        for line in &parser.schema().lines {
+            if let LineMatch::Exact = line.line_match {
+                for name in line.names.iter().chain(line.aliases.iter().flatten()) {
+                    strings.insert(name.clone(), PyString::new(_py, name).into());
+                }
+            }
+            for field in &line.fields {
+                let outputs: Vec<&String> = match field {
+                    Field::StrEnum(enum_field) => enum_field.lookup.0.values().collect(),
+                    Field::IntEnum(enum_field) => enum_field.lookup.0.values().flatten().collect(),
+                    _ => vec![],
+                };
+                for output in outputs {
+                    strings.insert(output.clone(), PyString::new(_py, output).into());
+                }
+            }
+        }
+        Ok(PyParser {
+            parser,
+            members,
+            strings,
+            interned: Mutex::default(),
+            validators: by_field,
+        })
+    }
+    fn parse_line<'a>(&self, _py: Python<'a>, line: &str) -> PyResult<PyObject> {
+        let line = self.parser.strip_bom(line);
+        let parsed = self.parser.parse_indexed(line)?;
+        Ok(self.to_py(_py, parsed)?)
+    }
+    fn parse_line_bytes<'a>(&self, _py: Python<'a>, line: &[u8]) -> PyResult<PyObject> {
+        self.parse_line(_py, &self.parser.decode(line)?)
+    }
+    // As ::Parser::to_json, without the enum members or validators
+    fn parse_line_to_json<'a>(&self, _py: Python<'a>, line: &str) -> PyResult<String> {
+        self.check_no_validators()?;
+        let line = self.parser.strip_bom(line);
+        let parsed = self.parser.parse_indexed(line)?;
+        warn(_py, &parsed.warnings)?;
+        Ok(self.parser.to_json(&parsed).to_string())
+    }
+    // Parse many lines, checking the trailers as described by schema.trailer_checks.
+    // With on_error="collect", returns (parsed, rejects) of (line, line_no, error).
+    // With stats, returns (that, stats), see Output::finish
+    #[pyo3(signature = (lines, on_error="raise", stats=false))]
+    fn parse_lines<'a>(
+        &self,
+        _py: Python<'a>,
+        lines: &PyAny,
+        on_error: &str,
+        stats: bool,
+    ) -> PyResult<PyObject> {
+        let mut output = Output::new(&self.parser, on_error, stats)?;
+        let mut batch = Batch::new(&self.parser);
+        self.for_each_line::<&str, _>(lines, &mut |line, raw_len| {
+            let line = String::from_utf8_lossy(line);
+            let result = batch.parse(&line, raw_len);
+            output.observe(&result);
+            let result = self.batch_to_py(_py, &batch, &line, result);
+            output.add(_py, &self.parser, &batch, line.as_bytes(), result)
+        })?;
+        Ok(output.finish(_py))
+    }
+    // As parse_lines, with the output as a list of parsed lines per schema line
+    #[pyo3(signature = (lines, on_error="raise", stats=false))]
+    fn parse_lines_grouped<'a>(
+        &self,
+        _py: Python<'a>,
+        lines: &PyAny,
+        on_error: &str,
+        stats: bool,
+    ) -> PyResult<PyObject> {
+        let mut output = Output::new(&self.parser, on_error, stats)?.grouped(&self.parser);
+        let mut batch = Batch::new(&self.parser);
+        self.for_each_line::<&str, _>(lines, &mut |line, raw_len| {
+            let line = String::from_utf8_lossy(line);
+            let result = batch.parse(&line, raw_len);
+            output.observe(&result);
+            let result = self.batch_to_py(_py, &batch, &line, result);
+            output.add(_py, &self.parser, &batch, line.as_bytes(), result)
+        })?;
+        Ok(output.finish(_py))
+    }
+    #[pyo3(signature = (lines, on_error="raise", stats=false))]
+    fn parse_lines_bytes<'a>(
+        &self,
+        _py: Python<'a>,
+        lines: &PyAny,
+        on_error: &str,
+        stats: bool,
+    ) -> PyResult<PyObject> {
+        let mut output = Output::new(&self.parser, on_error, stats)?;
+        let mut batch = Batch::new(&self.parser);
+        self.for_each_line::<&[u8], _>(lines, &mut |line, raw_len| {
+            let result = batch.parse_bytes(line, raw_len);
+            output.observe(&result);
+            let result = self.batch_to_py(_py, &batch, &String::from_utf8_lossy(line), result);
+            output.add(_py, &self.parser, &batch, line, result)
+        })?;
+        Ok(output.finish(_py))
+    }
+    // As parse_lines, reading the lines from a (possibly compressed) file.
+    // progress is called with (lines, bytes) so far every progress_every lines,
+    // and at the end
+    #[pyo3(signature = (
+        path, on_error="raise", stats=false, progress=None, progress_every=100_000
+    ))]
+    fn parse_file<'a>(
+        &self,
+        _py: Python<'a>,
+        path: &str,
+        on_error: &str,
+        stats: bool,
+        progress: Option<&PyAny>,
+        progress_every: usize,
+    ) -> PyResult<PyObject> {
+        if progress_every == 0 {
+            return Err(PyValueError::new_err("progress_every needs to be > 0"));
+        }
+        let mut output = Output::new(&self.parser, on_error, stats)?;
+        let mut batch = Batch::new(&self.parser);
+        let mut bytes_read = 0;
+        self.parser
+            .read_file(path, &mut |line: &[u8], raw_len: usize| -> PyResult<()> {
+                let result = batch.parse_bytes(line, raw_len);
+                output.observe(&result);
+                let result = self.batch_to_py(_py, &batch, &String::from_utf8_lossy(line), result);
+                output.add(_py, &self.parser, &batch, line, result)?;
+                bytes_read += raw_len;
+                // is_multiple_of needs a newer toolchain than CI's
+                #[allow(clippy::manual_is_multiple_of)]
+                match progress {
+                    Some(progress) if batch.line_no() % progress_every == 0 => {
+                        progress.call1((batch.line_no(), bytes_read))?;
+                    }
+                    _ => {}
+                }
+                Ok(())
+            })?;
+        if let Some(progress) = progress {
+            progress.call1((batch.line_no(), bytes_read))?;
+        }
+        Ok(output.finish(_py))
+    }
+    // As parse_file, writing each line as parse_line_to_json does, one per
+    // line. Returns how many were written, with on_error="collect" as
+    // (written, rejects)
+    #[pyo3(signature = (in_path, out_path, on_error="raise"))]
+    fn parse_file_to_ndjson<'a>(
+        &self,
+        _py: Python<'a>,
+        in_path: &str,
+        out_path: &str,
+        on_error: &str,
+    ) -> PyResult<PyObject> {
+        self.check_no_validators()?;
+        let mut rejects = Output::new(&self.parser, on_error, false)?.rejects;
+        let mut batch = Batch::new(&self.parser);
+        let mut file = BufWriter::new(File::create(out_path)?);
+        let mut written = 0;
+        self.parser.read_file(in_path, &mut |line: &[u8],
+                                              raw_len: usize|
+         -> PyResult<()> {
+            match (batch.parse_bytes(line, raw_len), &mut rejects) {
+                (Ok(parsed), _) => {
+                    warn(_py, &parsed.warnings)?;
+                    ::serde_json::to_writer(&mut file, &self.parser.to_json(&parsed))
+                        .map_err(|e| PyIOError::new_err(e.to_string()))?;
+                    file.write_all(b"\n")?;
+                    written += 1;
+                }
+                (Err(e), Some(rejects)) => {
+                    rejects.push(to_reject(_py, &self.parser, &batch, line, e))
+                }
+                (Err(e), None) => return Err(e.into()),
+            }
+            Ok(())
+        })?;
+        file.flush()?;
+        Ok(match rejects {
+            Some(rejects) => (written, rejects).into_py(_py),
+            None => written.into_py(_py),
+        })
+    }
+    // As parse_file, writing a Parquet file per line to out_dir, see
+    // to_parquet. Returns the paths written, with on_error="collect" as
+    // (paths, rejects)
+    #[cfg(feature = "parquet")]
+    #[pyo3(signature = (in_path, out_dir, on_error="raise", row_group_size=100_000))]
+    fn parse_file_to_parquet<'a>(
+        &self,
+        _py: Python<'a>,
+        in_path: &str,
+        out_dir: &str,
+        on_error: &str,
+        row_group_size: usize,
+    ) -> PyResult<PyObject> {
+        self.check_no_validators()?;
+        if row_group_size == 0 {
+            return Err(PyValueError::new_err("row_group_size needs to be > 0"));
+        }
+        let mut rejects = Output::new(&self.parser, on_error, false)?.rejects;
+        let mut batch = Batch::new(&self.parser);
+        let mut files = ParquetFiles::new(&self.parser, out_dir, row_group_size);
+        self.parser.read_file(in_path, &mut |line: &[u8],
+                                              raw_len: usize|
+         -> PyResult<()> {
+            match (batch.parse_bytes(line, raw_len), &mut rejects) {
+                (Ok(parsed), _) => {
+                    warn(_py, &parsed.warnings)?;
+                    files.add(parsed)?;
+                }
+                (Err(e), Some(rejects)) => {
+                    rejects.push(to_reject(_py, &self.parser, &batch, line, e))
+                }
+                (Err(e), None) => return Err(e.into()),
+            }
+            Ok(())
+        })?;
+        let paths = files.finish()?;
+        Ok(match rejects {
+            Some(rejects) => (paths, rejects).into_py(_py),
+            None => paths.into_py(_py),
+        })
+    }
+    // As parse_file, in chunks of lines parsed by a background thread. The GIL
+    // is only held to make each chunk's Python objects
+    #[pyo3(signature = (path, on_error="raise", chunk_size=10_000))]
+    fn iter_file(
+        slf: &PyCell<Self>,
+        path: String,
+        on_error: &str,
+        chunk_size: usize,
+    ) -> PyResult<FileChunks> {
+        let collect = Output::new(&slf.get().parser, on_error, false)?
+            .rejects
+            .is_some();
+        if chunk_size == 0 {
+            return Err(PyValueError::new_err("chunk_size needs to be > 0"));
+        }
+        let parser = Arc::clone(&slf.get().parser);
+        // Parse at most a couple of chunks ahead
+        let (sender, receiver) = mpsc::sync_channel(2);
+        thread::spawn(move || {
+            let mut batch = Batch::new(&parser);
+            let mut chunk = Vec::with_capacity(chunk_size);
+            let result = parser.read_file(&path, &mut |line: &[u8], raw_len: usize| {
+                let result = batch.parse_bytes(line, raw_len);
+                let stop = result.is_err() && !collect;
+                chunk.push(result);
+                if chunk.len() == chunk_size || stop {
+                    // Fails if the FileChunks was dropped
+                    sender
+                        .send(Ok(mem::take(&mut chunk)))
+                        .map_err(|_| Stop::Done)?;
+                }
+                if stop {
+                    return Err(Stop::Done);
+                }
+                Ok(())
+            });
+            let _ = match result {
+                Ok(()) if chunk.is_empty() => Ok(()),
+                Ok(()) => sender.send(Ok(chunk)),
+                Err(Stop::Io(e)) => sender.send(Err(e)),
+                Err(Stop::Done) => Ok(()),
+            };
+        });
+        Ok(FileChunks {
+            parser: slf.into(),
+            collect,
+            receiver: Some(receiver),
+        })
+    }
+    // As parse_lines, nesting them as Groups as described by schema.groups
+    fn parse_document<'a>(&self, _py: Python<'a>, lines: &PyAny) -> PyResult<Vec<PyObject>> {
+        let schema = self.parser.schema();
+        let plan = &self.parser.plan;
+        let mut batch = Batch::new(&self.parser);
+        let mut out = vec![];
+        let mut stack: Vec<OpenGroup> = vec![];
+        self.for_each_line::<&str, _>(lines, &mut |line, raw_len| {
+            let line = String::from_utf8_lossy(line);
+            let result = batch.parse(&line, raw_len);
+            let (line_i, parsed) = self.batch_to_py(_py, &batch, &line, result)?;
+            loop {
+                let group = stack.last().map(|open| &plan.groups[open.group_i]);
+                match group {
+                    Some(group) if group.end == Some(line_i) => {
+                        let closed = stack.pop().unwrap().close(_py, parsed)?;
+                        add_to_document(&mut stack, &mut out, closed);
+                    }
+                    Some(group) if group.children.contains(&line_i) => {
+                        match plan.group_starts.get(&line_i) {
+                            Some(group_i) => stack.push(OpenGroup::new(*group_i, parsed)),
+                            None => stack.last_mut().unwrap().children.push(parsed),
+                        }
+                    }
+                    // Groups without an end line finish at the first line that isn't theirs
+                    Some(group) if group.end.is_none() => {
+                        let closed = stack.pop().unwrap().close(_py, _py.None())?;
+                        add_to_document(&mut stack, &mut out, closed);
+                        continue;
+                    }
+                    Some(group) => {
+                        let e = Error::from(format!(
+                            "Line {:?} isn't allowed in group {:?}",
+                            schema.lines[line_i].names, schema.lines[group.start].names,
+                        ));
+                        return Err(batch.error(e, "group", &line).into());
+                    }
+                    None => match plan.group_starts.get(&line_i) {
+                        Some(group_i) => stack.push(OpenGroup::new(*group_i, parsed)),
+                        None => out.push(parsed),
+                    },
+                }
+                break;
+            }
+            Ok(())
+        })?;
+        while let Some(open) = stack.pop() {
+            let group = &plan.groups[open.group_i];
+            if group.end.is_some() {
+                return Err(PyValueError::new_err(format!(
+                    "Group {:?} is missing its end line",
+                    schema.lines[group.start].names,
+                )));
+            }
+            let closed = open.close(_py, _py.None())?;
+            add_to_document(&mut stack, &mut out, closed);
+        }
+        Ok(out)
+    }
+    fn parse_first<'a>(&self, _py: Python<'a>, line: &str) -> PyResult<PyObject> {
+        Ok(self.parser.parse_first(line)?.into_py(_py))
+    }
+    fn parse_prefix<'a>(&self, _py: Python<'a>, line: &str, n: usize) -> PyResult<Vec<String>> {
+        Ok(self.parser.parse_prefix(line, n)?)
+    }
+    fn split<'a>(&self, _py: Python<'a>, line: &str) -> PyResult<Vec<String>> {
+        let parts = self.parser.split(line)?;
+        Ok(parts
+            .into_iter()
+            .map(|part| part.value.into_owned())
+            .collect())
+    }
+    // As (value, is_quoted)
+    fn split_quoted<'a>(&self, _py: Python<'a>, line: &str) -> PyResult<Vec<(String, bool)>> {
+        let parts = self.parser.split(line)?;
+        Ok(parts
+            .into_iter()
+            .map(|part| (part.value.into_owned(), part.is_quoted))
+            .collect())
+    }
+    // As parse_line without building the output, None or every problem with the line
+    fn validate_line<'a>(&self, _py: Python<'a>, line: &str) -> Option<Vec<String>> {
+        let (mut errors, parsed) = self.parser.validate_line_parsed(line);
+        errors.extend(self.validator_errors(_py, parsed));
+        if errors.is_empty() {
+            return None;
+        }
+        Some(errors.into_iter().map(|e| e.message).collect())
+    }
+    // As parse_lines(on_error="collect") without building the output, returns the
+    // rejects, there may be more than one per line
+    fn validate_lines<'a>(&self, _py: Python<'a>, lines: &PyAny) -> PyResult<Vec<PyObject>> {
+        let mut batch = Batch::new(&self.parser);
+        let mut rejects = vec![];
+        self.for_each_line::<&str, _>(lines, &mut |line, raw_len| {
+            let decoded = String::from_utf8_lossy(line);
+            let (mut errors, parsed) = batch.validate_parsed(&decoded, raw_len);
+            for e in self.validator_errors(_py, parsed) {
+                errors.push(batch.error(e, "parse", &decoded));
+            }
+            for e in errors {
+                rejects.push(to_reject(_py, &self.parser, &batch, line, e));
+            }
+            Ok(())
+        })?;
+        Ok(rejects)
+    }
+    // With count_lines, as (rejects, the number of lines)
+    #[pyo3(signature = (path, count_lines=false))]
+    fn validate_file<'a>(
+        &self,
+        _py: Python<'a>,
+        path: &str,
+        count_lines: bool,
+    ) -> PyResult<PyObject> {
+        let mut batch = Batch::new(&self.parser);
+        let mut rejects = vec![];
+        self.parser
+            .read_file(path, &mut |line: &[u8], raw_len: usize| -> PyResult<()> {
+                let (mut errors, parsed) = batch.validate_bytes_parsed(line, raw_len);
+                for e in self.validator_errors(_py, parsed) {
+                    errors.push(batch.error(e, "parse", &String::from_utf8_lossy(line)));
+                }
+                for e in errors {
+                    rejects.push(to_reject(_py, &self.parser, &batch, line, e));
+                }
+                Ok(())
+            })?;
+        if count_lines {
+            return Ok((rejects, batch.line_no()).into_py(_py));
+        }
+        Ok(rejects.into_py(_py))
+    }
+    // As dicts mirroring the schema JSON
+    #[getter]
+    fn schema<'a>(&self, _py: Python<'a>) -> PyResult<PyObject> {
+        to_py_json(_py, self.parser.schema())
+    }
+    // Composite names are tuples
+    #[getter]
+    fn line_names<'a>(&self, _py: Python<'a>) -> Vec<PyObject> {
+        self.parser
+            .schema()
+            .line_names()
+            .map(|names| match names {
+                [name] => name.into_py(_py),
+                names => PyTuple::new(_py, names).into_py(_py),
+            })
+            .collect()
+    }
+    fn fields_for<'a>(&self, _py: Python<'a>, line_name: &PyAny) -> PyResult<PyObject> {
+        let line_i = self.line_index(line_name)?;
+        to_py_json(_py, &self.parser.schema().lines[line_i].fields)
+    }
+    // A random line that parses as the named line
+    #[pyo3(signature = (line_name, seed=None))]
+    fn generate_line(&self, line_name: &PyAny, seed: Option<u64>) -> PyResult<String> {
+        let line_i = self.line_index(line_name)?;
+        Ok(Generator::new(&self.parser, seed).line(line_i)?)
+    }
+    // Of n_lines random lines, picked from line_names, or all the lines
+    #[pyo3(signature = (path, n_lines, seed=None, line_names=None))]
+    fn generate_file(
+        &self,
+        path: &str,
+        n_lines: usize,
+        seed: Option<u64>,
+        line_names: Option<Vec<&PyAny>>,
+    ) -> PyResult<()> {
+        let line_is: Vec<usize> = match line_names {
+            Some(line_names) => line_names
+                .into_iter()
+                .map(|line_name| self.line_index(line_name))
+                .collect::<PyResult<_>>()?,
+            None => (0..self.parser.schema().lines.len()).collect(),
+        };
+        let terminator = match &self.parser.schema().line_terminator {
+            Some(terminator) => terminator.as_str(),
+            None => "\n",
+        };
+        let mut generator = Generator::new(&self.parser, seed);
+        let mut file = BufWriter::new(File::create(path)?);
+        for _ in 0..n_lines {
+            let line = generator.random_line(&line_is)?;
+            file.write_all(line.as_bytes())?;
+            file.write_all(terminator.as_bytes())?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+// Attributed to the caller of the Schema method
+fn warn(py: Python, warnings: &[String]) -> PyResult<()> {
+    for warning in warnings {
+        PyErr::warn(py, py.get_type::<LineWarning>(), warning, 2)?;
+    }
+    Ok(())
+}
+
+fn to_py_json<T: ::serde::Serialize>(py: Python, value: &T) -> PyResult<PyObject> {
+    let value = ::serde_json::to_value(value).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    Ok(json_to_py(py, value))
+}
+
+fn json_to_py(py: Python, value: ::serde_json::Value) -> PyObject {
+    use serde_json::Value as Json;
+    match value {
+        Json::Null => py.None(),
+        Json::Bool(b) => b.into_py(py),
+        Json::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => i.into_py(py),
+            (_, Some(u)) => u.into_py(py),
+            _ => n.as_f64().into_py(py),
+        },
+        Json::String(s) => s.into_py(py),
+        Json::Array(items) => items
+            .into_iter()
+            .map(|item| json_to_py(py, item))
+            .collect::<Vec<_>>()
+            .into_py(py),
+        Json::Object(entries) => {
+            let dict = PyDict::new(py);
+            for (k, v) in entries {
+                dict.set_item(k, json_to_py(py, v)).unwrap();
+            }
+            dict.into_py(py)
+        }
+    }
+}
+
+impl PyParser {
+    // Calls on_line with each str (or bytes) of lines, joined as in
+    // schema.continuation. Joined strs are still valid UTF-8
+    fn for_each_line<'p, T, F>(&self, lines: &'p PyAny, on_line: &mut F) -> PyResult<()>
+    where
+        T: FromPyObject<'p> + AsRef<[u8]>,
+        F: FnMut(&[u8], usize) -> PyResult<()>,
+    {
+        let mut joiner = Joiner::new(&self.parser);
+        for line in lines.iter()? {
+            let line: T = line?.extract()?;
+            let line = line.as_ref();
+            joiner.push(line, line.len(), on_line)?;
+        }
+        joiner.finish(on_line)
+    }
+    // Of a line name as in Line.name
+    fn line_index(&self, line_name: &PyAny) -> PyResult<usize> {
+        let names: Vec<String> = match line_name.extract::<String>() {
+            Ok(name) => vec![name],
+            Err(_) => line_name.extract()?,
+        };
+        self.parser
+            .schema()
+            .line_index(&names)
+            .ok_or_else(|| PyKeyError::new_err(format!("Line {:?} isn't in the schema", names)))
+    }
+    // As a tuple, with any enum outputs swapped for their Python enum members.
+    // With schema.include_raw, as (tuple, raw tuple)
+    fn to_py(&self, py: Python, parsed: ParsedLine) -> Result<PyObject, Error> {
+        let line_i = parsed.line_index;
+        // The fields come after the line name(s)
+        let offset = parsed.n_names;
+        warn(py, &parsed.warnings).map_err(|e| e.to_string())?;
+        let mut items: Vec<PyObject> = Vec::with_capacity(parsed.values.len());
+        for (i, value) in parsed.values.into_iter().enumerate() {
+            let field_i = i.checked_sub(offset);
+            let item = self.value_to_py(py, line_i, field_i, value)?;
+            items.push(self.validate_value(py, line_i, field_i, item)?);
+        }
+        let values = PyTuple::new(py, &items);
+        Ok(match parsed.raw {
+            Some(raw) => (values, PyTuple::new(py, raw)).into_py(py),
+            None => values.into_py(py),
+        })
+    }
+    // Of the value of the field at field_i, None for the line name(s)
+    fn value_to_py(
+        &self,
+        py: Python,
+        line_i: usize,
+        field_i: Option<usize>,
+        value: Value,
+    ) -> Result<PyObject, Error> {
+        let fields = &self.parser.schema().lines[line_i].fields;
+        let members = field_i.and_then(|field_i| self.members.get(&(line_i, field_i)));
+        Ok(match members {
+            Some(members) if !value.is_null() => {
+                let err = format!(
+                    "Value not in enum class - '{}' given schema: {:?}",
+                    value,
+                    fields[field_i.unwrap()]
+                );
+                match members.as_ref(py).get_item(value.into_py(py)) {
+                    Ok(Some(member)) => member.into_py(py),
+                    Ok(None) => return Err(err.into()),
+                    Err(e) => return Err(e.to_string().into()),
+                }
+            }
+            _ => match (value, field_i.and_then(|i| fields.get(i))) {
+                (Value::Str(s), None | Some(Field::StrEnum(_)) | Some(Field::IntEnum(_))) => {
+                    match self.strings.get(&s) {
+                        Some(string) => string.clone_ref(py).into_py(py),
+                        None => s.into_py(py),
+                    }
+                }
+                (Value::Str(s), Some(Field::Str(StrField { intern: true, .. }))) => self
+                    .interned
+                    .lock()
+                    .unwrap()
+                    .entry(s)
+                    .or_insert_with_key(|s| PyString::new(py, s).into())
+                    .clone_ref(py)
+                    .into_py(py),
+                (value, _) => value.into_py(py),
+            },
+        })
+    }
+    // What the field's validator returns, if it has one
+    fn validate_value(
+        &self,
+        py: Python,
+        line_i: usize,
+        field_i: Option<usize>,
+        item: PyObject,
+    ) -> Result<PyObject, Error> {
+        let Some(field_i) = field_i else {
+            return Ok(item);
+        };
+        match self.validators.get(&(line_i, field_i)) {
+            Some(validator) if !item.is_none(py) => {
+                Ok(validator.call1(py, (&item,)).map_err(|e| {
+                    format!(
+                        "Validator failed - {} given schema: {:?}",
+                        e,
+                        self.parser.schema().lines[line_i].fields[field_i]
+                    )
+                })?)
+            }
+            _ => Ok(item),
+        }
+    }
+    // Of the fields of a validated line with validators, the ones failing them
+    fn validator_errors(&self, py: Python, parsed: Option<ParsedLine>) -> Vec<Error> {
+        let Some(parsed) = parsed else {
+            return vec![];
+        };
+        let (line_i, offset) = (parsed.line_index, parsed.n_names);
+        let mut errors = vec![];
+        for (i, value) in parsed.values.into_iter().enumerate() {
+            let field_i = i.checked_sub(offset);
+            if !field_i.is_some_and(|field_i| self.validators.contains_key(&(line_i, field_i))) {
+                continue;
+            }
+            let result = self
+                .value_to_py(py, line_i, field_i, value)
+                .and_then(|item| self.validate_value(py, line_i, field_i, item));
+            if let Err(e) = result {
+                errors.push(e)
+            }
+        }
+        errors
+    }
+    // The Rust only outputs can't call them
+    fn check_no_validators(&self) -> PyResult<()> {
+        if !self.validators.is_empty() {
+            return Err(PyValueError::new_err(
+                "Schema.validators are Python callables, use .parse_line() etc.",
+            ));
+        }
+        Ok(())
+    }
+    fn batch_to_py(
+        &self,
+        py: Python,
+        batch: &Batch,
+        line: &str,
+        result: Result<ParsedLine, Error>,
+    ) -> Result<(usize, PyObject), Error> {
+        let parsed = result?;
+        let line_i = parsed.line_index;
+        let parsed = self
+            .to_py(py, parsed)
+            .map_err(|e| batch.error(e, "parse", line))?;
+        Ok((line_i, parsed))
+    }
+}
+
+// The parsed lines, and the failed ones if we're collecting them rather than raising
+struct Output {
+    out: Vec<PyObject>,
+    // Per schema line, instead of out
+    groups: Option<Vec<Vec<PyObject>>>,
+    rejects: Option<Vec<PyObject>>,
+    stats: Option<Stats>,
+}
+impl Output {
+    fn new(parser: &::Parser, on_error: &str, stats: bool) -> PyResult<Self> {
+        let rejects = match on_error {
+            "raise" => None,
+            "collect" => Some(vec![]),
+            _ => {
+                return Err(PyValueError::new_err(
+                    "on_error needs to be 'raise' or 'collect'",
+                ))
+            }
+        };
+        Ok(Output {
+            out: vec![],
+            groups: None,
+            rejects,
+            stats: if stats {
+                Some(Stats::new(parser.schema()))
+            } else {
+                None
+            },
+        })
+    }
+    fn grouped(self, parser: &::Parser) -> Self {
+        let n_lines = parser.schema().lines.len();
+        Output {
+            groups: Some((0..n_lines).map(|_| vec![]).collect()),
+            ..self
+        }
+    }
+    fn observe(&mut self, result: &Result<ParsedLine, Error>) {
+        match (&mut self.stats, result) {
+            (Some(stats), Ok(parsed)) => stats.add(parsed),
+            (Some(stats), Err(_)) => stats.add_error(),
+            (None, _) => {}
+        }
+    }
+    // Add the parsed line to the output, or its error to the rejects
+    fn add(
+        &mut self,
+        py: Python,
+        parser: &::Parser,
+        batch: &Batch,
+        line: &[u8],
+        result: Result<(usize, PyObject), Error>,
+    ) -> PyResult<()> {
+        match (result, &mut self.rejects) {
+            (Ok((line_i, parsed)), _) => match &mut self.groups {
+                Some(groups) => groups[line_i].push(parsed),
+                None => self.out.push(parsed),
+            },
+            (Err(e), Some(rejects)) => rejects.push(to_reject(py, parser, batch, line, e)),
+            (Err(e), None) => return Err(e.into()),
+        }
+        Ok(())
+    }
+    // With stats, as (output, (lines, errors, per line stats)) where the
+    // per line stats are (count, null counts, min values, max values)
+    fn finish(self, py: Python) -> PyObject {
+        let out = match self.groups {
+            Some(groups) => groups.into_py(py),
+            None => self.out.into_py(py),
+        };
+        let output = match self.rejects {
+            Some(rejects) => (out, rejects).into_py(py),
+            None => out,
+        };
+        let Some(stats) = self.stats else {
+            return output;
+        };
+        let line_stats: Vec<PyObject> = stats
+            .line_stats
+            .into_iter()
+            .map(|line| {
+                let (count, nulls) = (line.count, line.null_counts);
+                (count, nulls, line.min_values, line.max_values).into_py(py)
+            })
+            .collect();
+        (output, (stats.lines, stats.errors, line_stats)).into_py(py)
+    }
+}
+
+// As (line, line_no, error)
+fn to_reject(py: Python, parser: &::Parser, batch: &Batch, line: &[u8], e: Error) -> PyObject {
+    let line = String::from_utf8_lossy(line);
+    let line = parser.plan.terminator.strip(&line);
+    (line, batch.line_no(), PyErr::from(e).value(py)).into_py(py)
+}
+
+// Why a FileChunks thread stopped reading
+enum Stop {
+    Io(io::Error),
+    Done,
+}
+impl From<io::Error> for Stop {
+    fn from(e: io::Error) -> Self {
+        Stop::Io(e)
+    }
+}
+
+// From PyParser::iter_file, each item is as parse_file's output for a chunk of lines
+#[pyclass(module = "xlineparse")]
+pub struct FileChunks {
+    parser: Py<PyParser>,
+    collect: bool,
+    // None once finished
+    receiver: Option<Receiver<Chunk>>,
+}
+
+type Chunk = io::Result<Vec<Result<ParsedLine, Error>>>;
+#[pymethods]
+impl FileChunks {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+    fn __next__(mut slf: PyRefMut<Self>, py: Python) -> PyResult<Option<PyObject>> {
+        let Some(receiver) = slf.receiver.take() else {
+            return Ok(None);
+        };
+        let (receiver, chunk) = py.allow_threads(move || {
+            let chunk = receiver.recv();
+            (receiver, chunk)
+        });
+        // The thread has finished
+        let Ok(chunk) = chunk else {
+            return Ok(None);
+        };
+        let chunk = chunk?;
+        slf.receiver = Some(receiver);
+        let parser = slf.parser.get();
+        let mut out = vec![];
+        let mut rejects = vec![];
+        for result in chunk {
+            match (
+                result.and_then(|parsed| parser.to_py(py, parsed)),
+                slf.collect,
+            ) {
+                (Ok(parsed), _) => out.push(parsed),
+                (Err(e), true) => rejects.push(location_reject(py, e)),
+                (Err(e), false) => {
+                    slf.receiver = None;
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(Some(if slf.collect {
+            (out, rejects).into_py(py)
+        } else {
+            out.into_py(py)
+        }))
+    }
+}
+
+// As to_reject, from the error's location
+fn location_reject(py: Python, e: Error) -> PyObject {
+    let (line, line_no) = match &e.location {
+        Some(location) => (location.line.clone(), location.line_no),
+        None => (String::new(), 0),
+    };
+    (line, line_no, PyErr::from(e).value(py)).into_py(py)
+}
+
+// A start line and its children from parse_document
+#[pyclass(frozen, get_all, module = "xlineparse")]
+pub struct Group {
+    header: PyObject,
+    children: Vec<PyObject>,
+    trailer: PyObject, // None for groups without an end line
+}
+#[pymethods]
+impl Group {
+    fn __repr__(&self, py: Python) -> PyResult<String> {
+        let children = self
+            .children
+            .iter()
+            .map(|child| Ok(child.as_ref(py).repr()?.to_string()))
+            .collect::<PyResult<Vec<_>>>()?;
+        Ok(format!(
+            "Group(header={}, children=[{}], trailer={})",
+            self.header.as_ref(py).repr()?,
+            children.join(", "),
+            self.trailer.as_ref(py).repr()?,
+        ))
+    }
+}
+
+struct OpenGroup {
+    group_i: usize,
+    header: PyObject,
+    children: Vec<PyObject>,
+}
+impl OpenGroup {
+    fn new(group_i: usize, header: PyObject) -> Self {
+        OpenGroup {
+            group_i,
+            header,
+            children: vec![],
+        }
+    }
+    fn close(self, py: Python, trailer: PyObject) -> PyResult<PyObject> {
+        let group = Group {
+            header: self.header,
+            children: self.children,
+            trailer,
+        };
+        Ok(Py::new(py, group)?.into_py(py))
+    }
+}
+
+fn add_to_document(stack: &mut [OpenGroup], out: &mut Vec<PyObject>, item: PyObject) {
+    match stack.last_mut() {
+        Some(open) => open.children.push(item),
+        None => out.push(item),
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "xlineparse")]
+fn init_mod(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyParser>()?;
+    m.add_class::<Group>()?;
+    m.add_class::<FileChunks>()?;
+    m.add("ReconciliationError", _py.get_type::<ReconciliationError>())?;
+    m.add("LineWarning", _py.get_type::<LineWarning>())?;
+    Ok(())
+}
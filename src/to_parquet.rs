@@ -0,0 +1,491 @@
+// Parsed lines as Parquet, a file per schema line with Arrow types from its fields
+use arrow_array::builder::{ListBuilder, StringBuilder};
+use arrow_array::{
+    ArrayRef, BooleanArray, Date32Array, Decimal128Array, Float64Array, Int64Array, RecordBatch,
+    StringArray, StructArray, Time64MicrosecondArray, TimestampMicrosecondArray,
+};
+use arrow_buffer::NullBuffer;
+use arrow_schema::{DataType, Field as ArrowField, Fields, Schema as ArrowSchema, TimeUnit};
+use chrono::{NaiveDate, Timelike};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use rust_decimal::prelude::ToPrimitive;
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use {
+    CurrencyField, DatetimeField, DecimalField, Error, Field, IntEnumField, ParsedLine, Parser,
+    PercentField, Value,
+};
+
+// Of decimals without round_decimal_places, max_decimal_places or
+// implied_decimal_places, leaving
+// room for any integer part a Decimal can have
+const DEFAULT_SCALE: u32 = 9;
+
+pub struct ParquetFiles<'a> {
+    parser: &'a Parser,
+    row_group_size: usize,
+    // Per schema line, the path to write to and the file once it has a row
+    paths: Vec<String>,
+    files: Vec<Option<LineFile>>,
+}
+
+struct LineFile {
+    schema: Arc<ArrowSchema>,
+    // Per column, the values of the row group so far
+    columns: Vec<Vec<Value>>,
+    writer: ArrowWriter<File>,
+}
+
+impl<'a> ParquetFiles<'a> {
+    // Files are named after the lines, eg: "TX_02.parquet", with the line
+    // index added where lines share a name
+    pub fn new(parser: &'a Parser, out_dir: &str, row_group_size: usize) -> Self {
+        let mut stems = HashSet::new();
+        let paths = parser
+            .schema
+            .lines
+            .iter()
+            .enumerate()
+            .map(|(line_i, line)| {
+                let mut stem = line.names.join("_");
+                if !stems.insert(stem.clone()) {
+                    stem = format!("{}_{}", stem, line_i);
+                }
+                let path = Path::new(out_dir).join(format!("{}.parquet", stem));
+                path.to_string_lossy().into_owned()
+            })
+            .collect();
+        ParquetFiles {
+            parser,
+            row_group_size,
+            paths,
+            files: parser.schema.lines.iter().map(|_| None).collect(),
+        }
+    }
+    pub fn add(&mut self, parsed: ParsedLine) -> Result<(), Error> {
+        let line_i = parsed.line_index;
+        if self.files[line_i].is_none() {
+            self.files[line_i] = Some(self.open(line_i)?);
+        }
+        let file = self.files[line_i].as_mut().unwrap();
+        let n_names = parsed.n_names;
+        for (column, value) in file
+            .columns
+            .iter_mut()
+            .zip(parsed.values.into_iter().skip(n_names))
+        {
+            column.push(value);
+        }
+        if file.columns[0].len() >= self.row_group_size {
+            file.flush()?;
+        }
+        Ok(())
+    }
+    // Returns the paths written, of the lines that had any rows
+    pub fn finish(self) -> Result<Vec<String>, Error> {
+        let mut written = vec![];
+        for (file, path) in self.files.into_iter().zip(self.paths) {
+            if let Some(mut file) = file {
+                file.flush()?;
+                file.writer.close().map_err(to_error)?;
+                written.push(path);
+            }
+        }
+        Ok(written)
+    }
+    fn open(&self, line_i: usize) -> Result<LineFile, Error> {
+        let line = &self.parser.schema.lines[line_i];
+        let mut columns: Vec<ArrowField> = line
+            .fields
+            .iter()
+            .enumerate()
+            .map(|(i, field)| {
+                let name = line.field_names.get(i).cloned().unwrap_or(i.to_string());
+                ArrowField::new(name, data_type(field), true)
+            })
+            .collect();
+        if line.keep_extra_fields {
+            let item = ArrowField::new("item", DataType::Utf8, true);
+            columns.push(ArrowField::new(
+                "extra",
+                DataType::List(Arc::new(item)),
+                true,
+            ));
+        }
+        if columns.is_empty() {
+            return Err(format!("Line {:?} has no fields to write", line.names).into());
+        }
+        let schema = Arc::new(ArrowSchema::new(columns));
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(self.row_group_size)
+            .build();
+        let file = File::create(&self.paths[line_i]).map_err(to_error)?;
+        let writer =
+            ArrowWriter::try_new(file, Arc::clone(&schema), Some(properties)).map_err(to_error)?;
+        Ok(LineFile {
+            columns: schema.fields().iter().map(|_| vec![]).collect(),
+            schema,
+            writer,
+        })
+    }
+}
+
+impl LineFile {
+    // Writes the values so far as a row group
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.columns[0].is_empty() {
+            return Ok(());
+        }
+        let arrays = self
+            .schema
+            .fields()
+            .iter()
+            .zip(&mut self.columns)
+            .map(|(field, values)| to_array(field.data_type(), &std::mem::take(values)))
+            .collect::<Result<Vec<ArrayRef>, String>>()?;
+        let batch = RecordBatch::try_new(Arc::clone(&self.schema), arrays).map_err(to_error)?;
+        self.writer.write(&batch).map_err(to_error)?;
+        self.writer.flush().map_err(to_error)
+    }
+}
+
+fn to_error<E: ToString>(e: E) -> Error {
+    e.to_string().into()
+}
+
+fn decimal_type(scale: u32) -> DataType {
+    DataType::Decimal128(38, scale as i8)
+}
+
+// Of the values output for the field
+fn data_type(field: &Field) -> DataType {
+    match field {
+        Field::Str(_) | Field::StrEnum(_) => DataType::Utf8,
+        Field::Int(_) => DataType::Int64,
+        Field::IntEnum(IntEnumField { mapping, .. }) => match mapping {
+            Some(_) => DataType::Utf8,
+            None => DataType::Int64,
+        },
+        Field::Float(_) => DataType::Float64,
+        Field::Decimal(DecimalField {
+            round_decimal_places,
+            max_decimal_places,
+            implied_decimal_places,
+            ..
+        }) => decimal_type(
+            round_decimal_places
+                .or(*max_decimal_places)
+                .or(*implied_decimal_places)
+                .unwrap_or(DEFAULT_SCALE),
+        ),
+        Field::Bool(_) => DataType::Boolean,
+        Field::Datetime(DatetimeField {
+            output_naive: true, ..
+        }) => DataType::Timestamp(TimeUnit::Microsecond, None),
+        Field::Datetime(DatetimeField {
+            time_zone,
+            output_time_zone,
+            ..
+        }) => {
+            let tz = output_time_zone.as_ref().or(time_zone.as_ref());
+            let tz = tz.map_or("UTC", |tz| tz.as_str());
+            DataType::Timestamp(TimeUnit::Microsecond, Some(tz.into()))
+        }
+        Field::Date(_) => DataType::Date32,
+        Field::Time(_) => DataType::Time64(TimeUnit::Microsecond),
+        Field::Composite(composite_field) => DataType::Struct(
+            composite_field
+                .fields
+                .iter()
+                .enumerate()
+                .map(|(i, field)| ArrowField::new(i.to_string(), data_type(field), true))
+                .collect(),
+        ),
+        Field::Currency(CurrencyField {
+            include_code: true, ..
+        }) => DataType::Struct(Fields::from(vec![
+            ArrowField::new("amount", decimal_type(DEFAULT_SCALE), true),
+            ArrowField::new("code", DataType::Utf8, true),
+        ])),
+        Field::Currency(_) => decimal_type(DEFAULT_SCALE),
+        Field::Percent(PercentField { as_float: true, .. }) => DataType::Float64,
+        Field::Percent(_) => decimal_type(DEFAULT_SCALE),
+    }
+}
+
+fn to_array(data_type: &DataType, values: &[Value]) -> Result<ArrayRef, String> {
+    let unexpected = |value: &Value| format!("Can't write {:?} as {}", value, data_type);
+    Ok(match data_type {
+        // Ints of an IntEnum without a label too
+        DataType::Utf8 => Arc::new(
+            values
+                .iter()
+                .map(|value| match value {
+                    Value::Null => None,
+                    value => Some(value.to_string()),
+                })
+                .collect::<StringArray>(),
+        ),
+        DataType::Int64 => Arc::new(
+            values
+                .iter()
+                .map(|value| match value {
+                    Value::Null => Ok(None),
+                    Value::Int(i) => i
+                        .to_i64()
+                        .map(Some)
+                        .ok_or_else(|| format!("Int {} is too large for Parquet", i)),
+                    value => Err(unexpected(value)),
+                })
+                .collect::<Result<Int64Array, String>>()?,
+        ),
+        DataType::Float64 => Arc::new(
+            values
+                .iter()
+                .map(|value| match value {
+                    Value::Null => Ok(None),
+                    Value::Float(f) => Ok(Some(*f)),
+                    value => Err(unexpected(value)),
+                })
+                .collect::<Result<Float64Array, String>>()?,
+        ),
+        DataType::Boolean => Arc::new(
+            values
+                .iter()
+                .map(|value| match value {
+                    Value::Null => Ok(None),
+                    Value::Bool(b) => Ok(Some(*b)),
+                    value => Err(unexpected(value)),
+                })
+                .collect::<Result<BooleanArray, String>>()?,
+        ),
+        DataType::Decimal128(precision, scale) => {
+            let scale = *scale as u32;
+            let array = values
+                .iter()
+                .map(|value| match value {
+                    Value::Null => Ok(None),
+                    Value::Decimal(d) if d.scale() > scale => Err(format!(
+                        "Decimal {} has more than {} decimal places, set max_decimal_places",
+                        d, scale
+                    )),
+                    Value::Decimal(d) => {
+                        let mut scaled = *d;
+                        scaled.rescale(scale);
+                        // rescale stops short where the mantissa would overflow
+                        if scaled.scale() != scale {
+                            return Err(format!(
+                                "Decimal {} can't be scaled to {} decimal places, set \
+                                 max_decimal_places",
+                                d, scale
+                            ));
+                        }
+                        Ok(Some(scaled.mantissa()))
+                    }
+                    value => Err(unexpected(value)),
+                })
+                .collect::<Result<Decimal128Array, String>>()?;
+            let array = array.with_precision_and_scale(*precision, scale as i8);
+            Arc::new(array.map_err(|e| e.to_string())?)
+        }
+        DataType::Timestamp(_, tz) => {
+            let array = values
+                .iter()
+                .map(|value| match value {
+                    Value::Null => Ok(None),
+                    Value::Datetime(dt) => Ok(Some(dt.timestamp_micros())),
+                    Value::NaiveDatetime(dt) => Ok(Some(dt.and_utc().timestamp_micros())),
+                    value => Err(unexpected(value)),
+                })
+                .collect::<Result<TimestampMicrosecondArray, String>>()?;
+            Arc::new(array.with_timezone_opt(tz.clone()))
+        }
+        DataType::Date32 => {
+            let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+            Arc::new(
+                values
+                    .iter()
+                    .map(|value| match value {
+                        Value::Null => Ok(None),
+                        Value::Date(d) => Ok(Some((*d - epoch).num_days() as i32)),
+                        value => Err(unexpected(value)),
+                    })
+                    .collect::<Result<Date32Array, String>>()?,
+            )
+        }
+        DataType::Time64(_) => Arc::new(
+            values
+                .iter()
+                .map(|value| match value {
+                    Value::Null => Ok(None),
+                    Value::Time(t) => Ok(Some(
+                        t.num_seconds_from_midnight() as i64 * 1_000_000
+                            + (t.nanosecond() / 1_000) as i64,
+                    )),
+                    value => Err(unexpected(value)),
+                })
+                .collect::<Result<Time64MicrosecondArray, String>>()?,
+        ),
+        DataType::Struct(fields) => {
+            let arrays = fields
+                .iter()
+                .enumerate()
+                .map(|(i, field)| {
+                    let components: Vec<Value> = values
+                        .iter()
+                        .map(|value| match value {
+                            Value::Composite(components) => {
+                                components.get(i).cloned().unwrap_or(Value::Null)
+                            }
+                            _ => Value::Null,
+                        })
+                        .collect();
+                    to_array(field.data_type(), &components)
+                })
+                .collect::<Result<Vec<ArrayRef>, String>>()?;
+            let nulls: Vec<bool> = values.iter().map(|value| !value.is_null()).collect();
+            let array = StructArray::try_new(fields.clone(), arrays, Some(NullBuffer::from(nulls)));
+            Arc::new(array.map_err(|e| e.to_string())?)
+        }
+        // The kept extra fields
+        DataType::List(_) => {
+            let mut builder = ListBuilder::new(StringBuilder::new());
+            for value in values {
+                match value {
+                    Value::Composite(extra) => {
+                        for part in extra {
+                            builder.values().append_value(part.to_string());
+                        }
+                        builder.append(true);
+                    }
+                    _ => builder.append(false),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        data_type => return Err(format!("Can't write {} to Parquet", data_type)),
+    })
+}
+
+#[cfg(all(test, feature = "parquet"))]
+mod tests {
+    use super::*;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Decimal128Type, Int64Type, TimestampMicrosecondType};
+    use arrow_array::{Array, ArrowPrimitiveType};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use rust_decimal::Decimal;
+    use Batch;
+
+    const SCHEMA: &str = r#"{
+        "delimiter": "|",
+        "quote_str": null,
+        "trailing_delimiter": false,
+        "coerce_empty_quoted": false,
+        "lines": [
+            {
+                "name": "a",
+                "fields": [
+                    {"kind": "DECIMAL", "required": false, "max_decimal_places": 2},
+                    {
+                        "kind": "DATETIME",
+                        "required": true,
+                        "format": "%Y-%m-%d %H:%M",
+                        "time_zone": "Europe/London"
+                    },
+                    {"kind": "INT", "required": false}
+                ]
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn read_back() {
+        let out_dir = std::env::temp_dir().join(format!("xlineparse-pq-{}", std::process::id()));
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let parser = Parser::from_json(SCHEMA).unwrap();
+        let mut batch = Batch::new(&parser);
+        let mut files = ParquetFiles::new(&parser, out_dir.to_str().unwrap(), 2);
+        for line in [
+            "a|1.5|2024-07-01 12:00|1",
+            "a||2024-01-01 00:30|",
+            "a|-0.01|2024-01-02 00:00|3",
+        ] {
+            files.add(batch.parse(line, line.len()).unwrap()).unwrap();
+        }
+        let paths = files.finish().unwrap();
+        assert_eq!(paths, vec![out_dir.join("a.parquet").to_str().unwrap()]);
+
+        let builder =
+            ParquetRecordBatchReaderBuilder::try_new(File::open(&paths[0]).unwrap()).unwrap();
+        assert_eq!(builder.metadata().num_row_groups(), 2);
+        let schema = Arc::clone(builder.schema());
+        assert_eq!(schema.field(0).data_type(), &DataType::Decimal128(38, 2));
+        assert_eq!(
+            schema.field(1).data_type(),
+            &DataType::Timestamp(TimeUnit::Microsecond, Some("Europe/London".into()))
+        );
+        assert_eq!(schema.field(2).data_type(), &DataType::Int64);
+        let batches = builder
+            .build()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        std::fs::remove_dir_all(&out_dir).unwrap();
+
+        assert_eq!(
+            column::<Decimal128Type>(&batches, 0),
+            vec![Some(150), None, Some(-1)]
+        );
+        // 12:00 BST, 00:30 GMT and midnight GMT
+        assert_eq!(
+            column::<TimestampMicrosecondType>(&batches, 1),
+            vec![
+                Some(1_719_831_600_000_000),
+                Some(1_704_069_000_000_000),
+                Some(1_704_153_600_000_000)
+            ]
+        );
+        assert_eq!(
+            batches
+                .iter()
+                .map(|b| b.column(2).null_count())
+                .sum::<usize>(),
+            1
+        );
+        assert_eq!(
+            column::<Int64Type>(&batches, 2),
+            vec![Some(1), None, Some(3)]
+        );
+    }
+
+    fn column<T: ArrowPrimitiveType>(batches: &[RecordBatch], i: usize) -> Vec<Option<T::Native>> {
+        batches
+            .iter()
+            .flat_map(|batch| {
+                batch
+                    .column(i)
+                    .as_primitive::<T>()
+                    .iter()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decimal_out_of_range() {
+        let big = Decimal::from_str_exact("99999999999999999999999").unwrap();
+        let error = to_array(&decimal_type(DEFAULT_SCALE), &[Value::Decimal(big)]).unwrap_err();
+        assert_eq!(
+            error,
+            "Decimal 99999999999999999999999 can't be scaled to 9 decimal places, set \
+             max_decimal_places"
+        );
+    }
+}
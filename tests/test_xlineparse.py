@@ -1,11 +1,21 @@
+import asyncio
+import contextlib
 import datetime as dt
+from dataclasses import replace
 from decimal import Decimal
 import enum
+import gzip
+import io
+import json
+import math
+import warnings
 from typing import Annotated, Any, Literal
 import zoneinfo
 
 import pytest
 import xlineparse as xlp
+from xlineparse import cli
+from xlineparse import xlineparse as _xlineparse
 
 AsdLine = tuple[
     Literal["asd"],
@@ -127,6 +137,46 @@ def test_parse_str_enum_weird_quoted() -> None:
     )
 
 
+def test_parse_quoted_bool_and_enum() -> None:
+    def schema(case_insensitive: bool) -> xlp.Schema:
+        return xlp.Schema.from_type(
+            delimiter="|",
+            quote_str='"',
+            t=tuple[
+                Literal["foo"],
+                Annotated[
+                    bool,
+                    xlp.BoolField(
+                        true_value='"Y"',
+                        false_value="N",
+                        case_insensitive=case_insensitive,
+                    ),
+                ],
+                Annotated[
+                    WeirdQuotedEnum,
+                    xlp.StrEnumField(
+                        cls=WeirdQuotedEnum, case_insensitive=case_insensitive
+                    ),
+                ],
+            ],
+        )
+
+    for case_insensitive in (False, True):
+        s = schema(case_insensitive)
+        assert s.parse_line("foo|Y|A") == ("foo", True, WeirdQuotedEnum.A)
+        assert s.parse_line('foo|"Y"|"A"') == ("foo", True, WeirdQuotedEnum.A)
+        assert s.parse_line('foo|"N"|B') == ("foo", False, WeirdQuotedEnum.B)
+
+    s = schema(case_insensitive=True)
+    assert s.parse_line('foo|"y"|"b"') == ("foo", True, WeirdQuotedEnum.B)
+    assert s.parse_line('foo|"n"|a') == ("foo", False, WeirdQuotedEnum.A)
+    s = schema(case_insensitive=False)
+    with pytest.raises(xlp.LineParseError, match="neither true or false"):
+        s.parse_line('foo|"y"|"A"')
+    with pytest.raises(xlp.LineParseError):
+        s.parse_line('foo|"Y"|"a"')
+
+
 def test_parse_int_enum() -> None:
     assert xlp.Schema.from_type(
         delimiter="|",
@@ -288,6 +338,40 @@ def test_decimal_constraints() -> None:
         ).parse_line("a|3.0")
 
 
+def test_exclusive_constraints() -> None:
+    _simple_schema(Annotated[int, xlp.IntField(exclusive_min=0)]).parse_line("a|1")
+    with pytest.raises(xlp.LineParseError):
+        _simple_schema(Annotated[int, xlp.IntField(exclusive_min=0)]).parse_line("a|0")
+    with pytest.raises(xlp.LineParseError):
+        _simple_schema(Annotated[int, xlp.IntField(exclusive_max=2)]).parse_line("a|2")
+    with pytest.raises(xlp.LineParseError):
+        _simple_schema(Annotated[int, xlp.IntField(not_equal=3)]).parse_line("a|3")
+
+    _simple_schema(
+        Annotated[float, xlp.FloatField(exclusive_min=0.0)]
+    ).parse_line("a|0.1")
+    with pytest.raises(xlp.LineParseError):
+        _simple_schema(
+            Annotated[float, xlp.FloatField(exclusive_min=0.0)]
+        ).parse_line("a|0.0")
+    with pytest.raises(xlp.LineParseError):
+        _simple_schema(
+            Annotated[float, xlp.FloatField(exclusive_max=2.0)]
+        ).parse_line("a|2.0")
+
+    _simple_schema(
+        Annotated[Decimal, xlp.DecimalField(exclusive_min=Decimal("0"))]
+    ).parse_line("a|0.01")
+    with pytest.raises(xlp.LineParseError):
+        _simple_schema(
+            Annotated[Decimal, xlp.DecimalField(exclusive_min=Decimal("0"))]
+        ).parse_line("a|0.00")
+    with pytest.raises(xlp.LineParseError):
+        _simple_schema(
+            Annotated[Decimal, xlp.DecimalField(not_equal=Decimal("1"))]
+        ).parse_line("a|1.0")
+
+
 def test_decimal_rounding() -> None:
     assert _simple_schema(
         Annotated[Decimal, xlp.DecimalField(round_decimal_places=3)]
@@ -297,6 +381,26 @@ def test_decimal_rounding() -> None:
     ).parse_line("a|2.00001") == ("a", Decimal("2.000"))
 
 
+def test_decimal_rounding_strategy() -> None:
+    def round_2dp(rounding: Any, value: str) -> Decimal:
+        schema = _simple_schema(
+            Annotated[
+                Decimal,
+                xlp.DecimalField(round_decimal_places=2, rounding=rounding),
+            ]
+        )
+        return schema.parse_line(f"a|{value}")[1]  # type: ignore
+
+    assert round_2dp("half_even", "2.125") == Decimal("2.12")
+    assert round_2dp("half_up", "2.125") == Decimal("2.13")
+    assert round_2dp("half_up", "-2.125") == Decimal("-2.13")
+    assert round_2dp("half_down", "2.125") == Decimal("2.12")
+    assert round_2dp("half_down", "2.126") == Decimal("2.13")
+    assert round_2dp("floor", "2.129") == Decimal("2.12")
+    assert round_2dp("floor", "-2.121") == Decimal("-2.13")
+    assert round_2dp("ceiling", "2.121") == Decimal("2.13")
+
+
 def test_errors() -> None:
     xlp.Schema.from_type(
         delimiter="|",
@@ -409,3 +513,1954 @@ def test_parse_first() -> None:
         ).parse_first("abc,...bkaaadfsd")
         == "abc"
     )
+
+
+def test_big_int_bounds() -> None:
+    big = 2**100
+    schema = _simple_schema(Annotated[int, xlp.IntField(min_value=big, max_value=big)])
+    assert schema.parse_line(f"a|{big}") == ("a", big)
+    with pytest.raises(xlp.LineParseError):
+        schema.parse_line(f"a|{big + 1}")
+    with pytest.raises(xlp.LineParseError):
+        schema.parse_line(f"a|{big - 1}")
+
+    # Above 2**53 an f64 can't tell these apart
+    schema = _simple_schema(Annotated[int, xlp.IntField(max_value=2**60)])
+    schema.parse_line(f"a|{2**60}")
+    with pytest.raises(xlp.LineParseError):
+        schema.parse_line(f"a|{2**60 + 1}")
+
+
+def test_datetime_sub_second_and_offsets() -> None:
+    schema = _simple_schema(
+        Annotated[
+            dt.datetime,
+            xlp.DatetimeField(format="%Y-%m-%d %H:%M:%S%.6f", time_zone="Asia/Kolkata"),
+        ]
+    )
+    assert schema.parse_line("a|2014-07-28 12:00:09.123456") == (
+        "a",
+        dt.datetime(
+            2014, 7, 28, 12, 0, 9, 123456, tzinfo=zoneinfo.ZoneInfo("Asia/Kolkata")
+        ),
+    )
+    assert schema.parse_line("a|2014-07-28 12:00:09.123456")[1].utcoffset() == (
+        dt.timedelta(hours=5, minutes=30)
+    )
+
+
+def test_datetime_offset_in_value() -> None:
+    expected = dt.datetime(
+        2024, 1, 2, 3, 4, 5, tzinfo=dt.timezone(dt.timedelta(hours=1))
+    )
+    schema = _simple_schema(
+        Annotated[dt.datetime, xlp.DatetimeField(format="%Y-%m-%dT%H:%M:%S%z")]
+    )
+    assert schema.parse_line("a|2024-01-02T03:04:05+0100") == ("a", expected)
+    assert schema.parse_line("a|2024-01-02T03:04:05+0100")[1].utcoffset() == (
+        dt.timedelta(hours=1)
+    )
+    schema = _simple_schema(
+        Annotated[dt.datetime, xlp.DatetimeField(format="%Y-%m-%dT%H:%M:%S%:z")]
+    )
+    assert schema.parse_line("a|2024-01-02T03:04:05+01:00") == ("a", expected)
+
+    with pytest.raises(xlp.LineParseError):
+        schema.parse_line("a|2024-01-02T03:04:05")
+    with pytest.raises(xlp.LineParseError, match="Missing time_zone"):
+        _simple_schema(
+            Annotated[dt.datetime, xlp.DatetimeField(format="%Y-%m-%dT%H:%M:%S")]
+        ).parse_line("a|2024-01-02T03:04:05")
+
+
+def test_datetime_time_zone_checked() -> None:
+    field = xlp.DatetimeField(format="%Y-%m-%d %H:%M", time_zone="Not/AZone")
+    with pytest.raises(ValueError, match="Unknown time_zone: 'Not/AZone'"):
+        _simple_schema(Annotated[dt.datetime, field])
+    with pytest.raises(ValueError, match="Unknown time_zone: 'Not/AZone'"):
+        xlp.Schema.from_type(
+            delimiter="+",
+            component_delimiter=":",
+            t=tuple[Literal["a"], tuple[Annotated[dt.datetime, field]]],
+        )
+
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        quote_str='"',
+        t=tuple[
+            Literal["a"],
+            str,
+            Annotated[
+                dt.datetime,
+                xlp.DatetimeField(format="%Y-%m-%d %H:%M", time_zone="Europe/London"),
+            ],
+        ]
+        | tuple[Literal["b"], int],
+    )
+    london = zoneinfo.ZoneInfo("Europe/London")
+    # Parsing again reuses the plan built with the schema
+    for _ in range(2):
+        assert schema.parse_line('a|"x|y"|2024-07-01 12:00') == (
+            "a",
+            "x|y",
+            dt.datetime(2024, 7, 1, 12, tzinfo=london),
+        )
+        assert schema.parse_line("b|1") == ("b", 1)
+        with pytest.raises(xlp.LineParseError, match="No schema line"):
+            schema.parse_line("c|1")
+
+
+def test_datetime_epoch() -> None:
+    seconds = _simple_schema(
+        Annotated[dt.datetime, xlp.DatetimeField(format="epoch_seconds")]
+    )
+    assert seconds.parse_line("a|1700000000") == (
+        "a",
+        dt.datetime(2023, 11, 14, 22, 13, 20, tzinfo=dt.timezone.utc),
+    )
+    assert seconds.parse_line("a|1700000000.25") == (
+        "a",
+        dt.datetime(2023, 11, 14, 22, 13, 20, 250000, tzinfo=dt.timezone.utc),
+    )
+    assert seconds.parse_line("a|-1.5") == (
+        "a",
+        dt.datetime(1969, 12, 31, 23, 59, 58, 500000, tzinfo=dt.timezone.utc),
+    )
+    with pytest.raises(xlp.LineParseError):
+        seconds.parse_line("a|yesterday")
+
+    millis = _simple_schema(
+        Annotated[
+            dt.datetime,
+            xlp.DatetimeField(format="epoch_millis", time_zone="Europe/London"),
+        ]
+    )
+    parsed = millis.parse_line("a|1700000000123")[1]
+    assert parsed == dt.datetime(
+        2023, 11, 14, 22, 13, 20, 123000, tzinfo=dt.timezone.utc
+    )
+    assert parsed.utcoffset() == dt.timedelta(0)
+
+
+def test_fallback_formats() -> None:
+    schema = _simple_schema(
+        Annotated[dt.date, xlp.DateField(format=["%Y-%m-%d", "%d/%m/%Y"])]
+    )
+    assert schema.parse_line("a|2024-01-02") == ("a", dt.date(2024, 1, 2))
+    assert schema.parse_line("a|02/01/2024") == ("a", dt.date(2024, 1, 2))
+    with pytest.raises(xlp.LineParseError, match=r"%Y-%m-%d.*%d/%m/%Y"):
+        schema.parse_line("a|2024/01/02")
+
+    schema = _simple_schema(
+        Annotated[
+            dt.datetime,
+            xlp.DatetimeField(
+                format=["%Y-%m-%dT%H:%M:%S%z", "%Y-%m-%d %H:%M", "epoch_seconds"],
+                time_zone="UTC",
+            ),
+        ]
+    )
+    expected = dt.datetime(2024, 1, 2, 3, 4, tzinfo=dt.timezone.utc)
+    assert schema.parse_line("a|2024-01-02T03:04:00+0000") == ("a", expected)
+    assert schema.parse_line("a|2024-01-02 03:04") == ("a", expected)
+    assert schema.parse_line("a|1704164640") == ("a", expected)
+
+    schema = _simple_schema(
+        Annotated[dt.time, xlp.TimeField(format=["%H:%M:%S", "%H%M"])]
+    )
+    assert schema.parse_line("a|12:32:00") == ("a", dt.time(12, 32))
+    assert schema.parse_line("a|1232") == ("a", dt.time(12, 32))
+
+    with pytest.raises(ValueError, match="invalid format"):
+        _simple_schema(Annotated[dt.date, xlp.DateField(format="%Q")])
+
+
+def test_datetime_dst_policies() -> None:
+    def london(**kwargs: Any) -> xlp.Schema:
+        return _simple_schema(
+            Annotated[
+                dt.datetime,
+                xlp.DatetimeField(
+                    format="%Y-%m-%d %H:%M", time_zone="Europe/London", **kwargs
+                ),
+            ]
+        )
+
+    bst = dt.timezone(dt.timedelta(hours=1))
+    gmt = dt.timezone.utc
+    # 01:30 happens twice on 2023-10-29
+    with pytest.raises(xlp.LineParseError, match="Ambiguous"):
+        london().parse_line("a|2023-10-29 01:30")
+    assert london(ambiguous="earliest").parse_line("a|2023-10-29 01:30") == (
+        "a",
+        dt.datetime(2023, 10, 29, 1, 30, tzinfo=bst),
+    )
+    assert london(ambiguous="latest").parse_line("a|2023-10-29 01:30") == (
+        "a",
+        dt.datetime(2023, 10, 29, 1, 30, tzinfo=gmt),
+    )
+    # 01:30 never happens on 2023-03-26
+    with pytest.raises(xlp.LineParseError, match="Nonexistent"):
+        london().parse_line("a|2023-03-26 01:30")
+    assert london(nonexistent="shift_forward").parse_line("a|2023-03-26 01:30") == (
+        "a",
+        dt.datetime(2023, 3, 26, 2, 30, tzinfo=bst),
+    )
+    assert london(nonexistent="shift_backward").parse_line("a|2023-03-26 01:30") == (
+        "a",
+        dt.datetime(2023, 3, 26, 0, 30, tzinfo=gmt),
+    )
+
+
+def test_end_of_day_and_fractional_time() -> None:
+    for format, value in [
+        ("%H:%M", "24:00"),
+        ("%H%M", "2400"),
+        ("%H:%M:%S", "24:00:00"),
+        ("T%H:%M:%S%.f", "T24:00:00.000"),
+    ]:
+        schema = _simple_schema(Annotated[dt.time, xlp.TimeField(format=format)])
+        assert schema.parse_line(f"a|{value}") == ("a", dt.time(0, 0))
+    with pytest.raises(xlp.LineParseError):
+        _simple_schema(Annotated[dt.time, xlp.TimeField(format="%H:%M")]).parse_line(
+            "a|24:01"
+        )
+    with pytest.raises(xlp.LineParseError):
+        _simple_schema(
+            Annotated[dt.time, xlp.TimeField(format="%H:%M", allow_2400=False)]
+        ).parse_line("a|24:00")
+
+    assert _simple_schema(
+        Annotated[dt.time, xlp.TimeField(format="%H:%M:%S%.f")]
+    ).parse_line("a|12:32:01.250") == ("a", dt.time(12, 32, 1, 250000))
+
+    schema = _simple_schema(
+        Annotated[
+            dt.datetime,
+            xlp.DatetimeField(
+                format="%Y-%m-%d %H:%M", time_zone="UTC", allow_2400=True
+            ),
+        ]
+    )
+    assert schema.parse_line("a|2024-12-31 24:00") == (
+        "a",
+        dt.datetime(2025, 1, 1, tzinfo=dt.timezone.utc),
+    )
+
+
+def test_bool_many_values() -> None:
+    schema = _simple_schema(
+        Annotated[
+            bool,
+            xlp.BoolField(true_values=("Y", "YES", "1"), false_values=("N", "NO", "0")),
+        ]
+    )
+    assert [schema.parse_line(f"a|{v}")[1] for v in ["Y", "YES", "1"]] == [True] * 3
+    assert [schema.parse_line(f"a|{v}")[1] for v in ["N", "NO", "0"]] == [False] * 3
+    with pytest.raises(xlp.LineParseError):
+        schema.parse_line("a|yes")
+
+    schema = _simple_schema(
+        Annotated[
+            bool,
+            xlp.BoolField(
+                true_value="Y",
+                false_value="N",
+                true_values=("yes",),
+                case_insensitive=True,
+            ),
+        ]
+    )
+    assert schema.parse_line("a|y") == ("a", True)
+    assert schema.parse_line("a|YES") == ("a", True)
+    assert schema.parse_line("a|n") == ("a", False)
+    schema = _simple_schema(
+        Annotated[
+            bool | None,
+            xlp.BoolField(
+                required=False,
+                true_value="Y",
+                false_value="N",
+                null_values=("n/a",),
+                case_insensitive=True,
+            ),
+        ]
+    )
+    assert schema.parse_line("a|N/A") == ("a", None)
+    assert schema.parse_line("a|y") == ("a", True)
+
+
+def test_str_enum_case_insensitive_and_strip() -> None:
+    schema = _simple_schema(
+        Annotated[
+            FooEnum, xlp.StrEnumField(cls=FooEnum, case_insensitive=True, strip=True)
+        ]
+    )
+    assert schema.parse_line("a| a ") == ("a", FooEnum.A)
+    assert schema.parse_line("a|b") == ("a", FooEnum.B)
+    with pytest.raises(xlp.LineParseError):
+        schema.parse_line("a|c")
+    with pytest.raises(xlp.LineParseError):
+        _simple_schema(
+            Annotated[FooEnum, xlp.StrEnumField(cls=FooEnum, strip=True)]
+        ).parse_line("a| a ")
+
+
+class TxType(enum.Enum):
+    CREDIT = "CREDIT"
+    DEBIT = "DEBIT"
+
+
+def test_enum_mapping() -> None:
+    mapping = {"01": "CREDIT", "02": "DEBIT"}
+    schema = _simple_schema(Annotated[str, xlp.StrEnumField(mapping=mapping)])
+    assert schema.parse_line("a|01") == ("a", "CREDIT")
+    with pytest.raises(xlp.LineParseError):
+        schema.parse_line("a|CREDIT")
+
+    schema = _simple_schema(
+        Annotated[TxType, xlp.StrEnumField(cls=TxType, mapping=mapping)]
+    )
+    assert schema.parse_line("a|02") == ("a", TxType.DEBIT)
+
+    schema = _simple_schema(
+        Annotated[str, xlp.IntEnumField(mapping={1: "ONE", 2: "TWO"})]
+    )
+    assert schema.parse_line("a|2") == ("a", "TWO")
+
+
+def test_large_enum() -> None:
+    StatusEnum = enum.Enum(  # type: ignore
+        "StatusEnum", {f"S{i}": f"{i:04}" for i in range(3000)}
+    )
+    schema = _simple_schema(StatusEnum)
+    assert schema.parse_line("a|2999") == ("a", StatusEnum["S2999"])
+    with pytest.raises(xlp.LineParseError):
+        schema.parse_line("a|3000")
+
+
+def test_line_match() -> None:
+    schema = xlp.Schema(
+        delimiter="|",
+        lines=[
+            xlp.Line(name="TX", match="prefix", fields=[xlp.IntField()]),
+            xlp.Line(name="TXX", fields=[xlp.StrField()]),
+            xlp.Line(
+                name=r"^R\d+$",
+                match="regex",
+                fields=[xlp.StrEnumField(cls=FooEnum)],
+            ),
+        ],
+    )
+    assert schema.parse_line("TX0001|1") == ("TX0001", 1)
+    assert schema.parse_line("TXX|1") == ("TXX", "1")  # exact wins
+    assert schema.parse_line("R12|A") == ("R12", FooEnum.A)
+    with pytest.raises(xlp.LineParseError, match="No schema line"):
+        schema.parse_line("R12x|A")
+
+    with pytest.raises(ValueError):
+        xlp.Schema(delimiter="|", lines=[xlp.Line(name="(", match="regex", fields=[])])
+
+
+def test_line_match_enum_members() -> None:
+    schema = xlp.Schema(
+        delimiter="|",
+        lines=[
+            xlp.Line(
+                name="TX",
+                match="prefix",
+                fields=[
+                    xlp.IntEnumField(cls=BarEnum),
+                    xlp.StrEnumField(cls=FooEnum, required=False),
+                ],
+            ),
+            xlp.Line(
+                name=r"^R\d+$",
+                match="regex",
+                fields=[xlp.StrEnumField(cls=TxType), xlp.IntEnumField(cls=BarEnum)],
+            ),
+        ],
+    )
+    # The name parsed isn't Line.name, members are found by line index
+    assert schema.parse_line("TX01|2|B") == ("TX01", BarEnum.TWO, FooEnum.B)
+    assert schema.parse_line("TX02|1|") == ("TX02", BarEnum.ONE, None)
+    assert schema.parse_line("R7|DEBIT|1") == ("R7", TxType.DEBIT, BarEnum.ONE)
+    assert list(schema.parse_lines(["TX1|1|A", "R12|CREDIT|2"])) == [
+        ("TX1", BarEnum.ONE, FooEnum.A),
+        ("R12", TxType.CREDIT, BarEnum.TWO),
+    ]
+
+    schema_json = xlp.Schema.from_type(delimiter="|", t=QweLine).to_json()
+    with pytest.raises(ValueError, match="enum_members need enum fields"):
+        _xlineparse.Parser(schema_json, [(0, 0, {})])
+
+
+def test_discriminator() -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        discriminator=xlp.FieldDiscriminator(index=2),
+        t=tuple[Literal["TX"], int, str] | tuple[Literal["RX"], int, int],
+    )
+    assert schema.parse_line("1|a|TX") == ("TX", 1, "a")
+    assert schema.parse_line("1|2|RX") == ("RX", 1, 2)
+    with pytest.raises(xlp.LineParseError, match="length < 3"):
+        schema.parse_line("1|TX")
+
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        discriminator=xlp.BytesDiscriminator(start=5, end=7),
+        t=tuple[Literal["TX"], str, int],
+    )
+    assert schema.parse_line("0001-TX|2") == ("TX", "0001-TX", 2)
+    with pytest.raises(xlp.LineParseError):
+        schema.parse_line("01|2")
+
+
+def test_headerless() -> None:
+    schema = xlp.Schema.from_type(
+        delimiter=",",
+        discriminator=xlp.NoDiscriminator(),
+        t=tuple[int, str | None],
+    )
+    assert schema.parse_line("1,a") == (1, "a")
+    assert schema.parse_line("2,") == (2, None)
+    with pytest.raises(xlp.LineParseError, match="Mismatched"):
+        schema.parse_line("1,a,b")
+    with pytest.raises(ValueError, match="exactly one line"):
+        xlp.Schema.from_type(
+            delimiter=",",
+            discriminator=xlp.NoDiscriminator(),
+            t=tuple[int] | tuple[str],
+        )
+
+
+def test_composite_line_name() -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        t=(
+            tuple[Literal["TX"], Literal["01"], str]
+            | tuple[Literal["TX"], Literal["02"], int]
+            | tuple[Literal["RX"], int]
+        ),
+    )
+    assert schema.parse_line("TX|01|a") == ("TX", "01", "a")
+    assert schema.parse_line("TX|02|5") == ("TX", "02", 5)
+    assert schema.parse_line("RX|5") == ("RX", 5)
+    with pytest.raises(xlp.LineParseError, match="No schema line matching 'TX'"):
+        schema.parse_line("TX|03|5")
+
+
+def test_parse_document() -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        groups=[
+            xlp.Grouper(start="FH", children=("BH",), end="FT"),
+            xlp.Grouper(start="BH", children=("TX", "NOTE"), end="BT"),
+            xlp.Grouper(start="TX", children=("NOTE",)),
+        ],
+        t=(
+            tuple[Literal["FH"], str]
+            | tuple[Literal["BH"], int]
+            | tuple[Literal["TX"], int]
+            | tuple[Literal["NOTE"], str]
+            | tuple[Literal["BT"], int]
+            | tuple[Literal["FT"], int]
+        ),
+    )
+    lines = [
+        "FH|file",
+        "BH|1",
+        "TX|10",
+        "NOTE|a",
+        "TX|20",
+        "BT|2",
+        "FT|1",
+    ]
+    (file,) = schema.parse_document(lines)
+    assert isinstance(file, xlp.Group)
+    assert file.header == ("FH", "file")
+    assert file.trailer == ("FT", 1)
+    (batch,) = file.children
+    assert batch.header == ("BH", 1)
+    assert batch.trailer == ("BT", 2)
+    tx_1, tx_2 = batch.children
+    assert (tx_1.header, tx_1.children, tx_1.trailer) == (
+        ("TX", 10),
+        [("NOTE", "a")],
+        None,
+    )
+    assert (tx_2.header, tx_2.children) == (("TX", 20), [])
+
+    with pytest.raises(xlp.LineParseError, match="missing its end line"):
+        schema.parse_document(lines[:-1])
+    with pytest.raises(xlp.LineParseError, match="isn't allowed in group"):
+        schema.parse_document(["FH|file", "TX|10"])
+    with pytest.raises(xlp.LineParseError, match="Failed to parse line: 'BH|x'"):
+        schema.parse_document(["FH|file", "BH|x"])
+
+
+def test_trailer_checks(tmp_path: Any) -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        trailer_checks=[
+            xlp.TrailerCheck(
+                line="FT",
+                count_field=0,
+                count_lines=("TX",),
+                sums=(xlp.TrailerSum(field=1, line="TX", line_field=0),),
+            )
+        ],
+        t=(
+            tuple[Literal["FH"], str]
+            | tuple[Literal["TX"], Decimal | None]
+            | tuple[Literal["FT"], int, Decimal]
+        ),
+    )
+    lines = ["FH|file\n", "TX|1.50\n", "TX|\n", "TX|2\n", "FT|3|3.5\n"]
+    path = tmp_path / "file.txt"
+    path.write_text("".join(lines))
+    assert schema.parse_file(path) == [
+        ("FH", "file"),
+        ("TX", Decimal("1.50")),
+        ("TX", None),
+        ("TX", Decimal("2")),
+        ("FT", 3, Decimal("3.5")),
+    ]
+    with pytest.raises(xlp.ReconciliationError, match="expected: 3, actual: 2"):
+        schema.parse_lines(lines[:2] + lines[3:])
+    with pytest.raises(xlp.ReconciliationError, match="expected: 3.6, actual: 3.50"):
+        schema.parse_lines(lines[:-1] + ["FT|3|3.6"])
+    with pytest.raises(xlp.LineParseError, match="Failed to parse line: 'TX|x'"):
+        schema.parse_lines(["TX|x"])
+    big = "TX|70000000000000000000000000000\n"
+    with pytest.raises(xlp.ReconciliationError, match="is too large"):
+        schema.parse_lines([big, big, "FT|2|0"])
+
+
+def test_sequence() -> None:
+    SequenceNo = Annotated[int, xlp.IntField(sequence=xlp.Sequence(start=1, step=1))]
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        t=tuple[Literal["A"], SequenceNo, str] | tuple[Literal["B"], SequenceNo],
+    )
+    assert schema.parse_lines(["A|1|x", "B|2", "A|3|y"]) == [
+        ("A", 1, "x"),
+        ("B", 2),
+        ("A", 3, "y"),
+    ]
+    with pytest.raises(xlp.LineParseError, match="Sequence gap at line 3"):
+        schema.parse_lines(["A|1|x", "B|2", "A|4|y"])
+    with pytest.raises(xlp.LineParseError, match="Duplicate sequence number at line 2"):
+        schema.parse_lines(["A|1|x", "B|1"])
+    # Single lines aren't checked
+    assert schema.parse_line("B|7") == ("B", 7)
+
+
+def test_parse_file_compressed(tmp_path: Any) -> None:
+    schema = _simple_schema(int)
+    content = b"a|1\r\na|2\n"
+    (tmp_path / "plain.psv").write_bytes(content)
+    # Detected by the magic bytes, not the extension
+    (tmp_path / "file.gz").write_bytes(gzip.compress(content))
+    (tmp_path / "file.psv").write_bytes(gzip.compress(content))
+    # A zstd frame holding the content as a single raw block
+    block_header = (len(content) << 3 | 1).to_bytes(3, "little")
+    zstd = b"\x28\xb5\x2f\xfd\x20" + bytes([len(content)]) + block_header + content
+    (tmp_path / "file.zst").write_bytes(zstd)
+    for name in ["plain.psv", "file.gz", "file.psv", "file.zst"]:
+        assert schema.parse_file(tmp_path / name) == [("a", 1), ("a", 2)]
+    with pytest.raises(FileNotFoundError):
+        schema.parse_file(tmp_path / "missing.psv")
+
+
+def test_parse_file_mapped(tmp_path: Any) -> None:
+    schema = _simple_schema(str | None)
+    path = tmp_path / "file.psv"
+    for content, expected in [
+        (b"", []),
+        (b"a|x", [("a", "x")]),
+        (b"a|x\na|\n", [("a", "x"), ("a", None)]),
+        (b"a|x\r\na|\xc3\xa9\r\n", [("a", "x"), ("a", "\xe9")]),
+    ]:
+        path.write_bytes(content)
+        assert schema.parse_file(path) == expected
+    path.write_bytes(b"a|x\na|\xff\n")
+    with pytest.raises(xlp.LineParseError, match="isn't valid utf-8"):
+        schema.parse_file(path)
+
+
+def test_parse_bytes(tmp_path: Any) -> None:
+    def schema(**kwargs: Any) -> xlp.Schema:
+        return xlp.Schema.from_type(delimiter="|", t=tuple[Literal["a"], str], **kwargs)
+
+    assert schema().parse_line_bytes("a|é\n".encode()) == ("a", "é")
+    assert schema(encoding="latin-1").parse_lines_bytes([b"a|\xe9", b"a|\x80"]) == [
+        ("a", "é"),
+        ("a", "\x80"),
+    ]
+    assert schema(encoding="cp1252").parse_line_bytes(b"a|\x80") == ("a", "€")
+    with pytest.raises(xlp.LineParseError, match="isn't valid utf-8"):
+        schema().parse_line_bytes(b"a|\xff")
+    replace = schema(encoding_errors="replace")
+    assert replace.parse_line_bytes(b"a|\xff") == ("a", "�")
+
+    path = tmp_path / "file.psv"
+    path.write_bytes(b"a|\xe9\n")
+    assert schema(encoding="latin-1").parse_file(path) == [("a", "é")]
+    with pytest.raises(ValueError, match="Unknown encoding"):
+        schema(encoding="nope")
+    with pytest.raises(ValueError, match="isn't ASCII compatible"):
+        schema(encoding="utf-16")
+
+
+def test_strip_bom(tmp_path: Any) -> None:
+    schema = _simple_schema(str)
+    assert schema.parse_line("\ufeffa|x") == ("a", "x")
+    assert schema.parse_line_bytes(b"\xef\xbb\xbfa|x") == ("a", "x")
+    path = tmp_path / "file.psv"
+    path.write_bytes(b"\xef\xbb\xbfa|x\na|\xef\xbb\xbfy\n")
+    assert schema.parse_file(path) == [("a", "x"), ("a", "\ufeffy")]
+
+    no_strip = xlp.Schema.from_type(
+        delimiter="|", strip_bom=False, t=tuple[Literal["a"], str]
+    )
+    with pytest.raises(xlp.LineParseError, match="No schema line matching"):
+        no_strip.parse_lines(["\ufeffa|x"])
+
+
+def test_line_terminator(tmp_path: Any) -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="|", trailing_delimiter=True, t=tuple[Literal["a"], int]
+    )
+    assert schema.parse_line("a|1|\r\n") == ("a", 1)
+    assert schema.parse_line("a|1|\r") == ("a", 1)
+    path = tmp_path / "file.psv"
+    path.write_bytes(b"a|1|\ra|2|\r\na|3|\n")
+    assert schema.parse_file(path) == [("a", 1), ("a", 2), ("a", 3)]
+    # Lots of lines so "\r\n"s get split across chunks
+    content = "".join(f"a|{i}|\r\n" for i in range(100_000)).encode()
+    path.write_bytes(gzip.compress(content))
+    assert schema.parse_file(path) == [("a", i) for i in range(100_000)]
+
+    schema = xlp.Schema.from_type(
+        delimiter="|", line_terminator="\x1e", t=tuple[Literal["a"], str]
+    )
+    assert schema.parse_line("a|x\x1e") == ("a", "x")
+    path.write_bytes(b"a|x\ny\x1ea|z\x1e")
+    assert schema.parse_file(path) == [("a", "x\ny"), ("a", "z")]
+
+
+def test_on_error_collect(tmp_path: Any) -> None:
+    schema = _simple_schema(int)
+    lines = ["a|1", "a|x", "b|2", "a|3"]
+    with pytest.raises(xlp.LineParseError, match="Does not parse as int"):
+        schema.parse_lines(lines)
+    parsed, rejects = schema.parse_lines(lines, on_error="collect")
+    assert parsed == [("a", 1), ("a", 3)]
+    assert [(r.line, r.line_no) for r in rejects] == [("a|x", 2), ("b|2", 3)]
+    assert isinstance(rejects[0].error, xlp.LineParseError)
+    assert "Does not parse as int" in str(rejects[0].error)
+    assert "No schema line matching 'b'" in str(rejects[1].error)
+
+    path = tmp_path / "file.psv"
+    path.write_bytes(b"a|1\r\na|\xff\r\na|3\r\n")
+    parsed, rejects = schema.parse_file(path, on_error="collect")
+    assert parsed == [("a", 1), ("a", 3)]
+    assert [(r.line, r.line_no) for r in rejects] == [("a|�", 2)]
+    with pytest.raises(ValueError, match="on_error"):
+        schema.parse_lines(lines, on_error="ignore")  # type: ignore
+
+
+def test_batch_error_position(tmp_path: Any) -> None:
+    schema = _simple_schema(int)
+    path = tmp_path / "file.psv"
+    path.write_bytes(b"a|1\r\na|22\r\na|x\r\n")
+    with pytest.raises(xlp.LineParseError, match=r"'a\|x' \(line 3, byte 11\)") as e:
+        schema.parse_file(path)
+    assert (e.value.line, e.value.line_no, e.value.byte_offset) == ("a|x", 3, 11)
+
+    with pytest.raises(xlp.LineParseError) as e:
+        schema.parse_lines(["a|1\n", "a|é\n"])
+    assert (e.value.line, e.value.line_no, e.value.byte_offset) == ("a|é", 2, 4)
+
+    _, (reject,) = schema.parse_lines_bytes([b"a|1", b"a|\xff"], on_error="collect")
+    assert (reject.error.line_no, reject.error.byte_offset) == (2, 3)
+    assert "Failed to decode line" in str(reject.error)
+
+
+def test_cli_validate(tmp_path: Any) -> None:
+    schema_path = tmp_path / "schema.json"
+    schema_path.write_text(_simple_schema(int).to_json())
+    path = tmp_path / "file.psv"
+    path.write_bytes(b"a|1\na|2\n")
+    argv = ["validate", "--schema", str(schema_path), str(path)]
+    out = io.StringIO()
+    with contextlib.redirect_stdout(out):
+        assert cli.main(argv) == 0
+    assert out.getvalue() == f"{path}: 2 lines, 0 errors\n"
+
+    path.write_bytes(b"a|1\na|x\nb|2\n")
+    out = io.StringIO()
+    with contextlib.redirect_stdout(out):
+        assert cli.main(argv) == 1
+    lines = out.getvalue().splitlines()
+    assert lines[0].startswith(f"{path}:2: Does not parse as int - 'x'")
+    assert lines[1] == f"{path}:3: No schema line matching 'b'"
+    assert lines[2] == f"{path}: 3 lines, 2 errors"
+
+
+def test_generate_stub() -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        t=tuple[
+            Literal["trans"],
+            Decimal,
+            Annotated[dt.date, xlp.DateField(format="%Y-%m-%d")],
+            str | None,
+            Annotated[str, xlp.StrEnumField(mapping={"C": "CREDIT", "D": "DEBIT"})],
+        ]
+        | tuple[Literal["TX"], Literal["02"], int]
+        | tuple[Literal["end"], FooEnum],
+    )
+    assert schema.generate_stub() == (
+        "from __future__ import annotations\n"
+        "\n"
+        "import datetime as dt\n"
+        "from decimal import Decimal\n"
+        f"from {__name__} import FooEnum\n"
+        "from typing import Literal\n"
+        "\n"
+        "TransLine = tuple[Literal['trans'], Decimal, dt.date, str | None, "
+        "Literal['CREDIT', 'DEBIT']]\n"
+        "TX02Line = tuple[Literal['TX'], Literal['02'], int]\n"
+        "EndLine = tuple[Literal['end'], FooEnum]\n"
+        "ParsedLine = TransLine | TX02Line | EndLine\n"
+    )
+    schema = xlp.Schema.from_type(
+        delimiter="|", include_raw=True, t=tuple[Literal["a"], int]
+    )
+    assert schema.generate_stub().splitlines()[-2:] == [
+        "ALine = tuple[tuple[Literal['a'], int], tuple[str, ...]]",
+        "ParsedLine = ALine",
+    ]
+
+
+def test_parser_introspection() -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        t=tuple[Literal["a"], Annotated[int, xlp.IntField(max_value=2**70)]]
+        | tuple[Literal["TX"], Literal["02"], str | None],
+    )
+    parser = xlp.Parser(schema.to_json())
+    assert parser.line_names == ["a", ("TX", "02")]
+    assert parser.schema["delimiter"] == "|"
+    assert [line["name"] for line in parser.schema["lines"]] == [["a"], ["TX", "02"]]
+    ((int_field,),) = [parser.fields_for("a")]
+    assert int_field["kind"] == "INT"
+    assert int_field["max_value"] == str(2**70)
+    assert parser.fields_for(("TX", "02"))[0]["required"] is False
+    with pytest.raises(KeyError, match="in the schema"):
+        parser.fields_for("b")
+    # The introspected schema makes the same parser
+    assert xlp.Parser(json.dumps(parser.schema)).parse_line("a|1") == ("a", 1)
+
+
+def test_schema_from_copybook() -> None:
+    copybook = """\
+000100 01  CUSTOMER-RECORD.                                             CUSTREC
+000200*    THE ID IS ZERO PADDED                                        CUSTREC
+000300     05  CUSTOMER-ID      PIC 9(5).                               CUSTREC
+000400     05  NAME             PIC X(8).                               CUSTREC
+000500     05  FILLER           PIC X(2).                               CUSTREC
+000600     05  BALANCE          PIC S9(3)V99.                           CUSTREC
+000700     05  PAYMENT OCCURS 2 TIMES.                                  CUSTREC
+000800         10  AMOUNT       PIC 9(2)V9 SIGN LEADING SEPARATE.       CUSTREC
+000900         88  IS-ZERO      VALUE ZERO.                             CUSTREC
+001000     05  FILLER           PIC XX.                                 CUSTREC
+"""
+    with pytest.raises(ValueError, match="SIGN SEPARATE needs an S"):
+        xlp.schema_from_copybook(copybook)
+    schema = xlp.schema_from_copybook(copybook.replace("PIC 9(2)V9", "PIC S99V9 "))
+    (line,) = schema.lines
+    assert line.field_names == (
+        "customer_id",
+        "name",
+        "filler",
+        "balance",
+        "amount_1",
+        "amount_2",
+        "filler_2",
+    )
+    assert line.widths == (5, 8, 2, 5, 4, 4, 2)
+    assert schema.parse_line("00042Jo        1234J-123+004  ") == (
+        42,
+        "Jo",
+        None,
+        Decimal("-123.41"),
+        Decimal("-12.3"),
+        Decimal("0.4"),
+        None,
+    )
+    with pytest.raises(xlp.LineParseError, match="Implied decimal isn't just digits"):
+        schema.parse_line("00042Jo        12 34-123+004  ")
+
+    free = """
+        01 HEADER.
+           05 KIND PIC X.
+           05 RUN-DATE PIC 9(8).
+        01 DETAIL.
+           05 KIND PIC X.
+           05 QUANTITY PIC S9(4).
+    """
+    with pytest.raises(ValueError, match="Many records need"):
+        xlp.schema_from_copybook(free)
+    with pytest.raises(ValueError, match="No line name for record DETAIL"):
+        xlp.schema_from_copybook(
+            free,
+            discriminator=xlp.BytesDiscriminator(start=0, end=1),
+            line_names={"HEADER": "H"},
+        )
+    schema = xlp.schema_from_copybook(
+        free,
+        discriminator=xlp.BytesDiscriminator(start=0, end=1),
+        line_names={"HEADER": "H", "DETAIL": "D"},
+    )
+    assert schema.parse_line("H20240131") == ("H", "H", 20240131)
+    assert schema.parse_line("D001}") == ("D", "D", -10)
+
+    for unsupported, message in [
+        ("PIC S9(4) COMP-3", "COMP-3 isn't supported"),
+        ("PIC X(4) REDEFINES KIND", "REDEFINES isn't supported"),
+        ("PIC X OCCURS 1 TO 3 DEPENDING ON KIND", "OCCURS DEPENDING"),
+        ("PIC 9(4) SIGN TRAILING SEPARATE", "only SIGN LEADING SEPARATE"),
+    ]:
+        with pytest.raises(ValueError, match=message):
+            xlp.schema_from_copybook(f"01 R. 05 KIND PIC X. 05 B {unsupported}.")
+
+
+def test_fixed_width() -> None:
+    schema = xlp.Schema(
+        delimiter="",
+        discriminator=xlp.BytesDiscriminator(start=0, end=2),
+        lines=[
+            xlp.Line(
+                name="AB",
+                fields=[
+                    xlp.StrField(pad_char=" ", pad_side="right"),
+                    xlp.IntField(zoned=True),
+                    xlp.DecimalField(implied_decimal_places=3),
+                ],
+                widths=(4, 3, 4),
+            )
+        ],
+    )
+    assert schema.parse_line("ABé 12R0125") == ("AB", "ABé", -129, Decimal("0.125"))
+    with pytest.raises(xlp.LineParseError, match="Mismatched line length"):
+        schema.parse_line("ABCD12R01259")
+    with pytest.raises(ValueError, match="needs a width per field"):
+        replace(schema, lines=[replace(schema.lines[0], widths=(4, 3))])
+    with pytest.raises(ValueError, match="bytes or none discriminator"):
+        replace(schema, discriminator=xlp.FieldDiscriminator())
+
+
+def test_composite_fields(tmp_path: Any) -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="+",
+        line_terminator="'",
+        component_delimiter=":",
+        release_str="?",
+        t=tuple[Literal["NAD"], str, tuple[str, str | None, int | None]]
+        | tuple[Literal["FTX"], str],
+    )
+    assert schema.parse_line("NAD+BY+12345::91'") == ("NAD", "BY", ("12345", None, 91))
+    assert schema.parse_line("NAD+BY+12345'") == ("NAD", "BY", ("12345", None, None))
+    assert schema.parse_line("NAD+BY+12?:3::91'") == ("NAD", "BY", ("12:3", None, 91))
+    assert schema.parse_line("FTX+a?+b?:c??'") == ("FTX", "a+b:c?")
+    with pytest.raises(xlp.LineParseError, match="Too many components"):
+        schema.parse_line("NAD+BY+1:2:3:4'")
+    with pytest.raises(xlp.LineParseError, match="Does not parse as int"):
+        schema.parse_line("NAD+BY+1::x'")
+    path = tmp_path / "file.edi"
+    path.write_bytes(b"NAD+BY+1::2'FTX+hi'")
+    assert schema.parse_file(path) == [("NAD", "BY", ("1", None, 2)), ("FTX", "hi")]
+    # A released terminator is part of the line, "??" is a released "?"
+    path.write_bytes(b"FTX+it?'s'FTX+a??'FTX+b?'")
+    assert schema.parse_file(path) == [("FTX", "it's"), ("FTX", "a?"), ("FTX", "b'")]
+    assert schema.parse_line("FTX+b?'") == ("FTX", "b'")
+
+    with pytest.raises(ValueError, match="need a component_delimiter"):
+        xlp.Schema.from_type(delimiter="*", t=tuple[Literal["N1"], tuple[str, str]])
+
+
+def test_include_raw() -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        quote_str='"',
+        include_raw=True,
+        t=tuple[Literal["a"], Decimal, str | None, int],
+    )
+    assert schema.parse_line('a|1.50|"x|y"|007') == (
+        ("a", Decimal("1.50"), "x|y", 7),
+        ("a", "1.50", '"x|y"', "007"),
+    )
+    assert schema.parse_lines(["a|1||2\n"]) == [
+        (("a", Decimal("1"), None, 2), ("a", "1", "", "2"))
+    ]
+
+
+def test_validate(tmp_path: Any) -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        t=tuple[Literal["a"], int, Decimal, str]
+        | tuple[Literal["t"], int],
+        trailer_checks=[xlp.TrailerCheck(line="t", count_field=0)],
+    )
+    assert schema.validate_line("a|1|2.5|x") is None
+    errors = schema.validate_line("a|x|y|z")
+    assert errors is not None and len(errors) == 2
+    assert errors[0].startswith("Does not parse as int")
+    assert errors[1].startswith("Does not parse as decimal")
+    assert schema.validate_line("b|1") == ["No schema line matching 'b'"]
+
+    lines = ["a|1|2|x", "a|x|y|z", "b", "t|3"]
+    rejects = schema.validate_lines(lines)
+    assert [(r.line, r.line_no) for r in rejects] == [
+        ("a|x|y|z", 2),
+        ("a|x|y|z", 2),
+        ("b", 3),
+        ("t|3", 4),
+    ]
+    assert isinstance(rejects[3].error, xlp.ReconciliationError)
+    path = tmp_path / "file.psv"
+    path.write_text("\n".join(lines))
+    assert [(r.line, r.line_no) for r in schema.validate_file(path)] == [
+        (r.line, r.line_no) for r in rejects
+    ]
+
+
+def test_split() -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        quote_str='"',
+        trailing_delimiter=True,
+        t=tuple[Literal["a"], str],
+    )
+    assert schema.split('a|"x|y"||""|\n') == ["a", "x|y", "", ""]
+    assert schema.split_quoted('a|"x|y"||""|') == [
+        ("a", False),
+        ("x|y", True),
+        ("", False),
+        ("", True),
+    ]
+    with pytest.raises(ValueError, match="trailing delimiter"):
+        schema.split("a|b")
+    # Lines not in the schema split as its trailing_delimiter
+    assert schema.split("zzz|b|") == ["zzz", "b"]
+    with pytest.raises(ValueError, match="trailing delimiter"):
+        schema.split("zzz|b")
+    schema = xlp.Schema.from_type(
+        delimiter="+", release_str="?", t=tuple[Literal["a"], str]
+    )
+    assert schema.split("a+b?+c??+d") == ["a", "b+c?", "d"]
+
+
+def test_field_coerce_empty_quoted() -> None:
+    schema = xlp.Schema.from_type(
+        delimiter=",",
+        quote_str='"',
+        t=tuple[
+            Literal["a"],
+            str | None,
+            Annotated[str | None, xlp.StrField(coerce_empty_quoted=True)],
+        ],
+    )
+    assert schema.parse_line('"a","",""') == ("a", None, "")
+    schema = xlp.Schema.from_type(
+        delimiter=",",
+        quote_str='"',
+        coerce_empty_quoted=True,
+        t=tuple[
+            Literal["a"],
+            str | None,
+            Annotated[str | None, xlp.StrField(coerce_empty_quoted=False)],
+        ],
+    )
+    assert schema.parse_line('"a","",""') == ("a", "", None)
+
+
+def test_line_trailing_delimiter() -> None:
+    schema = xlp.Schema(
+        delimiter="|",
+        trailing_delimiter=True,
+        lines=[
+            xlp.Line(name="a", fields=[xlp.IntField()]),
+            xlp.Line(name="t", fields=[xlp.IntField()], trailing_delimiter=False),
+        ],
+    )
+    assert schema.parse_lines(["a|1|", "a|2|", "t|2"]) == [
+        ("a", 1),
+        ("a", 2),
+        ("t", 2),
+    ]
+    assert schema.split("t|2") == ["t", "2"]
+    with pytest.raises(xlp.LineParseError, match="trailing delimiter"):
+        schema.parse_line("a|1")
+    with pytest.raises(xlp.LineParseError, match="Mismatched line length"):
+        schema.parse_line("t|2|")
+
+
+def test_quote_mode() -> None:
+    def split(quote_mode: Any, line: str) -> list[str]:
+        return xlp.Schema.from_type(
+            delimiter=",",
+            quote_str='"',
+            quote_mode=quote_mode,
+            t=tuple[Literal["a"], str, str],
+        ).split(line)
+
+    assert split("lenient", 'a,5"6,x') == ["a", "56,x"]
+    line = 'a,5"6,"x ""y"", z"'
+    assert split("minimal", line) == ["a", '5"6', 'x "y", z']
+    assert split("none", line) == ["a", '5"6', '"x ""y""', ' z"']
+    assert split("all", '"a","5""6",""') == ["a", '5"6', ""]
+    with pytest.raises(ValueError, match="Part isn't quoted"):
+        split("all", line)
+    with pytest.raises(ValueError, match="needs a quote_str"):
+        xlp.Schema.from_type(
+            delimiter=",", quote_mode="all", t=tuple[Literal["a"], str]
+        )
+
+
+def test_length_unit() -> None:
+    def parse(length_unit: Any, value: str) -> tuple[Any, ...]:
+        field = xlp.StrField(max_length=1, length_unit=length_unit)
+        return _simple_schema(Annotated[str, field]).parse_line(f"a|{value}")
+
+    with pytest.raises(xlp.LineParseError, match="too long"):
+        parse("bytes", "é")
+    assert parse("chars", "é") == ("a", "é")
+    with pytest.raises(xlp.LineParseError, match="too long"):
+        parse("chars", "e\u0301")
+    assert parse("graphemes", "e\u0301") == ("a", "e\u0301")
+
+
+def test_str_transform() -> None:
+    def parse(transform: Any, value: str) -> tuple[Any, ...]:
+        field = xlp.StrField(transform=transform)
+        return _simple_schema(Annotated[str, field]).parse_line(f"a|{value}")
+
+    assert parse("upper", "gb-ab12") == ("a", "GB-AB12")
+    assert parse("lower", "GB-AB12") == ("a", "gb-ab12")
+    value = "o'NEIL ltd 2nd"
+    assert parse("title", value) == ("a", value.title())
+    assert parse(None, value) == ("a", value)
+
+
+def test_character_ranges() -> None:
+    def parse(value: str, **kwargs: Any) -> tuple[Any, ...]:
+        field = xlp.StrField(**kwargs)
+        return _simple_schema(Annotated[str, field]).parse_line(f"a|{value}")
+
+    assert parse("AB-12", allowed_characters="0-9A-Z-") == ("a", "AB-12")
+    with pytest.raises(xlp.LineParseError, match="aren't allowed"):
+        parse("AB_12", allowed_characters="0-9A-Z-")
+    assert parse("hi there", allowed_characters=" -~") == ("a", "hi there")
+    with pytest.raises(xlp.LineParseError, match="aren't allowed"):
+        parse("hi\tthere", allowed_characters=" -~")
+    assert parse("abc", invalid_characters="0-9") == ("a", "abc")
+    with pytest.raises(xlp.LineParseError, match="invalid characters"):
+        parse("ab5", invalid_characters="0-9")
+    with pytest.raises(xlp.LineParseError, match="invalid characters"):
+        parse("a-b", invalid_characters="-_")
+
+
+def test_padding() -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        t=tuple[
+            Literal["a"],
+            Annotated[int, xlp.IntField(pad_char="0")],
+            Annotated[int | None, xlp.IntField(pad_char=" ")],
+            Annotated[Decimal, xlp.DecimalField(pad_char="0")],
+            Annotated[str, xlp.StrField(pad_char=" ", pad_side="right")],
+            Annotated[str | None, xlp.StrField(pad_char="*", pad_side="both")],
+        ],
+    )
+    assert schema.parse_line("a|0000123|   |0012.50|ABC   |**x**") == (
+        "a",
+        123,
+        None,
+        Decimal("12.50"),
+        "ABC",
+        "x",
+    )
+    assert schema.parse_line("a|0000|  7|0|  A|****") == (
+        "a",
+        0,
+        7,
+        Decimal("0"),
+        "  A",
+        None,
+    )
+
+
+def test_currency() -> None:
+    def parse(value: str, **kwargs: Any) -> Any:
+        field = xlp.CurrencyField(**kwargs)
+        return _simple_schema(Annotated[Any, field]).parse_line(f"a|{value}")[1]
+
+    assert parse("£1,234.56") == Decimal("1234.56")
+    assert parse("-$5.00", include_code=True) == (Decimal("-5.00"), "USD")
+    assert parse("$-5.00", include_code=True) == (Decimal("-5.00"), "USD")
+    assert parse("USD 99.00", include_code=True) == (Decimal("99.00"), "USD")
+    assert parse("99.00 EUR", include_code=True) == (Decimal("99.00"), "EUR")
+    assert parse("12", include_code=True) == (Decimal("12"), None)
+    assert parse("12", include_code=True, default_code="GBP") == (
+        Decimal("12"),
+        "GBP",
+    )
+    assert parse("$1", include_code=True, symbols={"$": "AUD"}) == (
+        Decimal("1"),
+        "AUD",
+    )
+    # The longest symbol matches
+    symbols = {"R": "ZAR", "R$": "BRL"}
+    assert parse("R$1", include_code=True, symbols=symbols) == (Decimal("1"), "BRL")
+    assert parse("1R", include_code=True, symbols=symbols) == (Decimal("1"), "ZAR")
+    with pytest.raises(xlp.LineParseError, match="isn't allowed"):
+        parse("USD 1", codes=("GBP",))
+    # Without codes, any ISO 4217 code
+    assert parse("CHF 5", include_code=True) == (Decimal("5"), "CHF")
+    for value in ["ABC12.00", "12.00 XYZ"]:
+        with pytest.raises(xlp.LineParseError, match="parse as currency"):
+            parse(value)
+    with pytest.raises(xlp.LineParseError, match="two signs"):
+        parse("-$-5.00")
+    with pytest.raises(xlp.LineParseError, match="too large"):
+        parse("£5", max_value=Decimal("1"))
+    with pytest.raises(xlp.LineParseError, match="parse as currency"):
+        parse("£five")
+
+
+def test_percent() -> None:
+    def parse(value: str, **kwargs: Any) -> Any:
+        field = xlp.PercentField(**kwargs)
+        return _simple_schema(Annotated[Any, field]).parse_line(f"a|{value}")[1]
+
+    assert parse("12.5%") == Decimal("12.5")
+    assert parse("12.5 %", as_fraction=True) == Decimal("0.125")
+    assert parse("12.5", assume_percent=True, as_float=True) == 12.5
+    with pytest.raises(xlp.LineParseError, match="missing a %"):
+        parse("12.5")
+    with pytest.raises(xlp.LineParseError, match="too large"):
+        parse("120%", as_fraction=True, max_value=Decimal(1))
+    with pytest.raises(xlp.LineParseError, match="parse as percent"):
+        parse("x%")
+
+
+def test_float_special_values() -> None:
+    def parse(value: str, **kwargs: Any) -> Any:
+        field = xlp.FloatField(**kwargs)
+        return _simple_schema(Annotated[float, field]).parse_line(f"a|{value}")[1]
+
+    assert math.isnan(parse("NaN"))
+    assert parse("-inf") == -math.inf
+    assert parse("1.2e5") == 120000.0
+    with pytest.raises(xlp.LineParseError, match="NaN"):
+        parse("NaN", allow_nan=False)
+    with pytest.raises(xlp.LineParseError, match="infinite"):
+        parse("-inf", allow_inf=False)
+    with pytest.raises(xlp.LineParseError, match="exponent"):
+        parse("1.2E5", allow_exponent=False)
+    assert parse("1.25", allow_nan=False, allow_inf=False, allow_exponent=False) == 1.25
+
+
+def test_extra_and_missing_fields() -> None:
+    fields: list[xlp.Field] = [xlp.IntField(), xlp.StrField(required=False)]
+    schema = xlp.Schema(
+        delimiter="|",
+        lines=[
+            xlp.Line(name="a", fields=fields, allow_extra_fields=True),
+            xlp.Line(
+                name="b",
+                fields=fields,
+                allow_extra_fields=True,
+                keep_extra_fields=True,
+            ),
+            xlp.Line(name="c", fields=fields, allow_missing_fields=True),
+        ],
+    )
+    assert schema.parse_line("a|1|x|new|newer") == ("a", 1, "x")
+    assert schema.parse_line("b|1|x|new|newer") == ("b", 1, "x", ("new", "newer"))
+    assert schema.parse_line("b|1|x") == ("b", 1, "x", ())
+    assert schema.parse_line("c|1") == ("c", 1, None)
+    with pytest.raises(xlp.LineParseError, match="Mismatched line length"):
+        schema.parse_line("a|1")
+    with pytest.raises(xlp.LineParseError, match="Mismatched line length"):
+        schema.parse_line("c|1|x|y")
+    with pytest.raises(xlp.LineParseError, match="Does not parse as int"):
+        schema.parse_line("c")
+    assert "BLine = tuple[Literal['b'], int, str | None, tuple[str, ...]]" in (
+        schema.generate_stub()
+    )
+
+    schema = xlp.Schema(
+        delimiter="|",
+        lines=[
+            xlp.Line(
+                name="t",
+                fields=[xlp.IntField(), xlp.StrEnumField(cls=FooEnum)],
+                allow_extra_fields=True,
+                keep_extra_fields=True,
+            ),
+        ],
+        trailer_checks=[xlp.TrailerCheck(line="t", count_field=0)],
+    )
+    assert schema.parse_lines(["t|0|A|x"]) == [("t", 0, FooEnum.A, ("x",))]
+
+
+def test_line_aliases() -> None:
+    schema = xlp.Schema(
+        delimiter="|",
+        lines=[
+            xlp.Line(name="TRAN", fields=[xlp.IntField()], aliases=("TRN",)),
+            xlp.Line(
+                name="HDR",
+                fields=[xlp.IntField()],
+                aliases=("HEAD", "H"),
+                normalize_aliases=True,
+            ),
+        ],
+        trailer_checks=[xlp.TrailerCheck(line="HEAD", count_field=0)],
+    )
+    assert schema.parse_line("TRAN|1") == ("TRAN", 1)
+    assert schema.parse_line("TRN|1") == ("TRN", 1)
+    assert schema.parse_line("H|1") == ("HDR", 1)
+    assert schema.parse_lines(["TRN|1", "TRAN|2", "HEAD|2"]) == [
+        ("TRN", 1),
+        ("TRAN", 2),
+        ("HDR", 2),
+    ]
+    stub = schema.generate_stub()
+    assert "TRANLine = tuple[Literal['TRAN', 'TRN'], int]" in stub
+    assert "HDRLine = tuple[Literal['HDR'], int]" in stub
+
+
+def test_stats(tmp_path: Any) -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        t=tuple[Literal["a"], int, str | None] | tuple[Literal["b"], Decimal],
+    )
+    lines = ["a|3|x", "a|1|", "a|2|y", "b|1.5", "c|1"]
+    stats = xlp.Stats()
+    parsed, rejects = schema.parse_lines(lines, "collect", stats=stats)
+    assert len(parsed) == 4 and len(rejects) == 1
+    assert stats == xlp.Stats(
+        lines=5,
+        errors=1,
+        by_line={
+            "a": xlp.LineStats(
+                count=3,
+                null_counts=[0, 1],
+                min_values=[1, "x"],
+                max_values=[3, "y"],
+            ),
+            "b": xlp.LineStats(
+                count=1,
+                null_counts=[0],
+                min_values=[Decimal("1.5")],
+                max_values=[Decimal("1.5")],
+            ),
+        },
+    )
+    path = tmp_path / "file.psv"
+    path.write_text("\n".join(lines[:4]))
+    file_stats = xlp.Stats()
+    schema.parse_file(path, stats=file_stats)
+    assert file_stats.lines == 4 and file_stats.errors == 0
+    assert file_stats.by_line["a"] == stats.by_line["a"]
+
+
+def test_progress(tmp_path: Any) -> None:
+    schema = xlp.Schema.from_type(delimiter="|", t=tuple[Literal["a"], int])
+    path = tmp_path / "file.psv"
+    path.write_text("".join(f"a|{i}\n" for i in range(5)))
+    calls: list[tuple[int, int]] = []
+    parsed = schema.parse_file(
+        path, progress=lambda *args: calls.append(args), progress_every=2
+    )
+    assert len(parsed) == 5
+    assert calls == [(2, 8), (4, 16), (5, 20)]
+
+    def cancel(lines: int, bytes_read: int) -> None:
+        raise KeyboardInterrupt
+
+    with pytest.raises(KeyboardInterrupt):
+        schema.parse_file(path, progress=cancel, progress_every=2)
+
+
+def test_parse_file_async(tmp_path: Any) -> None:
+    schema = xlp.Schema.from_type(delimiter="|", t=tuple[Literal["a"], FooEnum])
+    path = tmp_path / "file.psv"
+    path.write_text("a|A\na|B\na|C\na|A\na|B\n")
+
+    async def collect() -> list[Any]:
+        return [c async for c in schema.parse_file_async(path, "collect", 2)]
+
+    chunks = asyncio.run(collect())
+    assert [parsed for parsed, _ in chunks] == [
+        [("a", FooEnum.A), ("a", FooEnum.B)],
+        [("a", FooEnum.A)],
+        [("a", FooEnum.B)],
+    ]
+    ((reject,),) = [rejects for _, rejects in chunks if rejects]
+    assert (reject.line, reject.line_no) == ("a|C", 3)
+
+    async def parse() -> list[Any]:
+        return [c async for c in schema.parse_file_async(path, chunk_size=2)]
+
+    with pytest.raises(xlp.LineParseError) as e:
+        asyncio.run(parse())
+    assert e.value.line_no == 3
+
+
+def test_interning() -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        t=tuple[
+            Literal["a"],
+            Annotated[str, xlp.StrEnumField(mapping={"01": "CREDIT"})],
+            Annotated[str, xlp.StrField(intern=True)],
+            str,
+        ],
+    )
+    first, second = schema.parse_lines(["a|01|status|other", "a|01|status|other"])
+    assert first == second
+    assert first[0] is second[0]
+    assert first[1] is second[1]
+    assert first[2] is second[2]
+    assert first[3] is not second[3]
+
+
+def test_parse_prefix() -> None:
+    schema = xlp.Schema.from_type(
+        delimiter=",",
+        quote_str='"',
+        t=tuple[Literal["a"], int, str],
+    )
+    assert schema.parse_prefix('"a","x,y",zzz,not an int\n', 2) == ["a", "x,y"]
+    assert schema.parse_prefix("a,b", 5) == ["a", "b"]
+    assert schema.parse_prefix("a,b", 0) == []
+    assert schema.parse_prefix("unknown,b,c", 1) == ["unknown"]
+    schema = xlp.Schema.from_type(
+        delimiter="+", release_str="?", t=tuple[Literal["a"], str]
+    )
+    assert schema.parse_prefix("a+b?+c+d", 2) == ["a", "b+c"]
+
+
+def test_parse_lines_grouped() -> None:
+    schema = xlp.Schema.from_type(
+        delimiter="|",
+        t=tuple[Literal["header"], str]
+        | tuple[Literal["trans"], int]
+        | tuple[Literal["trailer"], int]
+        | tuple[Literal["unused"], str],
+    )
+    lines = ["header|a", "trans|1", "trans|2", "trailer|2"]
+    assert schema.parse_lines_grouped(lines) == {
+        "header": [("header", "a")],
+        "trans": [("trans", 1), ("trans", 2)],
+        "trailer": [("trailer", 2)],
+        "unused": [],
+    }
+    grouped, rejects = schema.parse_lines_grouped(
+        ["trans|1", "trans|x"], on_error="collect"
+    )
+    assert grouped["trans"] == [("trans", 1)]
+    assert [r.line_no for r in rejects] == [2]
+
+
+def test_unique_key() -> None:
+    schema = xlp.Schema(
+        delimiter="|",
+        lines=[
+            xlp.Line(
+                name="a",
+                fields=[xlp.StrField(), xlp.IntField(), xlp.StrField()],
+                unique_key=(0, 1),
+            ),
+            xlp.Line(name="b", fields=[xlp.StrField()]),
+        ],
+    )
+    lines = ["a|x|1|p", "a|x|2|q", "b|z", "b|z", "a|x|1|r", "a|x|1|s"]
+    parsed, rejects = schema.parse_lines(lines, on_error="collect")
+    assert [p[-1] for p in parsed] == ["p", "q", "z", "z"]
+    assert [r.line_no for r in rejects] == [5, 6]
+    assert "Duplicate key (x, 1) at line 5, first seen at line 1" in str(
+        rejects[0].error
+    )
+    with pytest.raises(xlp.LineParseError, match="first seen at line 1"):
+        schema.parse_lines(lines)
+    # Each batch has its own keys
+    assert schema.parse_line("a|x|1|p") == ("a", "x", 1, "p")
+    assert schema.parse_lines(["a|x|1|p"]) == [("a", "x", 1, "p")]
+    # Keys are compared by value
+    schema = xlp.Schema(
+        delimiter="|",
+        lines=[xlp.Line(name="a", fields=[xlp.DecimalField()], unique_key=(0,))],
+    )
+    _, rejects = schema.parse_lines(["a|1.0", "a|2", "a|1.00"], on_error="collect")
+    assert [r.line_no for r in rejects] == [3]
+    assert "Duplicate key (1.00) at line 3, first seen at line 1" in str(
+        rejects[0].error
+    )
+    with pytest.raises(ValueError, match="doesn't have a field 3"):
+        xlp.Schema(
+            delimiter="|",
+            lines=[xlp.Line(name="a", fields=[xlp.StrField()], unique_key=(3,))],
+        ).parse_line("a|x")
+
+
+def test_versions() -> None:
+    schema = xlp.Schema(
+        delimiter="|",
+        lines=[
+            xlp.Line(name="HDR", fields=[xlp.StrField()]),
+            xlp.Line(name="TRN", fields=[xlp.IntField()], versions=("1",)),
+            xlp.Line(
+                name="TRN",
+                fields=[xlp.IntField(), xlp.StrField()],
+                versions=("2", "3"),
+            ),
+        ],
+        version_field=xlp.VersionField(line="HDR", field=0),
+    )
+    assert schema.parse_lines(["HDR|1", "TRN|5", "HDR|2", "TRN|5|x"]) == [
+        ("HDR", "1"),
+        ("TRN", 5),
+        ("HDR", "2"),
+        ("TRN", 5, "x"),
+    ]
+    with pytest.raises(xlp.LineParseError, match="Mismatched line length"):
+        schema.parse_lines(["HDR|2", "TRN|5"])
+    with pytest.raises(xlp.LineParseError, match="Version '4' isn't in the schema"):
+        schema.parse_lines(["HDR|4", "TRN|5"])
+    # Before any header, and parsing single lines, the first line matching wins
+    assert schema.parse_lines(["TRN|5"]) == [("TRN", 5)]
+    assert schema.parse_line("TRN|5") == ("TRN", 5)
+    assert schema.parse_lines_grouped(["HDR|1", "TRN|5", "HDR|3", "TRN|6|y"]) == {
+        "HDR": [("HDR", "1"), ("HDR", "3")],
+        "TRN": [("TRN", 5), ("TRN", 6, "y")],
+    }
+    stats = xlp.Stats()
+    schema.parse_lines(["HDR|1", "TRN|5", "TRN|7", "HDR|3", "TRN|6|y"], stats=stats)
+    assert stats.by_line["TRN"] == xlp.LineStats(
+        count=3, null_counts=[0, 0], min_values=[5, "y"], max_values=[7, "y"]
+    )
+    with pytest.raises(ValueError, match="needs to be in every version"):
+        xlp.Schema(
+            delimiter="|",
+            lines=[xlp.Line(name="HDR", fields=[xlp.StrField()], versions=("1",))],
+            version_field=xlp.VersionField(line="HDR", field=0),
+        ).parse_line("HDR|1")
+
+
+def test_diff_schemas() -> None:
+    old = xlp.Schema(
+        delimiter="|",
+        lines=[
+            xlp.Line(
+                name="a",
+                fields=[
+                    xlp.StrField(max_length=10),
+                    xlp.IntField(min_value=0),
+                    xlp.DateField(format="%Y-%m-%d"),
+                ],
+            ),
+            xlp.Line(name="b", fields=[xlp.StrField()]),
+        ],
+    )
+    new = xlp.Schema(
+        delimiter="|",
+        lines=[
+            xlp.Line(
+                name="a",
+                fields=[
+                    xlp.StrField(max_length=20),
+                    xlp.IntField(min_value=5),
+                    xlp.DecimalField(),
+                    xlp.StrField(required=False),
+                ],
+                allow_missing_fields=True,
+            ),
+            xlp.Line(name="c", fields=[xlp.StrField()]),
+        ],
+    )
+    diff = xlp.diff_schemas(old.to_json(), new.to_json())
+    assert [(c.kind, c.line, c.field, c.key, c.breaking) for c in diff.changes] == [
+        ("loosened", "a", None, "allow_missing_fields", False),
+        ("loosened", "a", 0, "max_length", False),
+        ("tightened", "a", 1, "min_value", True),
+        ("type_changed", "a", 2, None, True),
+        ("field_added", "a", 3, None, False),
+        ("line_removed", "b", None, None, True),
+        ("line_added", "c", None, None, False),
+    ]
+    assert not diff.backward_compatible
+    assert xlp.diff_schemas(old.to_json(), old.to_json()).changes == []
+    b_line = replace(old.lines[1], allow_extra_fields=True)
+    looser = replace(old, lines=[old.lines[0], b_line, new.lines[1]])
+    diff = xlp.diff_schemas(old.to_json(), looser.to_json())
+    assert [(c.kind, c.line, c.key) for c in diff.changes] == [
+        ("loosened", "b", "allow_extra_fields"),
+        ("line_added", "c", None),
+    ]
+    assert diff.backward_compatible
+    tighter = replace(old, delimiter=",")
+    (change,) = xlp.diff_schemas(old.to_json(), tighter.to_json()).changes
+    assert (change.kind, change.key, change.old, change.new) == (
+        "changed",
+        "delimiter",
+        "|",
+        ",",
+    )
+
+
+def test_generate_lines(tmp_path: Any) -> None:
+    class Colour(enum.Enum):
+        RED = "R"
+        GREEN = "G"
+
+    schema = xlp.Schema(
+        delimiter="|",
+        quote_str='"',
+        quote_mode="all",
+        lines=[
+            xlp.Line(
+                name="a",
+                fields=[
+                    xlp.StrField(min_length=3, max_length=5, allowed_characters="a-c|"),
+                    xlp.StrEnumField(cls=Colour),
+                    xlp.IntField(min_value=10, max_value=12),
+                    xlp.IntField(sequence=xlp.Sequence(start=5)),
+                    xlp.DecimalField(required=False, round_decimal_places=1),
+                    xlp.BoolField(true_value="Y", false_value="N"),
+                    xlp.DatetimeField(format="%Y-%m-%dT%H:%M:%S", time_zone="UTC"),
+                    xlp.DateField(format=["%d/%m/%Y"]),
+                    xlp.CurrencyField(codes=("GBP",)),
+                    xlp.PercentField(max_value=Decimal(50)),
+                ],
+            ),
+            xlp.Line(name=("b", "c"), fields=[xlp.FloatField(min_value=-1.0)]),
+        ],
+    )
+    line = schema.generate_line("a", seed=1)
+    assert line == schema.generate_line("a", seed=1)
+    assert line.startswith('"a"|')
+    parsed = schema.parse_line(line)
+    assert 3 <= len(parsed[1]) <= 5 and set(parsed[1]) <= set("abc")
+    assert isinstance(parsed[2], Colour)
+    assert 10 <= parsed[3] <= 12
+    assert parsed[4] == 5
+    assert parsed[10] <= 50
+    assert schema.parse_line(schema.generate_line(("b", "c")))[2] >= -1.0
+
+    path = tmp_path / "file.txt"
+    schema.generate_file(path, 50, seed=2, line_names=["a"])
+    parsed_lines = schema.parse_file(path)
+    assert [p[4] for p in parsed_lines] == list(range(5, 55))
+    schema.generate_file(path, 20, seed=2)
+    assert len(schema.parse_file(path)) == 20
+    with pytest.raises(KeyError):
+        schema.generate_line("x")
+    huge = Decimal("7e28")
+    for field in [
+        xlp.DecimalField(max_value=huge),
+        xlp.PercentField(max_value=huge, as_fraction=True),
+    ]:
+        schema = xlp.Schema(delimiter="|", lines=[xlp.Line(name="a", fields=[field])])
+        with pytest.raises(ValueError, match="Couldn't generate a value"):
+            schema.generate_line("a")
+    schema = xlp.Schema.from_type(delimiter="||", t=tuple[Literal["a"], int])
+    with pytest.raises(ValueError, match="Delimiter needs to be of length 1"):
+        schema.generate_line("a")
+
+
+def test_warn_severity() -> None:
+    schema = xlp.Schema(
+        delimiter="|",
+        lines=[
+            xlp.Line(
+                name="a",
+                fields=[
+                    xlp.StrField(max_length=3, severity="warn"),
+                    xlp.IntField(min_value=0, severity="warn"),
+                    xlp.StrEnumField(mapping={"X": "x"}, severity="warn"),
+                    xlp.IntField(max_value=10),
+                ],
+            )
+        ],
+    )
+    with warnings.catch_warnings(record=True) as caught:
+        warnings.simplefilter("always")
+        assert schema.parse_line("a|abc|1|X|1") == ("a", "abc", 1, "x", 1)
+        assert caught == []
+        assert schema.parse_line("a|abcd|-1|Y|1") == ("a", "abcd", -1, "Y", 1)
+    assert [w.category for w in caught] == [xlp.LineWarning] * 3
+    assert str(caught[0].message).startswith("String is too long - 'abcd'")
+    assert caught[0].filename == __file__
+    with pytest.raises(xlp.LineParseError, match="Int is too large"):
+        schema.parse_line("a|abcd|-1|Y|11")
+    with pytest.raises(xlp.LineParseError, match="Does not parse as int"):
+        schema.parse_line("a|abc|x|X|1")
+    with warnings.catch_warnings(record=True) as caught:
+        warnings.simplefilter("always")
+        assert len(schema.parse_lines(["a|abc|1|X|1", "a|abc|-1|X|1"])) == 2
+    (warning,) = caught
+    assert str(warning.message).startswith("Line 2, byte 11: Int is too small")
+
+
+def test_validators(tmp_path: Any) -> None:
+    def luhn(value: str) -> str:
+        digits = [int(c) for c in reversed(value)]
+        doubled = [sum(divmod(d * 2, 10)) for d in digits[1::2]]
+        if (sum(digits[::2]) + sum(doubled)) % 10:
+            raise ValueError(f"Bad check digit: {value}")
+        return value
+
+    schema = xlp.Schema(
+        delimiter="|",
+        lines=[
+            xlp.Line(
+                name="a",
+                fields=[xlp.StrField(required=False), xlp.IntField()],
+            )
+        ],
+        validators={("a", 0): luhn, ("a", 1): lambda i: i * 2},
+    )
+    assert schema.parse_line("a|79927398713|2") == ("a", "79927398713", 4)
+    assert schema.parse_line("a||2") == ("a", None, 4)
+    with pytest.raises(xlp.LineParseError, match="Bad check digit: 79927398710"):
+        schema.parse_line("a|79927398710|2")
+    parsed, rejects = schema.parse_lines(
+        ["a|79927398713|1", "a|1|1"], on_error="collect"
+    )
+    assert parsed == [("a", "79927398713", 2)]
+    assert [reject.line_no for reject in rejects] == [2]
+    # Validators run on the fields that parse
+    errors = schema.validate_line("a|1|x")
+    assert [e.split(" given schema")[0] for e in errors or []] == [
+        "Does not parse as int - 'x'",
+        "Validator failed - ValueError: Bad check digit: 1",
+    ]
+    (reject,) = schema.validate_lines(["a|79927398713|1", "a|1|1"])
+    assert reject.line_no == 2 and "Bad check digit: 1" in str(reject.error)
+    path = tmp_path / "file.psv"
+    path.write_text("a|1|1\na|79927398713|1\n")
+    (reject,) = schema.validate_file(path)
+    assert reject.line_no == 1 and "Bad check digit: 1" in str(reject.error)
+    with pytest.raises(ValueError, match="validators are Python callables"):
+        schema.parse_line_to_json("a|79927398713|1")
+    with pytest.raises(ValueError, match="validators are Python callables"):
+        schema.parse_file_to_ndjson(path, tmp_path / "out.ndjson")
+    with pytest.raises(ValueError, match="isn't in the schema"):
+        xlp.Schema(delimiter="|", lines=schema.lines, validators={("b", 0): luhn})
+
+
+def test_datetime_output_time_zone() -> None:
+    def field(**kwargs: Any) -> xlp.DatetimeField:
+        return xlp.DatetimeField(
+            format="%Y-%m-%d %H:%M:%S", time_zone="Europe/London", **kwargs
+        )
+
+    utc = _simple_schema(Annotated[dt.datetime, field(output_time_zone="UTC")])
+    parsed = utc.parse_line("a|2024-07-01 12:00:00")[1]
+    assert parsed == dt.datetime(2024, 7, 1, 11, tzinfo=dt.timezone.utc)
+    assert parsed.utcoffset() == dt.timedelta(0)
+    naive = _simple_schema(
+        Annotated[dt.datetime, field(output_time_zone="UTC", output_naive=True)]
+    )
+    assert naive.parse_line("a|2024-07-01 12:00:00")[1] == dt.datetime(2024, 7, 1, 11)
+    local = _simple_schema(Annotated[dt.datetime, field(output_naive=True)])
+    assert local.parse_line("a|2024-07-01 12:00:00")[1] == dt.datetime(2024, 7, 1, 12)
+    with pytest.raises(ValueError, match="Unknown output_time_zone: 'Mars'"):
+        _simple_schema(Annotated[dt.datetime, field(output_time_zone="Mars")])
+
+
+def test_decimal_precision() -> None:
+    schema = _simple_schema(
+        Annotated[Decimal, xlp.DecimalField(max_digits=5, max_decimal_places=2)]
+    )
+    assert schema.parse_line("a|123.45") == ("a", Decimal("123.45"))
+    assert schema.parse_line("a|-0.05") == ("a", Decimal("-0.05"))
+    with pytest.raises(xlp.LineParseError, match="too many decimal places"):
+        schema.parse_line("a|1.234")
+    with pytest.raises(xlp.LineParseError, match="too many digits"):
+        schema.parse_line("a|1234.5")
+    rounded = _simple_schema(
+        Annotated[
+            Decimal, xlp.DecimalField(round_decimal_places=2, max_decimal_places=2)
+        ]
+    )
+    assert rounded.parse_line("a|1.234") == ("a", Decimal("1.23"))
+    assert schema.parse_line(schema.generate_line("a", seed=1))[1] < 1000
+
+
+def test_bool_null_values() -> None:
+    field = xlp.BoolField(true_value="Y", false_value="N", null_values=(" ", "?"))
+    schema = _simple_schema(Annotated[bool | None, replace(field, required=False)])
+    assert schema.parse_line("a|Y") == ("a", True)
+    assert schema.parse_line("a| ") == ("a", None)
+    assert schema.parse_line("a|?") == ("a", None)
+    assert schema.parse_line("a|") == ("a", None)
+    with pytest.raises(xlp.LineParseError, match="neither true or false"):
+        schema.parse_line("a|X")
+    required = _simple_schema(Annotated[bool, field])
+    with pytest.raises(xlp.LineParseError, match="Null value for required bool"):
+        required.parse_line("a| ")
+
+
+def test_continuation(tmp_path: Any) -> None:
+    lines = [xlp.Line(name="a", fields=[xlp.StrField(), xlp.IntField()])]
+    trailing = xlp.Schema(
+        delimiter="|",
+        lines=lines,
+        continuation=xlp.Continuation(marker="\\", separator=" "),
+    )
+    assert trailing.parse_lines(["a|long\\", "text|1", "a|x|2"]) == [
+        ("a", "long text", 1),
+        ("a", "x", 2),
+    ]
+    assert trailing.parse_lines(io.StringIO("a|long\\\ntext|1\n")) == [
+        ("a", "long text", 1)
+    ]
+    leading = xlp.Schema(
+        delimiter="|",
+        lines=lines,
+        continuation=xlp.Continuation(kind="leading", marker="+"),
+    )
+    path = tmp_path / "file.txt"
+    path.write_text("a|ab\n+cd|1\na|x|2\n+\na|y|3\n")
+    assert leading.parse_file(path) == [("a", "abcd", 1), ("a", "x", 2), ("a", "y", 3)]
+    path.write_text("a|ab\n+cd|1\na|x|\n+y\n")
+    parsed, rejects = leading.parse_file(path, on_error="collect")
+    assert parsed == [("a", "abcd", 1)]
+    assert rejects[0].line == "a|x|y"
+    assert (rejects[0].line_no, rejects[0].error.byte_offset) == (2, 11)
+    assert len(leading.parse_lines_bytes([b"a|a|1", b"+2"])) == 1
+    assert leading.parse_lines(["a|a\r\n", "+b|1\r\n"]) == [("a", "ab", 1)]
+    with pytest.raises(ValueError, match="marker can't be empty"):
+        xlp.Schema(delimiter="|", lines=lines, continuation=xlp.Continuation(marker=""))
+
+
+def test_multiline_quoted(tmp_path: Any) -> None:
+    lines = [xlp.Line(name="a", fields=[xlp.StrField(), xlp.IntField()])]
+    schema = xlp.Schema(
+        delimiter=",",
+        quote_str='"',
+        quote_mode="minimal",
+        lines=lines,
+        multiline_quoted=True,
+    )
+    content = b'a,"one\r\ntwo",1\r\na,"x,""\ny""",2\r\na,plain,3\r\n'
+    expected = [("a", "one\r\ntwo", 1), ("a", 'x,"\ny"', 2), ("a", "plain", 3)]
+    path = tmp_path / "file.csv"
+    path.write_bytes(content)
+    assert schema.parse_file(path) == expected
+    (tmp_path / "file.gz").write_bytes(gzip.compress(content))
+    assert schema.parse_file(tmp_path / "file.gz") == expected
+    lenient = replace(schema, quote_mode="lenient")
+    assert lenient.parse_file(path)[1] == ("a", "x,\ny", 2)
+    path.write_bytes(b'a,b"c,1\na,"open\n')
+    parsed, rejects = schema.parse_file(path, on_error="collect")
+    assert parsed == [("a", 'b"c', 1)]
+    assert "Quoted part isn't closed" in str(rejects[0].error)
+    parsed, rejects = lenient.parse_file(path, on_error="collect")
+    assert parsed == [] and [r.line for r in rejects] == ['a,b"c,1\na,"open']
+    # A long record split over many chunks of the file
+    long = "\n".join(["x" * 1000] * 500)
+    content = f'a,"{long}",1\na,y,2\n'.encode()
+    (tmp_path / "file.gz").write_bytes(gzip.compress(content))
+    assert schema.parse_file(tmp_path / "file.gz") == [("a", long, 1), ("a", "y", 2)]
+    short = replace(schema, multiline_max_lines=2)
+    path.write_bytes(b'a,"1\n2\n3",1\na,"1\n2",2\n')
+    parsed, rejects = short.parse_file(path, on_error="collect")
+    assert parsed == [("a", "1\n2", 2)]
+    assert [r.line for r in rejects] == ['a,"1\n2', '3",1']
+    with pytest.raises(ValueError, match="needs a quote_str"):
+        xlp.Schema(delimiter=",", lines=lines, multiline_quoted=True)
+    with pytest.raises(ValueError, match="Delimiter needs to be"):
+        replace(schema, delimiter=",,")
+    with pytest.raises(ValueError, match="can't be used with quote_mode none"):
+        replace(schema, quote_mode="none")
+
+
+def test_json_output(tmp_path: Any) -> None:
+    schema = xlp.Schema(
+        delimiter="|",
+        lines=[
+            xlp.Line(
+                name="a",
+                fields=[
+                    xlp.DecimalField(),
+                    xlp.IntField(required=False),
+                    xlp.DatetimeField(format="%Y-%m-%d %H:%M", time_zone="UTC"),
+                    xlp.StrEnumField(mapping={"X": "x"}),
+                ],
+                field_names=("amount", "count", "at", "kind"),
+            ),
+            xlp.Line(
+                name=("b", "c"),
+                fields=[
+                    xlp.FloatField(),
+                    xlp.BoolField(true_value="T", false_value="F"),
+                ],
+            ),
+        ],
+    )
+    assert json.loads(schema.parse_line_to_json("a|1.10||2024-01-02 03:04|X")) == {
+        "line": "a",
+        "fields": {
+            "amount": "1.10",
+            "count": None,
+            "at": "2024-01-02T03:04:00+00:00",
+            "kind": "x",
+        },
+    }
+    assert json.loads(schema.parse_line_to_json("b|c|1.5|T")) == {
+        "line": ["b", "c"],
+        "fields": {"0": 1.5, "1": True},
+    }
+    with pytest.raises(xlp.LineParseError):
+        schema.parse_line_to_json("a|x||2024-01-02 03:04|X")
+
+    in_path, out_path = tmp_path / "in.psv", tmp_path / "out.ndjson"
+    in_path.write_text("a|1|2|2024-01-02 03:04|X\nb|c|x|T\nb|c|2|F\n")
+    written, rejects = schema.parse_file_to_ndjson(in_path, out_path, "collect")
+    assert written == 2 and [r.line_no for r in rejects] == [2]
+    records = [json.loads(line) for line in out_path.read_text().splitlines()]
+    assert [r["line"] for r in records] == ["a", ["b", "c"]]
+    assert records[0]["fields"]["count"] == 2
+    with pytest.raises(xlp.LineParseError):
+        schema.parse_file_to_ndjson(in_path, out_path)
+    with pytest.raises(ValueError, match="distinct field name per field"):
+        xlp.Schema(
+            delimiter="|",
+            lines=[xlp.Line(name="a", fields=[xlp.IntField()], field_names=("x", "y"))],
+        )
+
+
+def test_parquet_output(tmp_path: Any) -> None:
+    schema = xlp.Schema(
+        delimiter="|",
+        lines=[
+            xlp.Line(
+                name="a",
+                fields=[
+                    xlp.DecimalField(max_decimal_places=2),
+                    xlp.IntField(required=False),
+                    xlp.DatetimeField(format="%Y-%m-%d %H:%M", time_zone="UTC"),
+                ],
+                field_names=("amount", "count", "at"),
+            ),
+            xlp.Line(name=("b", "c"), fields=[xlp.FloatField()]),
+            xlp.Line(name="d", fields=[xlp.StrField()]),
+        ],
+    )
+    in_path = tmp_path / "in.psv"
+    in_path.write_text(
+        "a|1.5|2|2024-01-02 03:04\nb|c|x\nb|c|2.5\na|3||2024-01-03 00:00\n"
+    )
+    out_dir = tmp_path / "out"
+    out_dir.mkdir()
+    if not hasattr(schema._parser, "parse_file_to_parquet"):
+        with pytest.raises(NotImplementedError, match="--features parquet"):
+            schema.parse_file_to_parquet(in_path, out_dir)
+        return
+    paths, rejects = schema.parse_file_to_parquet(
+        in_path, out_dir, "collect", row_group_size=1
+    )
+    assert paths == [str(out_dir / "a.parquet"), str(out_dir / "b_c.parquet")]
+    assert [r.line_no for r in rejects] == [2]
+    for path in paths:
+        content = (out_dir / path).read_bytes()
+        assert content[:4] == content[-4:] == b"PAR1"
+    assert b"amount" in (out_dir / "a.parquet").read_bytes()
+    with pytest.raises(xlp.LineParseError):
+        schema.parse_file_to_parquet(in_path, out_dir)
+    with pytest.raises(ValueError, match="row_group_size"):
+        schema.parse_file_to_parquet(in_path, out_dir, row_group_size=0)
+
+
+def test_schema_includes(tmp_path: Any) -> None:
+    str_field, int_field = xlp.StrField().as_dict(), xlp.IntField().as_dict()
+    common = dict(
+        field_groups=dict(
+            address=dict(
+                fields=[str_field, str_field], field_names=["street", "postcode"]
+            ),
+            audit=dict(
+                fields=[int_field, dict(kind="GROUP", name="address")],
+                field_names=["user"],
+            ),
+        ),
+        lines=[xlp.Line(name="z", fields=[xlp.IntField()]).as_dict()],
+    )
+    (tmp_path / "shared").mkdir()
+    (tmp_path / "shared" / "common.json").write_text(json.dumps(common))
+    line = xlp.Line(name="b", fields=[xlp.IntField()], field_names=("id",))
+    schema = json.loads(xlp.Schema(delimiter="|", lines=[line]).to_json())
+    schema["includes"] = ["shared/common.json"]
+    schema["lines"][0]["fields"] += [
+        dict(kind="GROUP", name="address", prefix="billing_"),
+        dict(kind="GROUP", name="audit"),
+    ]
+    parser = _xlineparse.Parser(json.dumps(schema), base_dir=str(tmp_path))
+    assert json.loads(parser.parse_line_to_json("b|1|High St|AB1|7|Low Rd|CD2")) == {
+        "line": "b",
+        "fields": {
+            "id": 1,
+            "billing_street": "High St",
+            "billing_postcode": "AB1",
+            "user": 7,
+            "street": "Low Rd",
+            "postcode": "CD2",
+        },
+    }
+    assert parser.parse_line("z|3") == ("z", 3)
+
+    schema_path = tmp_path / "schema.json"
+    schema_path.write_text(json.dumps(schema))
+    path = tmp_path / "file.psv"
+    path.write_bytes(b"z|3\nb|1|High St|AB1|7|Low Rd|CD2\n")
+    with contextlib.redirect_stdout(io.StringIO()):
+        assert cli.main(["validate", "--schema", str(schema_path), str(path)]) == 0
+
+    # common.json is included twice, directly and by other.json
+    other = dict(includes=["common.json"], lines=[])
+    (tmp_path / "shared" / "other.json").write_text(json.dumps(other))
+    schema["includes"].append("shared/other.json")
+    schema["component_delimiter"] = ";"
+    schema["lines"].append(
+        dict(
+            name="c",
+            fields=[
+                dict(
+                    kind="COMPOSITE",
+                    required=True,
+                    fields=[dict(kind="GROUP", name="address")],
+                )
+            ],
+        )
+    )
+    parser = _xlineparse.Parser(json.dumps(schema), base_dir=str(tmp_path))
+    assert parser.parse_line("z|3") == ("z", 3)
+    assert parser.parse_line("c|High St;AB1") == ("c", ("High St", "AB1"))
+
+    schema["lines"][0]["fields"].append(dict(kind="GROUP", name="shipping"))
+    with pytest.raises(ValueError, match="Unknown field group \"shipping\""):
+        _xlineparse.Parser(json.dumps(schema), base_dir=str(tmp_path))
+    common["includes"] = ["common.json"]
+    (tmp_path / "shared" / "common.json").write_text(json.dumps(common))
+    with pytest.raises(ValueError, match="includes itself"):
+        _xlineparse.Parser(json.dumps(schema), base_dir=str(tmp_path))